@@ -2,6 +2,7 @@ use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 use slint::ComponentHandle;
 use tracing::{debug, error, warn};
+use wsrx::upstream::Upstream;
 
 use crate::ui::{MainWindow, SettingsBridge};
 
@@ -13,6 +14,10 @@ pub struct WsrxDesktopConfig {
     pub running_in_tray: bool,
     #[serde(default = "default_language")]
     pub language: String,
+    /// The upstream proxy of instances that don't set their own, e.g.
+    /// `socks5h://127.0.0.1:1080` or `direct`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
 }
 
 impl Default for WsrxDesktopConfig {
@@ -21,6 +26,7 @@ impl Default for WsrxDesktopConfig {
             theme: default_theme(),
             running_in_tray: default_running_in_tray(),
             language: default_language(),
+            proxy: None,
         }
     }
 }
@@ -89,6 +95,12 @@ pub fn load_config(window: &MainWindow) {
     slint::select_bundled_translation(&config.language).ok();
     bridge.set_language(config.language.into());
     bridge.set_running_in_tray(config.running_in_tray);
+    if let Some(proxy) = &config.proxy {
+        match proxy.parse::<Upstream>() {
+            Ok(proxy) => Upstream::set_global(proxy),
+            Err(e) => warn!("Ignoring the proxy setting: {}", e),
+        }
+    }
 
     let window_clone = window.as_weak();
     bridge.on_change_language(move |lang| {
@@ -110,10 +122,16 @@ pub fn save_config(window: &MainWindow) {
         }
     };
     let config_file = proj_dirs.config_dir().join("config.toml");
+    // The proxy can only be set in the file, keep it as it is.
+    let proxy = std::fs::read_to_string(&config_file)
+        .ok()
+        .and_then(|config| toml::from_str::<WsrxDesktopConfig>(&config).ok())
+        .and_then(|config| config.proxy);
     let config = WsrxDesktopConfig {
         theme: bridge.get_theme().into(),
         running_in_tray: bridge.get_running_in_tray(),
         language: bridge.get_language().into(),
+        proxy,
     };
     debug!("Saving config: {:?}", config);
     let config = toml::to_string(&config).unwrap_or_else(|e| {
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
 use axum::{
     Json,
@@ -8,6 +8,7 @@ use axum::{
     response::{IntoResponse, Response},
     routing::{get, post},
 };
+use directories::ProjectDirs;
 use i_slint_backend_winit::WinitWindowAccessor;
 use serde::{Deserialize, Serialize};
 use slint::{ComponentHandle, Model, ToSharedString, VecModel};
@@ -15,15 +16,20 @@ use tower_http::{
     cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
 };
-use tracing::{Span, debug};
-use wsrx::utils::create_tcp_listener;
+use tracing::{Span, debug, error, info};
+use wsrx::{
+    capture::Capture, stats::TrafficSnapshot, tunnel::TunnelConfig, utils::create_listener,
+};
 
 use super::latency_worker::update_instance_latency;
 use crate::{
     bridges::ui_state::sync_scoped_instance,
     daemon::{
         latency_worker::update_instance_state,
-        model::{FeatureFlags, InstanceData, ProxyInstance, ScopeData, ServerState},
+        model::{
+            FeatureFlags, InstanceData, ProxyInstance, ScopeData, ServerState, format_traffic,
+            shutdown_instances,
+        },
     },
     ui::{Instance, InstanceBridge, Scope, ScopeBridge},
 };
@@ -59,6 +65,7 @@ pub fn router(state: ServerState) -> axum::Router {
                     "/pool",
                     get(get_instances)
                         .post(launch_instance)
+                        .patch(toggle_capture)
                         .delete(close_instance),
                 )
                 .route("/popup", post(popup_window))
@@ -112,6 +119,9 @@ struct InstanceResponse {
     #[deprecated]
     to: String,
     latency: i32,
+    traffic: TrafficSnapshot,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture: Option<PathBuf>,
 }
 
 impl From<&ProxyInstance> for InstanceResponse {
@@ -124,6 +134,10 @@ impl From<&ProxyInstance> for InstanceResponse {
             from: instance.local.clone(),
             to: instance.remote.clone(),
             latency: instance.latency,
+            traffic: instance.traffic(),
+            capture: instance
+                .capture()
+                .and_then(|capture| capture.path().map(PathBuf::from)),
         }
     }
 }
@@ -159,12 +173,23 @@ async fn launch_instance(
         .unwrap_or_default()
         .to_owned();
 
-    let listener = create_tcp_listener(&instance_data.local).await?;
+    let config = TunnelConfig {
+        local: instance_data.local.clone(),
+        remote: instance_data.remote.clone(),
+        handshake: instance_data.handshake.clone(),
+        proxy: instance_data.proxy.clone(),
+        tls: instance_data.tls.clone(),
+        encryption: instance_data.encryption.clone(),
+        pool_size: instance_data.pool_size,
+        ..Default::default()
+    };
+    config
+        .check_untrusted()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let listener = create_listener(&instance_data.local).await?;
 
-    let local = listener
-        .local_addr()
-        .expect("failed to bind port")
-        .to_string();
+    let local = listener.address().expect("failed to bind port");
 
     let mut instances = state.instances.write().await;
     if instances.iter().any(|i| i.local.as_str() == local) {
@@ -190,8 +215,15 @@ async fn launch_instance(
         instance_data.label.clone(),
         scope.clone(),
         listener,
-        instance_data.remote.clone(),
-    );
+        config,
+        state.events.clone(),
+    )
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to start instance: {e}"),
+        )
+    })?;
 
     let instance_resp: InstanceData = (&instance).into();
     instances.push(instance);
@@ -222,6 +254,7 @@ async fn launch_instance(
             local: local.as_str().into(),
             latency: -1,
             scope_host: scope.as_str().into(),
+            traffic: format_traffic(&Default::default()).into(),
         };
         instances.push(instance);
         sync_scoped_instance(ui_handle.as_weak());
@@ -272,7 +305,7 @@ async fn close_instance(
         ));
     }
 
-    instances.retain(|i| i.local.as_str() != req.local);
+    shutdown_instances(instances.extract_if(.., |i| i.local.as_str() == req.local));
 
     match slint::invoke_from_event_loop(move || {
         let ui_handle = state.ui.upgrade().unwrap();
@@ -306,6 +339,71 @@ async fn close_instance(
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+struct CaptureRequest {
+    #[serde(alias = "key")]
+    pub local: String,
+    pub capture: bool,
+}
+
+/// Starts or stops capturing the traffic of an instance.
+///
+/// Captures are written to the `captures` directory of the local data
+/// directory, websites can't choose where.
+async fn toggle_capture(
+    State(state): State<ServerState>, headers: HeaderMap,
+    axum::Json(req): axum::Json<CaptureRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let scope = headers
+        .get("Origin")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+
+    let instances = state.instances.read().await;
+
+    let Some(instance) = instances
+        .iter()
+        .find(|i| i.local.as_str() == req.local && i.scope_host.as_str() == scope)
+    else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Tunnel {} not found in scope {}", req.local, scope),
+        ));
+    };
+
+    if !req.capture {
+        instance.stop_capture();
+    } else if instance.capture().is_none() {
+        let Some(proj_dirs) = ProjectDirs::from("org", "xdsec", "wsrx") else {
+            error!("Unable to find project data directories");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to find data directory".to_owned(),
+            ));
+        };
+        let capture_dir = proj_dirs.data_local_dir().join("captures");
+        let path = capture_dir.join(Capture::file_name(&instance.local));
+        let capture = std::fs::create_dir_all(&capture_dir)
+            .and_then(|_| Capture::create(&path))
+            .map_err(|e| {
+                error!("Failed to create capture file {}: {e}", path.display());
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("failed to create capture file: {e}"),
+                )
+            })?;
+        info!(
+            "Capturing traffic of {} to {}",
+            instance.local,
+            path.display()
+        );
+        instance.start_capture(capture);
+    }
+
+    Ok(Json(InstanceResponse::from(instance)))
+}
+
 async fn get_control_status(
     State(state): State<ServerState>, headers: HeaderMap,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
@@ -1,10 +1,12 @@
 use reqwest::Method;
 use slint::{ComponentHandle, Model, VecModel};
 use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, warn};
+use wsrx::tunnel::{TunnelEvent, TunnelEventKind};
 
 use super::{
-    model::{FeatureFlags, InstanceData, ServerState},
+    model::{FeatureFlags, InstanceData, ServerState, format_traffic},
     ui_controller::on_instance_del,
 };
 use crate::{
@@ -13,6 +15,7 @@ use crate::{
 };
 
 pub async fn start(state: ServerState) {
+    tokio::spawn(watch_events(state.clone()));
     loop {
         let instances = state.instances.read().await;
         let instances_pure = instances
@@ -44,21 +47,94 @@ pub async fn start(state: ServerState) {
     }
 }
 
+/// Follows the events of all instances, refreshing an instance when its
+/// connections change, and probing it right away when one fails to connect.
+async fn watch_events(state: ServerState) {
+    let mut events = state.events.subscribe();
+    loop {
+        let TunnelEvent { tunnel, kind } = match events.recv().await {
+            Ok(event) => event,
+            // Missed events only delay the refresh until the next one.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let instance = state
+            .instances
+            .read()
+            .await
+            .iter()
+            .find(|instance| instance.local == tunnel)
+            .map(InstanceData::from);
+        let Some(instance) = instance else {
+            continue;
+        };
+        match kind {
+            TunnelEventKind::Connected { .. } | TunnelEventKind::Closed { .. } => {
+                update_instance_state(state.clone(), &instance, instance.latency).await;
+            }
+            TunnelEventKind::Failed { .. } => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let client = reqwest::Client::new();
+                    match update_instance_latency(&instance, &client).await {
+                        Ok(elapsed) => update_instance_state(state, &instance, elapsed).await,
+                        Err(e) => {
+                            update_instance_state(state.clone(), &instance, -1).await;
+                            pingfall(state, instance, e).await;
+                        }
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum LatencyError {
     #[error("Request error: {0}")]
     Rewqest(#[from] reqwest::Error),
     #[error("Non-success status code")]
     NonSuccessStatus(u16),
+    #[error("TLS options error: {0}")]
+    TlsOptions(#[from] std::io::Error),
+}
+
+/// Returns a client that reaches the instance through the same upstream proxy
+/// and with the same TLS options as its tunnel.
+fn probe_client(
+    instance: &InstanceData, client: &reqwest::Client,
+) -> Result<reqwest::Client, LatencyError> {
+    let proxy = reqwest::Url::parse(&instance.remote).ok().and_then(|url| {
+        let host = url.host_str().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        instance.proxy.resolve(host, url.scheme() == "wss")
+    });
+    if proxy.is_none() && instance.tls.is_default() {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.url())?);
+    }
+    if !instance.tls.is_default() {
+        builder = builder.tls_backend_preconfigured((*instance.tls.client_config()?).clone());
+    }
+    Ok(builder.build()?)
 }
 
 pub async fn update_instance_latency(
     instance: &InstanceData, client: &reqwest::Client,
 ) -> Result<i32, LatencyError> {
-    let req = client
+    let client = &probe_client(instance, client)?;
+    let mut req = client
         .request(Method::OPTIONS, instance.remote.replace("ws", "http"))
-        .header("User-Agent", format!("wsrx/{}", env!("CARGO_PKG_VERSION")))
-        .build()?;
+        .header("User-Agent", format!("wsrx/{}", env!("CARGO_PKG_VERSION")));
+    // Gated endpoints reject the probe without the handshake headers.
+    for (name, value) in instance.handshake.http_headers() {
+        req = req.header(name, value);
+    }
+    let req = req.build()?;
 
     let start_time = std::time::Instant::now();
 
@@ -82,6 +158,7 @@ pub async fn update_instance_state(state: ServerState, instance: &InstanceData,
         }
 
         proxy_instance.latency = elapsed;
+        let traffic = format_traffic(&proxy_instance.traffic());
         let window = state.ui.clone();
         let instance = instance.clone();
 
@@ -106,6 +183,7 @@ pub async fn update_instance_state(state: ServerState, instance: &InstanceData,
                         latency: elapsed,
                         label: instance.label.as_str().into(),
                         scope_host: instance.scope_host.as_str().into(),
+                        traffic: traffic.into(),
                     },
                 );
             }
@@ -147,6 +225,8 @@ async fn pingfall(state: ServerState, instance: InstanceData, err: LatencyError)
                         on_instance_del(&state, &instance.local).await;
                     }
                 }
+                // A broken local configuration says nothing about the remote.
+                LatencyError::TlsOptions(_) => {}
             }
         }
     }
@@ -5,7 +5,10 @@ use directories::ProjectDirs;
 use model::{ScopeData, ServerState};
 use serde::{Deserialize, Serialize};
 use slint::{ComponentHandle, Model, ToSharedString, VecModel};
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, broadcast},
+};
 use tracing::{debug, error, info, warn};
 
 use crate::ui::{Instance, InstanceBridge, MainWindow, Scope, ScopeBridge, SettingsBridge};
@@ -68,6 +71,7 @@ pub fn setup(ui: &MainWindow) {
         ui: handle.clone(),
         instances: Arc::new(RwLock::new(vec![])),
         scopes: Arc::new(RwLock::new(scopes.scopes.clone())),
+        events: broadcast::channel(wsrx::tunnel::EVENT_CAPACITY).0,
     };
     // Initialize the global state
     let instances: Rc<VecModel<Instance>> = Rc::new(VecModel::default());
@@ -3,8 +3,19 @@ use std::{collections::HashMap, fmt::Display, sync::Arc};
 use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::{net::TcpListener, sync::RwLock};
-use wsrx::tunnel::Tunnel;
+use tokio::sync::{RwLock, broadcast};
+use wsrx::{
+    capture::Capture,
+    encrypt::EncryptionKey,
+    handshake::Handshake,
+    stats::TrafficSnapshot,
+    tls::TlsOptions,
+    tunnel::{
+        DEFAULT_SHUTDOWN_GRACE, ShutdownStats, Tunnel, TunnelBuilder, TunnelConfig, TunnelEvent,
+        TunnelListener,
+    },
+    upstream::Upstream,
+};
 
 use super::default_label;
 use crate::ui::{Instance, MainWindow};
@@ -21,6 +32,24 @@ pub struct InstanceData {
     pub latency: i32,
     #[serde(default)]
     pub scope_host: String,
+    /// Extra headers, cookies and subprotocols of the WebSocket handshake,
+    /// never sent back to the web client as they may hold secrets.
+    #[serde(default, skip_serializing)]
+    pub handshake: Handshake,
+    /// The upstream proxy of the instance, the global one if not set.
+    #[serde(default)]
+    pub proxy: Upstream,
+    /// TLS options of a `wss://` remote, e.g. extra CA files or pinned keys.
+    #[serde(default)]
+    pub tls: TlsOptions,
+    /// The key the data is encrypted with end to end, if the remote requires
+    /// it, never sent back to the web client.
+    #[serde(default, skip_serializing)]
+    pub encryption: Option<EncryptionKey>,
+    /// How many handshaken WebSocket connections are kept ready, if the
+    /// remote supports pooled connections.
+    #[serde(default)]
+    pub pool_size: Option<usize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -38,6 +67,8 @@ pub struct ServerState {
     pub ui: slint::Weak<MainWindow>,
     pub instances: Arc<RwLock<Vec<ProxyInstance>>>,
     pub scopes: Arc<RwLock<Vec<ScopeData>>>,
+    /// The events of all instances, see [`ProxyInstance::new`].
+    pub events: broadcast::Sender<TunnelEvent>,
 }
 
 bitflags! {
@@ -119,27 +150,90 @@ where
 
 pub struct ProxyInstance {
     pub data: InstanceData,
-    _tunnel: Tunnel,
+    tunnel: Tunnel,
 }
 
 impl ProxyInstance {
     pub fn new(
-        label: impl AsRef<str>, scope_host: impl AsRef<str>, listener: TcpListener,
-        remote: impl AsRef<str>,
-    ) -> Self {
-        let tunnel = Tunnel::new(remote.as_ref(), listener);
-
-        Self {
-            data: InstanceData {
-                label: label.as_ref().to_string(),
-                remote: remote.as_ref().to_string(),
-                local: tunnel.local.clone(),
-                latency: -1,
-                scope_host: scope_host.as_ref().to_string(),
-            },
-            _tunnel: tunnel,
-        }
+        label: impl AsRef<str>, scope_host: impl AsRef<str>, listener: impl Into<TunnelListener>,
+        config: TunnelConfig, events: broadcast::Sender<TunnelEvent>,
+    ) -> Result<Self, wsrx::Error> {
+        let mut data = InstanceData {
+            label: label.as_ref().to_string(),
+            remote: config.remote.clone(),
+            local: String::new(),
+            latency: -1,
+            scope_host: scope_host.as_ref().to_string(),
+            handshake: config.handshake.clone(),
+            proxy: config.proxy.clone(),
+            tls: config.tls.clone(),
+            encryption: config.encryption.clone(),
+            pool_size: config.pool_size,
+        };
+        let tunnel = TunnelBuilder::from(config)
+            .with_events(events)
+            .build(listener)?;
+        data.local = tunnel.local.clone();
+
+        Ok(Self { data, tunnel })
+    }
+
+    pub fn traffic(&self) -> TrafficSnapshot {
+        self.tunnel.traffic()
+    }
+
+    pub fn capture(&self) -> Option<Capture> {
+        self.tunnel.capture()
+    }
+
+    pub fn start_capture(&self, capture: Capture) {
+        self.tunnel.start_capture(capture);
+    }
+
+    pub fn stop_capture(&self) {
+        self.tunnel.stop_capture();
+    }
+
+    /// Stops the instance, letting its connections finish first.
+    pub async fn shutdown(self) -> ShutdownStats {
+        self.tunnel.shutdown(DEFAULT_SHUTDOWN_GRACE).await
+    }
+}
+
+/// Shuts removed instances down in the background, so the UI doesn't wait
+/// for their connections.
+pub fn shutdown_instances(instances: impl IntoIterator<Item = ProxyInstance>) {
+    for instance in instances {
+        tokio::spawn(instance.shutdown());
+    }
+}
+
+/// Formats the traffic of an instance for display, e.g. `1.2 MiB in / 340 B
+/// out`, followed by the compression ratio if compressed, e.g. `(3.1x)`.
+pub fn format_traffic(traffic: &TrafficSnapshot) -> String {
+    let traffic_text = format!(
+        "{} in / {} out",
+        format_bytes(traffic.inbound_bytes),
+        format_bytes(traffic.outbound_bytes)
+    );
+    match traffic.compression_ratio() {
+        Some(ratio) => format!("{traffic_text} ({ratio:.1}x)"),
+        None => traffic_text,
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
+    format!("{value:.1} {}", UNITS[unit])
 }
 
 impl From<&ProxyInstance> for InstanceData {
@@ -156,6 +250,7 @@ impl From<&ProxyInstance> for Instance {
             local: value.local.as_str().into(),
             latency: value.latency,
             scope_host: value.scope_host.as_str().into(),
+            traffic: format_traffic(&value.traffic()).into(),
         }
     }
 }
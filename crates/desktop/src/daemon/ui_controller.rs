@@ -1,6 +1,6 @@
 use slint::{ComponentHandle, Model, ToSharedString, VecModel};
 use tracing::{debug, info, warn};
-use wsrx::utils::create_tcp_listener;
+use wsrx::{tunnel::TunnelConfig, utils::create_listener};
 
 use super::latency_worker::update_instance_latency;
 use crate::{
@@ -8,21 +8,18 @@ use crate::{
     daemon::{
         default_label,
         latency_worker::update_instance_state,
-        model::{ProxyInstance, ServerState},
+        model::{ProxyInstance, ServerState, format_traffic, shutdown_instances},
     },
     ui::{Instance, InstanceBridge, MainWindow, Scope, ScopeBridge},
 };
 
 pub async fn on_instance_add(state: &ServerState, remote: &str, local: &str) {
-    let listener = match create_tcp_listener(local).await {
+    let listener = match create_listener(local).await {
         Ok(listener) => listener,
         Err(_) => return,
     };
 
-    let local = listener
-        .local_addr()
-        .expect("failed to bind port")
-        .to_string();
+    let local = listener.address().expect("failed to bind port");
 
     if state
         .instances
@@ -38,7 +35,23 @@ pub async fn on_instance_add(state: &ServerState, remote: &str, local: &str) {
     let remote = remote.to_string();
     let scope = "default-scope".to_string();
 
-    let instance = ProxyInstance::new(default_label(), &scope, listener, &remote);
+    let config = TunnelConfig {
+        remote: remote.clone(),
+        ..Default::default()
+    };
+    let instance = match ProxyInstance::new(
+        default_label(),
+        &scope,
+        listener,
+        config,
+        state.events.clone(),
+    ) {
+        Ok(instance) => instance,
+        Err(e) => {
+            warn!("Failed to start instance {local}: {e}");
+            return;
+        }
+    };
 
     let state_clone = state.clone();
     let instance_data = (&instance).into();
@@ -70,6 +83,7 @@ pub async fn on_instance_add(state: &ServerState, remote: &str, local: &str) {
             local: local.as_str().into(),
             latency: -1,
             scope_host: scope.as_str().into(),
+            traffic: format_traffic(&Default::default()).into(),
         };
         instances.push(instance);
         sync_scoped_instance(ui_handle.as_weak());
@@ -84,11 +98,13 @@ pub async fn on_instance_add(state: &ServerState, remote: &str, local: &str) {
 }
 
 pub async fn on_instance_del(state: &ServerState, local: &str) {
-    state
-        .instances
-        .write()
-        .await
-        .retain(|instance| instance.local.as_str() != local);
+    shutdown_instances(
+        state
+            .instances
+            .write()
+            .await
+            .extract_if(.., |instance| instance.local.as_str() == local),
+    );
 
     let state = state.clone();
     let local = local.to_string();
@@ -184,11 +200,13 @@ pub async fn on_scope_del(state: &ServerState, ui: slint::Weak<MainWindow>, scop
 
     match removed_scope {
         Some(scope) => {
-            state
-                .instances
-                .write()
-                .await
-                .retain(|i| i.scope_host.as_str() != scope.host);
+            shutdown_instances(
+                state
+                    .instances
+                    .write()
+                    .await
+                    .extract_if(.., |i| i.scope_host.as_str() == scope.host),
+            );
 
             info!("Scope {} removed", scope.host);
         }
@@ -0,0 +1,96 @@
+//! Throughput benchmarks of the proxy path.
+//!
+//! Run with `cargo bench -p wsrx`.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+use tokio_tungstenite::tungstenite::Message as TgMessage;
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::{Decoder, Encoder, Framed},
+    sync::CancellationToken,
+};
+use wsrx::{
+    Message,
+    proxy::{MessageCodec, proxy_stream},
+};
+
+/// The bytes pushed through a session per iteration.
+const PAYLOAD: usize = 16 << 20;
+
+/// The size of a single socket read.
+const CHUNK: usize = 64 << 10;
+
+/// Pushes the payload from one in-memory socket to another through a proxied
+/// session, and reads it back on the other side.
+async fn pump(payload: Bytes) {
+    let (mut client, a) = duplex(CHUNK);
+    let (b, mut server) = duplex(CHUNK);
+    let session = tokio::spawn(proxy_stream(
+        Framed::new(a, MessageCodec::new()),
+        Framed::new(b, MessageCodec::new()),
+        CancellationToken::new(),
+    ));
+
+    let write = async move {
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+        client
+    };
+    let read = async move {
+        let mut buf = vec![0; CHUNK];
+        let mut total = 0;
+        loop {
+            match server.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => total += n,
+            }
+        }
+        assert_eq!(total, PAYLOAD);
+    };
+    let (client, _) = tokio::join!(write, read);
+    drop(client);
+    session.await.unwrap().unwrap();
+}
+
+fn bench_session(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let payload = Bytes::from(vec![0x5A; PAYLOAD]);
+
+    let mut group = c.benchmark_group("session");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64));
+    group.sample_size(20);
+    group.bench_function("tcp_to_tcp", |b| {
+        b.to_async(&runtime).iter(|| pump(payload.clone()))
+    });
+    group.finish();
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let chunk = vec![0xA5; CHUNK];
+
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(CHUNK as u64));
+    group.bench_function("decode_encode", |b| {
+        let mut codec = MessageCodec::new();
+        let mut read = BytesMut::with_capacity(CHUNK);
+        let mut write = BytesMut::with_capacity(CHUNK);
+        b.iter(|| {
+            read.extend_from_slice(&chunk);
+            let msg = codec.decode(&mut read).unwrap().unwrap();
+            codec.encode(msg, &mut write).unwrap();
+            write.clear();
+        })
+    });
+    group.bench_function("from_tungstenite", |b| {
+        let data = Bytes::from(chunk.clone());
+        b.iter(|| match Message::from(TgMessage::Binary(data.clone())) {
+            Message::Binary(data) => std::hint::black_box(data),
+            _ => unreachable!(),
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_session, bench_codec);
+criterion_main!(benches);
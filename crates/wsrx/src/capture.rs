@@ -0,0 +1,831 @@
+//! Traffic capture to pcapng files.
+//!
+//! A [`Capture`] records the stream side of proxied sessions as synthesized
+//! TCP (or UDP) packets, so tools like Wireshark can follow what went over a
+//! tunnel. Each session gets a handshake, one data segment per message in
+//! each direction with real timestamps, and a `FIN` when a side stops
+//! sending, or a `RST` if the session ends before both sides finished.
+//!
+//! Packets are raw IPv4 or IPv6 packets (`LINKTYPE_RAW`), with correct
+//! lengths and checksums. Sessions without socket addresses, such as Unix
+//! sockets or stdio, get loopback addresses with a port of their own.
+//!
+//! Sessions only build their packets, a thread of each capture writes them,
+//! so a slow disk never blocks the runtime.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU16, Ordering},
+        mpsc,
+    },
+    task::{Context, Poll},
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{sink::Sink, stream::Stream};
+
+use crate::proxy::{Error, Message};
+
+/// The link type of raw IPv4 and IPv6 packets.
+const LINKTYPE_RAW: u16 = 101;
+
+/// The largest payload of a synthesized segment, bigger messages are split.
+const MAX_SEGMENT: usize = 65_000;
+
+/// The first port given to sessions without a client address.
+const FIRST_PLACEHOLDER_PORT: u16 = 49152;
+
+/// The server address of sessions without one.
+const PLACEHOLDER_SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 65535);
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+struct Shared {
+    path: Option<PathBuf>,
+    /// The writer of the output, `None` once the capture is closed.
+    writer: Mutex<Option<Writer>>,
+    next_port: AtomicU16,
+}
+
+/// The thread writing the packets of a capture, and the queue of its
+/// packets.
+struct Writer {
+    packets: mpsc::Sender<Vec<u8>>,
+    thread: JoinHandle<()>,
+}
+
+/// A pcapng file that sessions are recorded into.
+///
+/// Cloning the capture yields a handle to the same file. Writes are flushed
+/// whenever the writer caught up with the sessions, so the file can be
+/// opened while sessions are still running.
+#[derive(Clone)]
+pub struct Capture {
+    inner: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Capture")
+            .field("path", &self.inner.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Capture {
+    /// Creates the pcapng file, replacing it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::new(File::create(path)?, Some(path.to_path_buf()))
+    }
+
+    /// Writes the capture to any writer, such as a pipe to Wireshark.
+    pub fn from_writer(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        Self::new(writer, None)
+    }
+
+    fn new(writer: impl Write + Send + 'static, path: Option<PathBuf>) -> io::Result<Self> {
+        let mut out = BufWriter::new(writer);
+        out.write_all(&section_header())?;
+        out.write_all(&interface_description())?;
+        out.flush()?;
+        let (packets, queue) = mpsc::channel::<Vec<u8>>();
+        let thread = std::thread::Builder::new()
+            .name("wsrx-capture".to_string())
+            .spawn(move || {
+                // A full disk shouldn't break the sessions, stop recording
+                // instead.
+                write_packets(&mut out, queue).ok();
+            })?;
+        Ok(Self {
+            inner: Arc::new(Shared {
+                path,
+                writer: Mutex::new(Some(Writer { packets, thread })),
+                next_port: AtomicU16::new(FIRST_PLACEHOLDER_PORT),
+            }),
+        })
+    }
+
+    /// Returns a file name for a capture of the tunnel at `local`, unique
+    /// to the millisecond, e.g. `wsrx-127.0.0.1_8080-1760745600000.pcapng`.
+    pub fn file_name(local: &str) -> String {
+        let local: String = local
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        format!("wsrx-{local}-{millis}.pcapng")
+    }
+
+    /// Returns the path of the file, if created with [`Capture::create`].
+    pub fn path(&self) -> Option<&Path> {
+        self.inner.path.as_deref()
+    }
+
+    /// Writes the queued packets and closes the file, sessions still
+    /// running record nothing from now on.
+    pub fn close(&self) {
+        let writer = self.inner.writer.lock().unwrap().take();
+        if let Some(Writer { packets, thread }) = writer {
+            drop(packets);
+            thread.join().ok();
+        }
+    }
+
+    /// Returns whether the capture was closed, or stopped because writing
+    /// failed.
+    pub fn is_closed(&self) -> bool {
+        self.inner
+            .writer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|writer| writer.thread.is_finished())
+    }
+
+    /// Starts recording a TCP session and writes its handshake.
+    ///
+    /// `client` is the peer that connected to the tunnel, `server` the
+    /// address it connected to, loopback addresses are used if unknown.
+    pub fn tcp_session(
+        &self, client: Option<SocketAddr>, server: Option<SocketAddr>,
+    ) -> CaptureSession {
+        let mut session = self.session(PROTO_TCP, client, server);
+        session.tcp(Side::Client, TCP_SYN, &[]);
+        session.client_seq += 1;
+        session.tcp(Side::Server, TCP_SYN | TCP_ACK, &[]);
+        session.server_seq += 1;
+        session.tcp(Side::Client, TCP_ACK, &[]);
+        session
+    }
+
+    /// Starts recording the datagrams of a UDP peer.
+    pub fn udp_session(
+        &self, client: Option<SocketAddr>, server: Option<SocketAddr>,
+    ) -> CaptureSession {
+        self.session(PROTO_UDP, client, server)
+    }
+
+    fn session(
+        &self, protocol: u8, client: Option<SocketAddr>, server: Option<SocketAddr>,
+    ) -> CaptureSession {
+        let client = client.unwrap_or_else(|| {
+            let port = self.inner.next_port.fetch_add(1, Ordering::Relaxed);
+            let port = port.max(FIRST_PLACEHOLDER_PORT);
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+        });
+        let server = server.unwrap_or(PLACEHOLDER_SERVER);
+        let (client, server) = match (client, server) {
+            (SocketAddr::V4(_), SocketAddr::V6(_)) => (to_ipv6(client), server),
+            (SocketAddr::V6(_), SocketAddr::V4(_)) => (client, to_ipv6(server)),
+            _ => (client, server),
+        };
+        CaptureSession {
+            capture: self.clone(),
+            protocol,
+            client,
+            server,
+            client_seq: 0,
+            server_seq: 0,
+            client_done: false,
+            server_done: false,
+            ip_id: 0,
+        }
+    }
+
+    /// Queues a packet with the current time for writing.
+    fn write_packet(&self, packet: &[u8]) {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let Some(Writer { packets, .. }) = writer.as_ref() else {
+            return;
+        };
+        let block = enhanced_packet(SystemTime::now(), packet);
+        // The writer stops once writing fails.
+        if packets.send(block).is_err() {
+            *writer = None;
+        }
+    }
+}
+
+/// Writes the queued packets until the capture is closed, flushing whenever
+/// the queue is empty.
+fn write_packets(out: &mut impl Write, queue: mpsc::Receiver<Vec<u8>>) -> io::Result<()> {
+    while let Ok(block) = queue.recv() {
+        out.write_all(&block)?;
+        while let Ok(block) = queue.try_recv() {
+            out.write_all(&block)?;
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        v6 => v6,
+    }
+}
+
+/// Which side of a session sent a packet.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Side {
+    Client,
+    Server,
+}
+
+/// The recording of a single session.
+///
+/// Dropping it before both sides finished records a reset.
+pub struct CaptureSession {
+    capture: Capture,
+    protocol: u8,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+    client_done: bool,
+    server_done: bool,
+    ip_id: u16,
+}
+
+impl CaptureSession {
+    /// Records data sent by the client.
+    pub fn client_data(&mut self, data: &[u8]) {
+        self.data(Side::Client, data);
+    }
+
+    /// Records data sent by the server.
+    pub fn server_data(&mut self, data: &[u8]) {
+        self.data(Side::Server, data);
+    }
+
+    /// Records that the client stopped sending.
+    pub fn client_fin(&mut self) {
+        self.fin(Side::Client);
+    }
+
+    /// Records that the server stopped sending.
+    pub fn server_fin(&mut self) {
+        self.fin(Side::Server);
+    }
+
+    fn data(&mut self, side: Side, data: &[u8]) {
+        if self.protocol == PROTO_UDP {
+            self.udp(side, data);
+            return;
+        }
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.tcp(side, TCP_PSH | TCP_ACK, segment);
+            let seq = match side {
+                Side::Client => &mut self.client_seq,
+                Side::Server => &mut self.server_seq,
+            };
+            *seq = seq.wrapping_add(segment.len() as u32);
+        }
+    }
+
+    fn fin(&mut self, side: Side) {
+        let done = match side {
+            Side::Client => &mut self.client_done,
+            Side::Server => &mut self.server_done,
+        };
+        if std::mem::replace(done, true) || self.protocol == PROTO_UDP {
+            return;
+        }
+        self.tcp(side, TCP_FIN | TCP_ACK, &[]);
+        // The FIN takes a sequence number of its own.
+        let seq = match side {
+            Side::Client => &mut self.client_seq,
+            Side::Server => &mut self.server_seq,
+        };
+        *seq = seq.wrapping_add(1);
+    }
+
+    /// Writes a TCP segment from one side with the current sequence numbers.
+    fn tcp(&mut self, side: Side, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = match side {
+            Side::Client => (self.client, self.server, self.client_seq, self.server_seq),
+            Side::Server => (self.server, self.client, self.server_seq, self.client_seq),
+        };
+        let ack = if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+            0
+        } else {
+            ack
+        };
+        let mut segment = Vec::with_capacity(20 + payload.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.push(5 << 4);
+        segment.push(flags);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes());
+        segment.extend_from_slice(&[0; 4]);
+        segment.extend_from_slice(payload);
+        self.ip(src.ip(), dst.ip(), PROTO_TCP, segment, 16);
+    }
+
+    /// Writes a UDP datagram from one side.
+    fn udp(&mut self, side: Side, payload: &[u8]) {
+        let (src, dst) = match side {
+            Side::Client => (self.client, self.server),
+            Side::Server => (self.server, self.client),
+        };
+        let len = (8 + payload.len()).min(u16::MAX as usize) as u16;
+        let mut datagram = Vec::with_capacity(8 + payload.len());
+        datagram.extend_from_slice(&src.port().to_be_bytes());
+        datagram.extend_from_slice(&dst.port().to_be_bytes());
+        datagram.extend_from_slice(&len.to_be_bytes());
+        datagram.extend_from_slice(&[0; 2]);
+        datagram.extend_from_slice(payload);
+        self.ip(src.ip(), dst.ip(), PROTO_UDP, datagram, 6);
+    }
+
+    /// Fills in the transport checksum at `checksum_at`, and writes the
+    /// transport segment in an IP packet.
+    fn ip(
+        &mut self, src: IpAddr, dst: IpAddr, protocol: u8, mut segment: Vec<u8>, checksum_at: usize,
+    ) {
+        let len = segment.len();
+        let mut sum = Checksum::default();
+        let mut packet = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                sum.add(&src.octets());
+                sum.add(&dst.octets());
+                sum.add(&[0, protocol]);
+                sum.add(&(len as u16).to_be_bytes());
+                ipv4_header(src, dst, protocol, len, self.next_ip_id())
+            }
+            (src, dst) => {
+                let (src, dst) = (to_v6(src), to_v6(dst));
+                sum.add(&src.octets());
+                sum.add(&dst.octets());
+                sum.add(&(len as u32).to_be_bytes());
+                sum.add(&[0, 0, 0, protocol]);
+                ipv6_header(src, dst, protocol, len)
+            }
+        };
+        sum.add(&segment);
+        let checksum = match sum.finish() {
+            // A zero UDP checksum means none was computed.
+            0 if protocol == PROTO_UDP => 0xFFFF,
+            checksum => checksum,
+        };
+        segment[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(&segment);
+        self.capture.write_packet(&packet);
+    }
+
+    fn next_ip_id(&mut self) -> u16 {
+        self.ip_id = self.ip_id.wrapping_add(1);
+        self.ip_id
+    }
+}
+
+impl Drop for CaptureSession {
+    fn drop(&mut self) {
+        if self.protocol == PROTO_TCP && !(self.client_done && self.server_done) {
+            let side = match self.client_done {
+                true => Side::Server,
+                false => Side::Client,
+            };
+            self.tcp(side, TCP_RST | TCP_ACK, &[]);
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize, id: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(20 + len);
+    header.push(0x45);
+    header.push(0);
+    header.extend_from_slice(&((20 + len).min(u16::MAX as usize) as u16).to_be_bytes());
+    header.extend_from_slice(&id.to_be_bytes());
+    // Don't fragment.
+    header.extend_from_slice(&0x4000u16.to_be_bytes());
+    header.push(64);
+    header.push(protocol);
+    header.extend_from_slice(&[0; 2]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    let mut sum = Checksum::default();
+    sum.add(&header);
+    header[10..12].copy_from_slice(&sum.finish().to_be_bytes());
+    header
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, protocol: u8, len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(40 + len);
+    header.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+    header.extend_from_slice(&(len.min(u16::MAX as usize) as u16).to_be_bytes());
+    header.push(protocol);
+    header.push(64);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    header
+}
+
+/// The internet checksum of RFC 1071.
+#[derive(Default)]
+struct Checksum {
+    sum: u32,
+    /// An odd byte left over from the previous data.
+    odd: Option<u8>,
+}
+
+impl Checksum {
+    fn add(&mut self, mut data: &[u8]) {
+        if let Some(high) = self.odd.take()
+            && let Some((&low, rest)) = data.split_first()
+        {
+            self.add_word(u16::from_be_bytes([high, low]));
+            data = rest;
+        }
+        let mut words = data.chunks_exact(2);
+        for word in &mut words {
+            self.add_word(u16::from_be_bytes([word[0], word[1]]));
+        }
+        if let [last] = words.remainder() {
+            self.odd = Some(*last);
+        }
+    }
+
+    fn add_word(&mut self, word: u16) {
+        self.sum += u32::from(word);
+        self.sum = (self.sum & 0xFFFF) + (self.sum >> 16);
+    }
+
+    fn finish(mut self) -> u16 {
+        if let Some(high) = self.odd.take() {
+            self.add_word(u16::from_be_bytes([high, 0]));
+        }
+        !(self.sum as u16)
+    }
+}
+
+/// Builds a pcapng block of the given type around its body.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padded = body.len().next_multiple_of(4);
+    let len = (12 + padded) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(8 + padded, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // The length of the section is not known in advance.
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(0x0A0D_0D0A, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snapshot length limit.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    block(1, &body)
+}
+
+fn enhanced_packet(time: SystemTime, packet: &[u8]) -> Vec<u8> {
+    // Timestamps are in microseconds, the default resolution.
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    block(6, &body)
+}
+
+/// A wrapper that records the stream side of a session into a capture.
+///
+/// Messages read from the wrapped stream are sent by the client of the
+/// capture, and messages written to it by the server. Without a session
+/// the wrapper only passes messages through.
+pub struct Captured<S> {
+    inner: S,
+    session: Option<CaptureSession>,
+}
+
+impl<S> Captured<S> {
+    /// Wraps a stream, recording it into the session if any.
+    pub fn new(inner: S, session: Option<CaptureSession>) -> Self {
+        Self { inner, session }
+    }
+}
+
+/// A wrapper around captured stream that implements `Stream` trait.
+impl<S> Stream for Captured<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    /// Polls the next message, recording its data as sent by the client.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let msg = futures_util::ready!(Pin::new(&mut this.inner).poll_next(cx));
+        if let Some(session) = &mut this.session {
+            match &msg {
+                Some(Ok(Message::Binary(data))) => session.client_data(data),
+                None => session.client_fin(),
+                _ => {}
+            }
+        }
+        Poll::Ready(msg)
+    }
+}
+
+/// A wrapper around captured stream that implements `Sink` trait.
+impl<S> Sink<Message> for Captured<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    /// Polls the wrapped sink if it is ready to send a message.
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    /// Sends a message to the wrapped sink, recording its data as sent by
+    /// the server.
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let (Message::Binary(data), Some(session)) = (&item, &mut self.session) {
+            session.server_data(data);
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    /// Polls the wrapped sink to flush the message.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    /// Polls the wrapped sink to close the connection, recording that the
+    /// server stopped sending.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let res = futures_util::ready!(Pin::new(&mut self.inner).poll_close(cx));
+        if let (Ok(()), Some(session)) = (&res, &mut self.session) {
+            session.server_fin();
+        }
+        Poll::Ready(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer into memory that can be read while the capture is open.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A writer that fails after the given number of writes.
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.0 {
+                0 => Err(io::Error::from(io::ErrorKind::StorageFull)),
+                _ => {
+                    self.0 -= 1;
+                    Ok(buf.len())
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Splits a pcapng file into its blocks, checking their lengths.
+    fn blocks(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let kind = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&data[len - 4..len], &data[4..8]);
+            blocks.push((kind, &data[8..len - 4]));
+            data = &data[len..];
+        }
+        blocks
+    }
+
+    /// Returns the IP packets of a capture.
+    fn packets(data: &[u8]) -> Vec<Vec<u8>> {
+        let blocks = blocks(data);
+        assert_eq!(blocks[0].0, 0x0A0D_0D0A);
+        assert_eq!(&blocks[0].1[..4], &0x1A2B_3C4Du32.to_le_bytes());
+        assert_eq!(blocks[1].0, 1);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_RAW.to_le_bytes());
+        blocks[2..]
+            .iter()
+            .map(|(kind, body)| {
+                assert_eq!(*kind, 6);
+                let len = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+                body[20..20 + len].to_vec()
+            })
+            .collect()
+    }
+
+    /// Checks the checksums of an IPv4 packet, returning the protocol and
+    /// the transport segment.
+    fn ipv4(packet: &[u8]) -> (u8, &[u8]) {
+        let mut sum = Checksum::default();
+        sum.add(&packet[..20]);
+        assert_eq!(sum.finish(), 0);
+        assert_eq!(
+            u16::from_be_bytes([packet[2], packet[3]]) as usize,
+            packet.len()
+        );
+        let segment = &packet[20..];
+        let mut sum = Checksum::default();
+        sum.add(&packet[12..20]);
+        sum.add(&[0, packet[9]]);
+        sum.add(&(segment.len() as u16).to_be_bytes());
+        sum.add(segment);
+        assert_eq!(sum.finish(), 0);
+        (packet[9], segment)
+    }
+
+    fn client() -> Option<SocketAddr> {
+        Some("127.0.0.1:40000".parse().unwrap())
+    }
+
+    fn server() -> Option<SocketAddr> {
+        Some("127.0.0.1:8080".parse().unwrap())
+    }
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        let mut sum = Checksum::default();
+        sum.add(&[0x00, 0x01, 0xF2]);
+        sum.add(&[0x03, 0xF4, 0xF5, 0xF6, 0xF7]);
+        assert_eq!(sum.finish(), 0x220D);
+    }
+
+    #[test]
+    fn tcp_sessions_are_recorded() {
+        let buffer = Buffer::default();
+        let capture = Capture::from_writer(buffer.clone()).unwrap();
+        let mut session = capture.tcp_session(client(), server());
+        session.client_data(b"hello");
+        session.server_data(b"hi");
+        session.client_fin();
+        session.server_fin();
+        drop(session);
+        capture.close();
+        assert!(capture.is_closed());
+
+        let packets = packets(&buffer.0.lock().unwrap());
+        let segments = packets
+            .iter()
+            .map(|packet| {
+                let (protocol, segment) = ipv4(packet);
+                assert_eq!(protocol, PROTO_TCP);
+                let seq = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+                (segment[13], seq, segment[20..].to_vec())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            segments,
+            [
+                (TCP_SYN, 0, vec![]),
+                (TCP_SYN | TCP_ACK, 0, vec![]),
+                (TCP_ACK, 1, vec![]),
+                (TCP_PSH | TCP_ACK, 1, b"hello".to_vec()),
+                (TCP_PSH | TCP_ACK, 1, b"hi".to_vec()),
+                (TCP_FIN | TCP_ACK, 6, vec![]),
+                (TCP_FIN | TCP_ACK, 3, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unfinished_tcp_sessions_are_reset() {
+        let buffer = Buffer::default();
+        let capture = Capture::from_writer(buffer.clone()).unwrap();
+        drop(capture.tcp_session(None, None));
+        capture.close();
+
+        let packets = packets(&buffer.0.lock().unwrap());
+        let (_, segment) = ipv4(packets.last().unwrap());
+        assert_eq!(segment[13], TCP_RST | TCP_ACK);
+        // Sessions without addresses get a placeholder port.
+        assert_eq!(
+            u16::from_be_bytes([segment[0], segment[1]]),
+            FIRST_PLACEHOLDER_PORT
+        );
+    }
+
+    #[test]
+    fn udp_sessions_are_recorded() {
+        let buffer = Buffer::default();
+        let capture = Capture::from_writer(buffer.clone()).unwrap();
+        let mut session = capture.udp_session(client(), server());
+        session.client_data(b"ping");
+        session.server_data(b"");
+        drop(session);
+        capture.close();
+
+        let packets = packets(&buffer.0.lock().unwrap());
+        assert_eq!(packets.len(), 2);
+        let (protocol, datagram) = ipv4(&packets[0]);
+        assert_eq!(protocol, PROTO_UDP);
+        assert_eq!(&datagram[8..], b"ping");
+        let (_, datagram) = ipv4(&packets[1]);
+        assert_eq!(u16::from_be_bytes([datagram[4], datagram[5]]), 8);
+    }
+
+    #[test]
+    fn mixed_addresses_are_recorded_as_ipv6() {
+        let buffer = Buffer::default();
+        let capture = Capture::from_writer(buffer.clone()).unwrap();
+        let mut session = capture.udp_session(client(), Some("[::1]:53".parse().unwrap()));
+        session.client_data(b"query");
+        drop(session);
+        capture.close();
+
+        let packets = packets(&buffer.0.lock().unwrap());
+        assert_eq!(packets[0][0] >> 4, 6);
+        assert_eq!(&packets[0][48..], b"query");
+    }
+
+    #[test]
+    fn closed_captures_record_nothing() {
+        let buffer = Buffer::default();
+        let capture = Capture::from_writer(buffer.clone()).unwrap();
+        let mut session = capture.udp_session(client(), server());
+        capture.close();
+        session.client_data(b"late");
+        assert!(packets(&buffer.0.lock().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn failed_writes_stop_the_capture() {
+        let capture = Capture::from_writer(Full(1)).unwrap();
+        let mut session = capture.udp_session(client(), server());
+        session.client_data(b"lost");
+        while !capture.is_closed() {
+            std::thread::yield_now();
+        }
+        session.client_data(b"dropped");
+    }
+}
@@ -1,32 +1,143 @@
-use std::sync::Arc;
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::Duration,
+};
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::broadcast,
+};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use url::Url;
-use wsrx::proxy;
+use wsrx::{
+    FrameEncoding, FrameLimits, Keepalive,
+    capture::{Capture, Captured},
+    deflate::Compression,
+    encrypt::EncryptionKey,
+    handshake::Handshake,
+    idle::IdleTimeout,
+    observe::Observer,
+    proxy::proxy_stream_observed,
+    stats::TrafficCounter,
+    tls::TlsOptions,
+    tunnel::{EVENT_CAPACITY, TunnelBuilder, TunnelConfig, TunnelEventKind, TunnelListener},
+    unix::UNIX_PREFIX,
+    utils::create_unix_listener,
+};
 
-use crate::cli::logger::init_logger;
+use crate::cli::logger::{init_logger, init_stderr_logger};
 
-pub async fn launch(
-    address: String, host: Option<String>, port: Option<u16>, log_json: Option<bool>,
-) {
-    let log_json = log_json.unwrap_or(false);
-    init_logger(log_json);
-    let port = port.unwrap_or(0);
-    let host = host.unwrap_or(String::from("127.0.0.1"));
+/// How the connections of a tunnel are opened and limited.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionOptions {
+    /// How long a WebSocket handshake may take.
+    pub connect_timeout: Duration,
+    /// How many times a failed connection is retried.
+    pub connect_retries: u32,
+    /// How many connections may be open at once.
+    pub max_connections: Option<usize>,
+    /// How long a connection may stay idle before it is closed.
+    pub idle_timeout: Option<Duration>,
+    /// How many handshaken connections are kept ready.
+    pub pool_size: Option<usize>,
+}
+
+/// What `wsrx connect` forwards, and how it talks to the remote.
+pub struct ConnectOptions {
+    /// The WebSocket url of the remote.
+    pub address: String,
+    /// The local host to listen on, or `unix:` and the path of a socket.
+    pub host: Option<String>,
+    /// The local port to listen on, a random one if not set.
+    pub port: Option<u16>,
+    /// Forward UDP datagrams instead of TCP connections.
+    pub udp: bool,
+    /// Carry the connections as streams of one WebSocket.
+    pub mux: bool,
+    /// Keep sessions through reconnects of their WebSocket.
+    pub resume: bool,
+    pub encoding: FrameEncoding,
+    pub compression: Option<Compression>,
+    pub encryption: Option<EncryptionKey>,
+    pub frame_limits: FrameLimits,
+    pub keepalive: Option<Keepalive>,
+    pub handshake: Handshake,
+    pub tls: TlsOptions,
+    pub connection: ConnectionOptions,
+    /// The file mode of a Unix socket listener.
+    pub unix_mode: Option<u32>,
+    /// The pcapng file the traffic is captured to.
+    pub capture: Option<PathBuf>,
+    pub observer: Option<Arc<dyn Observer>>,
+    pub log_json: Option<bool>,
+}
+
+impl ConnectOptions {
+    /// Builds the configuration of a tunnel to the WebSocket url.
+    fn tunnel_config(&self, url: String) -> TunnelConfig {
+        TunnelConfig {
+            remote: url,
+            mux: self.mux,
+            resume: self.resume,
+            encoding: self.encoding,
+            compression: self.compression,
+            encryption: self.encryption.clone(),
+            frame_limits: self.frame_limits,
+            ping_interval: self.keepalive.map(|k| k.interval),
+            max_missed_pongs: self.keepalive.map(|k| k.max_missed),
+            handshake: self.handshake.clone(),
+            tls: self.tls.clone(),
+            connect_timeout: Some(self.connection.connect_timeout),
+            connect_retries: Some(self.connection.connect_retries),
+            max_connections: self.connection.max_connections,
+            idle_timeout: self.connection.idle_timeout,
+            pool_size: self.connection.pool_size,
+            ..Default::default()
+        }
+    }
+}
+
+pub async fn launch(options: ConnectOptions) {
+    init_logger(options.log_json.unwrap_or(false));
+    let capture = match options.capture.as_deref().map(create_capture) {
+        Some(None) => return,
+        capture => capture.flatten(),
+    };
+    let port = options.port.unwrap_or(0);
+    let host = options.host.clone().unwrap_or(String::from("127.0.0.1"));
+    if let Some(path) = host.strip_prefix(UNIX_PREFIX) {
+        if options.udp {
+            error!("UDP can not be forwarded from a unix socket.");
+            return;
+        }
+        let Some(url) = parse_url(&options.address) else {
+            return;
+        };
+        let config = options.tunnel_config(url);
+        return launch_unix(path, options.unix_mode, config, capture, options.observer).await;
+    }
+    if options.udp {
+        let Some(url) = parse_url(&options.address) else {
+            return;
+        };
+        let config = TunnelConfig {
+            mux: false,
+            resume: false,
+            ..options.tunnel_config(url)
+        };
+        return launch_udp(host, port, config, capture, options.observer).await;
+    }
     let listener = TcpListener::bind(format!("{host}:{port}"))
         .await
         .expect("failed to bind port");
-    let Ok(url) = Url::parse(&address) else {
-        error!("Invalid url, please check your input.");
+    let Some(url) = parse_url(&options.address) else {
         return;
     };
-    if url.scheme() != "ws" && url.scheme() != "wss" {
-        error!("Invalid url scheme, only `ws` and `wss` are supported.");
-        return;
-    }
-    let url = url.as_ref().to_string();
+    let config = options.tunnel_config(url);
     info!(
         "Hi, I am not RX, RX is here -> {}",
         listener.local_addr().unwrap()
@@ -34,46 +145,166 @@ pub async fn launch(
     warn!(
         "wsrx will not report non-critical errors by default, you can set `RUST_LOG=wsrx=debug` to see more details."
     );
+    run(config, listener, capture, options.observer).await;
+}
+
+/// Pipe stdin and stdout through a single WebSocket session, then exit.
+///
+/// This is meant for ssh `ProxyCommand` and interactive sessions, the process
+/// exits with `0` once the session ends cleanly and `1` on errors. End of
+/// input (Ctrl-D on a terminal) only closes the sending side, and Ctrl-C
+/// closes the session. With `resume`, the session survives reconnects of its
+/// WebSocket. The local listener options are ignored.
+pub async fn launch_stdio(options: ConnectOptions) {
+    init_stderr_logger(options.log_json.unwrap_or(false));
+    let Some(url) = parse_url(&options.address) else {
+        process::exit(2);
+    };
+    let capture = match options.capture.as_deref().map(create_capture) {
+        Some(None) => process::exit(2),
+        capture => capture.flatten(),
+    };
+    let config = TunnelConfig {
+        mux: false,
+        ..options.tunnel_config(url)
+    };
+    let frame_limits = options.frame_limits;
+    let interactive = std::io::stdin().is_terminal();
 
     let token = CancellationToken::new();
-    let url = Arc::new(url);
+    let ctrl_c = token.clone();
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c().await.ok();
+        ctrl_c.cancel();
+    });
 
-    // This loop will "run forever"
-    loop {
-        let Ok((tcp, _)) = listener.accept().await else {
-            error!("Failed to accept tcp connection, exiting.");
-            token.cancel();
-            return;
+    let counter = TrafficCounter::new();
+    let observer = options.observer.as_deref();
+    let res = async {
+        let connected = || {
+            if interactive {
+                eprintln!("connected, Ctrl-D ends input, Ctrl-C closes the session");
+            }
         };
+        let stdio = frame_limits.framed(tokio::io::join(tokio::io::stdin(), tokio::io::stdout()));
+        let stdio = Captured::new(stdio, capture.map(|c| c.tcp_session(None, None)));
+        let stdio = IdleTimeout::new(stdio, config.idle_timeout(), token.clone());
+        if config.resume
+            && let Some(stream) = config.retry(&token, || config.connect_resumable()).await?
+        {
+            connected();
+            let token = stdio.token();
+            return proxy_stream_observed(stream, stdio, token, &counter, observer, "stdio").await;
+        }
+        let ws = config.retry(&token, || config.connect()).await?;
+        connected();
+        let token = stdio.token();
+        proxy_stream_observed(ws, stdio, token, &counter, observer, "stdio").await
+    }
+    .await;
 
-        if token.is_cancelled() {
-            return;
+    // Reading stdin blocks a thread that can't be interrupted, exit right
+    // away instead of waiting for the next line on a terminal.
+    match res {
+        Ok(stats) => {
+            debug!(
+                "session closed: {} bytes in, {} bytes out in {:?}",
+                stats.inbound_bytes, stats.outbound_bytes, stats.duration
+            );
+            process::exit(0);
+        }
+        Err(e) => {
+            error!("session failed: {e}");
+            process::exit(1);
         }
+    }
+}
 
-        let url = url.clone();
-        let peer_addr = tcp.peer_addr().unwrap();
+/// Creates the capture file, logging failures.
+fn create_capture(path: &Path) -> Option<Capture> {
+    match Capture::create(path) {
+        Ok(capture) => {
+            info!("Capturing traffic to {}", path.display());
+            Some(capture)
+        }
+        Err(e) => {
+            error!("Failed to create capture file {}: {e}", path.display());
+            None
+        }
+    }
+}
 
-        info!("CREATE remote <-wsrx-> {}", peer_addr);
+/// Forward UDP datagrams, every peer gets its own WebSocket session.
+async fn launch_udp(
+    host: String, port: u16, config: TunnelConfig, capture: Option<Capture>,
+    observer: Option<Arc<dyn Observer>>,
+) {
+    let socket = UdpSocket::bind(format!("{host}:{port}"))
+        .await
+        .expect("failed to bind port");
+    info!(
+        "Hi, I am not RX, RX is here -> udp:{}",
+        socket.local_addr().unwrap()
+    );
+    run(config, socket, capture, observer).await;
+}
 
-        let token = token.clone();
-        tokio::spawn(async move {
-            match proxy_ws_addr(url.as_ref(), tcp, token).await {
-                Ok(_) => {}
-                Err(e) => {
-                    info!("REMOVE remote <-wsrx-> {} with error", peer_addr);
-                    debug!("TCP connection closed: {}", e);
-                }
-            }
-        });
+/// Forward connections to a Unix socket, every connection gets its own
+/// WebSocket session. The socket file is removed on exit.
+async fn launch_unix(
+    path: &str, mode: Option<u32>, config: TunnelConfig, capture: Option<Capture>,
+    observer: Option<Arc<dyn Observer>>,
+) {
+    // Failures are logged by `create_unix_listener`.
+    let Ok(listener) = create_unix_listener(path, mode).await else {
+        return;
+    };
+    info!("Hi, I am not RX, RX is here -> {UNIX_PREFIX}{path}");
+    run(config, listener, capture, observer).await;
+}
+
+/// Parse and validate the WebSocket url.
+fn parse_url(address: &str) -> Option<String> {
+    let Ok(url) = Url::parse(address) else {
+        error!("Invalid url, please check your input.");
+        return None;
+    };
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        error!("Invalid url scheme, only `ws` and `wss` are supported.");
+        return None;
     }
+    Some(url.as_ref().to_string())
 }
 
-async fn proxy_ws_addr(
-    addr: impl AsRef<str>, tcp: TcpStream, token: CancellationToken,
-) -> Result<(), wsrx::Error> {
-    let peer_addr = tcp.peer_addr().unwrap();
-    let (ws, _) = tokio_tungstenite::connect_async(addr.as_ref()).await?;
-    proxy(ws.into(), tcp, token).await?;
-    info!("REMOVE remote <-wsrx-> {}", peer_addr);
-    Ok(())
+/// Runs a tunnel on the listener until Ctrl-C or until it stops accepting.
+async fn run(
+    config: TunnelConfig, listener: impl Into<TunnelListener>, capture: Option<Capture>,
+    observer: Option<Arc<dyn Observer>>,
+) {
+    let (events, mut receiver) = broadcast::channel(EVENT_CAPACITY);
+    let _tunnel = match TunnelBuilder::from(config)
+        .with_events(events)
+        .with_observer(observer)
+        .with_capture(capture)
+        .build(listener)
+    {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            error!("Failed to start tunnel: {e}");
+            return;
+        }
+    };
+    let stopped = async {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if matches!(event.kind, TunnelEventKind::Stopped { .. }) => return,
+                Err(broadcast::error::RecvError::Closed) => return,
+                _ => {}
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = stopped => error!("Failed to accept connections, exiting."),
+    }
 }
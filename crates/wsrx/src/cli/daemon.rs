@@ -1,6 +1,7 @@
 use std::{
     collections::HashMap,
     ops::Deref,
+    path::PathBuf,
     sync::{Arc, RwLock as SyncRwLock},
     time::Duration,
 };
@@ -9,34 +10,53 @@ use axum::{
     Json,
     body::Body,
     extract::{FromRef, Request as ExtractRequest, State},
-    http::{HeaderMap, HeaderValue, Method, Request, StatusCode, header::CONTENT_TYPE},
+    http::{
+        HeaderMap, HeaderValue, Method, Request, StatusCode,
+        header::{CONTENT_TYPE, ORIGIN},
+    },
     middleware::Next,
-    response::{IntoResponse, Response},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::get,
 };
 use chrono::{DateTime, Utc};
+use futures_util::{Stream, stream};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, sync::RwLock};
+use tokio::{
+    net::TcpListener,
+    sync::{
+        RwLock,
+        broadcast::{self, error::RecvError},
+    },
+};
 use tower_http::{
     cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
 };
 use tracing::{Span, debug, error, info};
 use wsrx::{
-    tunnel::{Tunnel, TunnelConfig},
-    utils::create_tcp_listener,
+    capture::Capture,
+    tunnel::{
+        DEFAULT_SHUTDOWN_GRACE, EVENT_CAPACITY, Tunnel, TunnelBuilder, TunnelConfig, TunnelEvent,
+    },
+    utils::create_listener_with_mode,
 };
 
-use crate::cli::logger::init_logger;
+use crate::cli::{
+    logger::init_logger,
+    tls::{TlsFiles, serve},
+};
 
 pub async fn launch(
     host: Option<String>, port: Option<u16>, secret: Option<String>, log_json: Option<bool>,
-    heartbeat: Option<u64>,
+    heartbeat: Option<u64>, tls: Option<TlsFiles>, capture_dir: PathBuf,
 ) {
     let log_json = log_json.unwrap_or(false);
     init_logger(log_json);
-    let router = build_router(secret);
+    let router = build_router(secret, capture_dir);
     let listener = TcpListener::bind(&format!(
         "{}:{}",
         host.unwrap_or(String::from("127.0.0.1")),
@@ -49,15 +69,14 @@ pub async fn launch(
         listener.local_addr().expect("failed to bind port")
     );
     info!(
-        "you can access manage api at http://{}/pool",
+        "you can access manage api at {}://{}/pool",
+        if tls.is_some() { "https" } else { "http" },
         listener.local_addr().expect("failed to bind port")
     );
     if let Some(interval) = heartbeat {
         tokio::spawn(heartbeat_watchdog(interval));
     }
-    axum::serve(listener, router)
-        .await
-        .expect("failed to launch server");
+    serve(listener, router, tls).await;
 }
 
 type ConnectionMap = Arc<RwLock<HashMap<String, Tunnel>>>;
@@ -66,6 +85,10 @@ type ConnectionMap = Arc<RwLock<HashMap<String, Tunnel>>>;
 pub struct GlobalState {
     pub secret: Option<String>,
     pub connections: ConnectionMap,
+    /// The directory traffic captures are written to.
+    pub capture_dir: Arc<PathBuf>,
+    /// The events of every tunnel.
+    pub events: broadcast::Sender<TunnelEvent>,
 }
 
 static ALLOWED_ORIGINS: Lazy<Arc<SyncRwLock<Vec<String>>>> =
@@ -98,13 +121,15 @@ async fn heartbeat_watchdog(interval: u64) {
     }
 }
 
-fn build_router(secret: Option<String>) -> axum::Router {
+fn build_router(secret: Option<String>, capture_dir: PathBuf) -> axum::Router {
     let state = GlobalState {
         secret,
         connections: Default::default(),
+        capture_dir: Arc::new(capture_dir),
+        events: broadcast::channel(EVENT_CAPACITY).0,
     };
     let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
         .allow_headers(Any)
         .allow_origin(AllowOrigin::predicate(
             |origin: &HeaderValue, _request_parts: &_| {
@@ -126,8 +151,12 @@ fn build_router(secret: Option<String>) -> axum::Router {
             axum::Router::new()
                 .route(
                     "/pool",
-                    get(get_tunnels).post(launch_tunnel).delete(close_tunnel),
+                    get(get_tunnels)
+                        .post(launch_tunnel)
+                        .patch(toggle_capture)
+                        .delete(close_tunnel),
                 )
+                .route("/events", get(stream_events))
                 .route("/heartbeat", get(update_heartbeat))
                 .route(
                     "/access",
@@ -180,14 +209,32 @@ fn build_router(secret: Option<String>) -> axum::Router {
         .with_state::<()>(state)
 }
 
+/// Starts a tunnel, configurations sent by web pages are checked with
+/// [`TunnelConfig::check_untrusted`] first.
 async fn launch_tunnel(
-    State(connections): State<ConnectionMap>, axum::Json(req): axum::Json<TunnelConfig>,
+    State(connections): State<ConnectionMap>, State(events): State<broadcast::Sender<TunnelEvent>>,
+    headers: HeaderMap, axum::Json(req): axum::Json<TunnelConfig>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // Browsers send the origin of every cross-origin request, other local
+    // clients are trusted like the user who runs them.
+    if headers.contains_key(ORIGIN) {
+        req.check_untrusted()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    }
     let mut pool = connections.write().await;
 
-    let listener = create_tcp_listener(req.local.as_str()).await?;
+    let listener = create_listener_with_mode(req.local.as_str(), req.unix_mode).await?;
 
-    let tunnel = Tunnel::new(req.remote, listener);
+    let tunnel = TunnelBuilder::from(req)
+        .with_events(events)
+        .build(listener)
+        .map_err(|e| {
+            error!("Failed to start tunnel: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to start tunnel: {e}"),
+            )
+        })?;
 
     let resp = serde_json::to_string(&tunnel).map_err(|e| {
         error!("Failed to serialize tunnel: {e:?}");
@@ -202,6 +249,25 @@ async fn launch_tunnel(
     resp
 }
 
+/// Streams the events of every tunnel as server-sent events, one JSON
+/// object each.
+async fn stream_events(
+    State(events): State<broadcast::Sender<TunnelEvent>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = stream::unfold(events.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((Event::default().json_data(event), events)),
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Event subscriber fell behind, skipped {skipped} events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
 async fn get_tunnels(State(connections): State<ConnectionMap>) -> impl IntoResponse {
     let pool = connections.read().await;
     let resp = serde_json::to_string(pool.deref()).map_err(|e| {
@@ -220,17 +286,74 @@ async fn get_tunnels(State(connections): State<ConnectionMap>) -> impl IntoRespo
 #[derive(Deserialize)]
 struct CloseTunnelRequest {
     pub key: String,
+    /// Seconds running sessions may take to finish, 5 if not set.
+    #[serde(default)]
+    pub grace: Option<u64>,
 }
 
+/// Stops a tunnel, waiting for its sessions to finish.
+///
+/// Responds with how many sessions were drained and how many were cut.
 async fn close_tunnel(
     State(connections): State<ConnectionMap>, axum::Json(req): axum::Json<CloseTunnelRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
-    if connections.write().await.remove(&req.key).is_some() {
-        Ok(StatusCode::OK)
-    } else {
+    // Don't keep the map locked while the tunnel drains.
+    let Some(tunnel) = connections.write().await.remove(&req.key) else {
+        error!("Tunnel does not exist: {}", req.key);
+        return Err((StatusCode::NOT_FOUND, "not found"));
+    };
+    let grace = req
+        .grace
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE);
+    Ok(axum::Json(tunnel.shutdown(grace).await))
+}
+
+#[derive(Deserialize)]
+struct CaptureRequest {
+    pub key: String,
+    pub capture: bool,
+}
+
+/// Starts or stops capturing the traffic of a tunnel.
+///
+/// Captures are written to the capture directory of the daemon, under a name
+/// it picks, so API clients can't write to arbitrary files.
+async fn toggle_capture(
+    State(connections): State<ConnectionMap>, State(capture_dir): State<Arc<PathBuf>>,
+    axum::Json(req): axum::Json<CaptureRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let pool = connections.read().await;
+    let Some(tunnel) = pool.get(&req.key) else {
         error!("Tunnel does not exist: {}", req.key);
-        Err((StatusCode::NOT_FOUND, "not found"))
+        return Err((StatusCode::NOT_FOUND, String::from("not found")));
+    };
+    if !req.capture {
+        tunnel.stop_capture();
+    } else if tunnel.capture().is_none() {
+        let path = capture_dir.join(Capture::file_name(&tunnel.local));
+        let capture = Capture::create(&path).map_err(|e| {
+            error!("Failed to create capture file {}: {e}", path.display());
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to create capture file: {e}"),
+            )
+        })?;
+        info!(
+            "Capturing traffic of {} to {}",
+            tunnel.local,
+            path.display()
+        );
+        tunnel.start_capture(capture);
     }
+
+    serde_json::to_string(tunnel).map_err(|e| {
+        error!("Failed to serialize tunnel: {e:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to serialize tunnel: {e:?}"),
+        )
+    })
 }
 
 #[derive(Serialize)]
@@ -20,3 +20,31 @@ pub fn init_logger(json: bool) {
             .init();
     }
 }
+
+/// Initialize the logger for stdio mode, where stdout carries the data.
+///
+/// Logs go to stderr, and only warnings are shown unless `RUST_LOG` is set,
+/// so they don't clutter an interactive session or an ssh `ProxyCommand`.
+pub fn init_stderr_logger(json: bool) {
+    if json {
+        tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "wsrx=warn".into()),
+            )
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(std::io::stderr),
+            )
+            .init();
+    } else {
+        tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "wsrx=warn".into()),
+            )
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .init();
+    }
+}
@@ -1,4 +1,121 @@
+use std::time::Duration;
+
 pub mod connect;
 pub mod daemon;
 pub mod logger;
 pub mod serve;
+pub mod tls;
+
+/// Builds the frame limits from the command line, unset limits keep their
+/// defaults.
+pub fn frame_limits(
+    max_frame_size: Option<usize>, max_message_size: Option<usize>,
+    write_buffer_size: Option<usize>,
+) -> wsrx::FrameLimits {
+    let defaults = wsrx::FrameLimits::default();
+    wsrx::FrameLimits {
+        max_frame_size: max_frame_size.unwrap_or(defaults.max_frame_size),
+        max_message_size: max_message_size.unwrap_or(defaults.max_message_size),
+        write_buffer_size: write_buffer_size.unwrap_or(defaults.write_buffer_size),
+    }
+}
+
+/// Builds the keepalive pings from the command line, an interval of `0`
+/// disables them.
+pub fn keepalive(ping_interval: Duration, max_missed_pongs: u32) -> Option<wsrx::Keepalive> {
+    (!ping_interval.is_zero())
+        .then(|| wsrx::Keepalive::new(ping_interval).with_max_missed(max_missed_pongs))
+}
+
+/// Parse a number of seconds, which may have a fraction, e.g. `0.5`.
+pub fn parse_secs(secs: &str) -> Result<Duration, String> {
+    secs.trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid number of seconds `{secs}`"))
+}
+
+/// Parse a number of seconds like [`parse_secs`], which must not be zero.
+pub fn parse_positive_secs(secs: &str) -> Result<Duration, String> {
+    match parse_secs(secs)? {
+        duration if duration.is_zero() => Err(format!("`{secs}` must be more than zero seconds")),
+        duration => Ok(duration),
+    }
+}
+
+/// Builds the compression thresholds from the command line, unset thresholds
+/// keep their defaults.
+pub fn compression(
+    deflate: bool, min_size: Option<usize>, min_savings: Option<u8>,
+) -> Option<wsrx::deflate::Compression> {
+    let defaults = wsrx::deflate::Compression::default();
+    deflate.then(|| wsrx::deflate::Compression {
+        min_size: min_size.unwrap_or(defaults.min_size),
+        min_savings: min_savings.unwrap_or(defaults.min_savings),
+    })
+}
+
+/// Builds the end-to-end encryption key from the command line, either given
+/// or derived from a secret, and exits if the encoding can't carry it.
+pub fn encryption(
+    key: Option<wsrx::encrypt::EncryptionKey>, secret: Option<&str>, encoding: wsrx::FrameEncoding,
+) -> Option<wsrx::encrypt::EncryptionKey> {
+    use clap::CommandFactory;
+
+    let key = key.or_else(|| secret.map(wsrx::encrypt::EncryptionKey::from_secret))?;
+    if encoding == wsrx::FrameEncoding::Text {
+        crate::WsrxCli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "encryption can't be used with the `text` encoding",
+            )
+            .exit();
+    }
+    Some(key)
+}
+
+/// Builds the observer that writes a hexdump of every message to stderr, if
+/// asked for.
+pub fn hexdump(hexdump: bool) -> Option<std::sync::Arc<dyn wsrx::observe::Observer>> {
+    hexdump.then(|| std::sync::Arc::new(wsrx::observe::HexDump::stderr()) as _)
+}
+
+/// Parses a `Name: value` request header.
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header `{header}`, expected `Name: value`"))?;
+    let (name, value) = (name.trim(), value.trim());
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name `{name}`"))?;
+    HeaderValue::from_str(value).map_err(|_| format!("invalid value of header `{name}`"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parses a pinned public key, see [`wsrx::tls::parse_pin`].
+pub fn parse_pin(pin: &str) -> Result<String, String> {
+    wsrx::tls::parse_pin(pin).map(|_| pin.trim().to_string())
+}
+
+/// Parses a `name=value` cookie.
+pub fn parse_cookie(cookie: &str) -> Result<String, String> {
+    match cookie.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(cookie.trim().to_string()),
+        _ => Err(format!("invalid cookie `{cookie}`, expected `name=value`")),
+    }
+}
+
+/// Builds the extra handshake data from the command line.
+pub fn handshake(
+    headers: Vec<(String, String)>, cookies: Vec<String>, subprotocols: Vec<String>,
+    bearer_token: Option<String>,
+) -> wsrx::handshake::Handshake {
+    wsrx::handshake::Handshake {
+        headers: headers.into_iter().collect(),
+        cookies,
+        subprotocols,
+        bearer_token,
+    }
+}
@@ -3,11 +3,12 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 use axum::{
     body::Body,
     extract::{FromRef, Path, Request as ExtractRequest, State, WebSocketUpgrade},
-    http::{Request, StatusCode, header::CONTENT_TYPE},
+    http::{HeaderMap, HeaderValue, Request, StatusCode, header::CONTENT_TYPE},
     middleware::Next,
     response::{IntoResponse, Response},
     routing::get,
 };
+use futures_util::{Sink, Stream};
 use serde::Deserialize;
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -15,22 +16,77 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tower_http::trace::TraceLayer;
-use tracing::{Span, error, info};
-use wsrx::proxy;
+use tracing::{Span, debug, error, info};
+use wsrx::{
+    FrameEncoding, FrameLimits, Keepalive, WrappedWsStream,
+    datagram::{DatagramStream, UDP_PREFIX, connect_udp},
+    deflate::{Compression, DEFLATE_HEADER, DEFLATE_VERSION},
+    encrypt::{ENCRYPT_HEADER, EncryptionKey},
+    limit::{RateLimited, RateLimits},
+    mux::{MUX_PROTOCOL, MuxSession},
+    observe::Observer,
+    pool::{self, POOL_HEADER, POOL_VERSION},
+    proxy::{Error, Message, proxy_stream_observed},
+    resume::{RESUME_PROTOCOL, ResumeRegistry},
+    stats::{TrafficCounter, TrafficSnapshot, TrafficStats},
+};
 
-use crate::cli::logger::init_logger;
+use crate::cli::{
+    logger::init_logger,
+    tls::{TlsFiles, serve},
+};
 
-/// Launch the server with the given host, port, and secret.
-pub async fn launch(
-    host: Option<String>, port: Option<u16>, secret: Option<String>, log_json: Option<bool>,
-) {
-    let log_json = log_json.unwrap_or(false);
-    init_logger(log_json);
-    let router = build_router(secret);
+/// What `wsrx serve` listens on, and how it treats its clients.
+pub struct ServeOptions {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// The secret of the manage api.
+    pub secret: Option<String>,
+    /// The bandwidth of all connections together.
+    pub global: BandwidthLimit,
+    /// The default bandwidth of every single connection.
+    pub connection: BandwidthLimit,
+    /// The default frame encoding of the tunnels.
+    pub encoding: FrameEncoding,
+    /// Compresses the data of clients that ask for it.
+    pub compression: Option<Compression>,
+    /// Requires every client to encrypt its data with the key.
+    pub encryption: Option<EncryptionKey>,
+    /// Caps the size of messages and buffers of every connection.
+    pub frame_limits: FrameLimits,
+    /// Pings every WebSocket connection.
+    pub keepalive: Option<Keepalive>,
+    /// Keeps the sessions of reconnecting clients.
+    pub resume: Option<ResumeRegistry>,
+    /// Serves `https` and `wss` with the certificate files.
+    pub tls: Option<TlsFiles>,
+    /// Sees the traffic of every session.
+    pub observer: Option<Arc<dyn Observer>>,
+    pub log_json: Option<bool>,
+}
+
+/// Launch the server with the given options.
+pub async fn launch(options: ServeOptions) {
+    init_logger(options.log_json.unwrap_or(false));
+    let router = build_router(GlobalState {
+        secret: options.secret,
+        connections: Default::default(),
+        limits: ServerLimits {
+            global: options.global.shared(),
+            connection: options.connection,
+        },
+        encoding: options.encoding,
+        compression: options.compression,
+        encryption: options.encryption,
+        frame_limits: options.frame_limits,
+        keepalive: options.keepalive,
+        resume: options.resume,
+        observer: options.observer,
+    });
     let listener = TcpListener::bind(&format!(
         "{}:{}",
-        host.unwrap_or(String::from("127.0.0.1")),
-        port.unwrap_or(0)
+        options.host.unwrap_or(String::from("127.0.0.1")),
+        options.port.unwrap_or(0)
     ))
     .await
     .expect("failed to bind port");
@@ -39,34 +95,123 @@ pub async fn launch(
         listener.local_addr().expect("failed to bind port")
     );
     info!(
-        "you can access manage api at http://{}/pool",
+        "you can access manage api at {}://{}/pool",
+        if options.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        },
         listener.local_addr().expect("failed to bind port")
     );
-    axum::serve(listener, router)
-        .await
-        .expect("failed to launch server");
+    serve(listener, router, options.tls).await;
 }
 
-type ConnectionMap = Arc<RwLock<HashMap<String, String>>>;
+/// Builds the registry of resumable sessions, a grace period of `0` seconds
+/// disables them.
+pub fn resume(grace: u64) -> Option<ResumeRegistry> {
+    (grace > 0).then(|| ResumeRegistry::new(Duration::from_secs(grace)))
+}
+
+/// Upload and download rates in bytes per second.
+///
+/// Uploads are the traffic from WebSocket clients to the targets.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct BandwidthLimit {
+    #[serde(default)]
+    pub upload: Option<u64>,
+    #[serde(default)]
+    pub download: Option<u64>,
+}
+
+impl BandwidthLimit {
+    /// Creates a new set of buckets with these rates.
+    fn shared(&self) -> RateLimits {
+        RateLimits::new()
+            .inbound_rate(self.upload)
+            .outbound_rate(self.download)
+    }
+}
+
+/// Parse a rate in bytes per second, with an optional `K`, `M` or `G` suffix.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    parse_bytes(rate, "rate")
+}
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix.
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    parse_bytes(size, "size")?
+        .try_into()
+        .map_err(|_| format!("size `{size}` is too large"))
+}
+
+fn parse_bytes(value: &str, what: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let unit = match unit.trim().to_ascii_uppercase().trim_end_matches("B") {
+        "" => 1,
+        "K" | "KI" => 1 << 10,
+        "M" | "MI" => 1 << 20,
+        "G" | "GI" => 1 << 30,
+        _ => return Err(format!("unknown {what} unit `{unit}`")),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(unit))
+        .filter(|value| *value > 0)
+        .ok_or_else(|| format!("invalid {what} `{value}`"))
+}
+
+/// The bandwidth limits applied on top of the limits of each tunnel.
+#[derive(Clone)]
+pub struct ServerLimits {
+    /// Buckets shared by every connection of the server.
+    pub global: RateLimits,
+    /// The default limit of a single connection.
+    pub connection: BandwidthLimit,
+}
+
+/// A tunnel of the pool.
+pub struct Target {
+    pub to: String,
+    /// Buckets shared by every connection of this key.
+    pub limits: RateLimits,
+    /// The limit of a single connection of this key.
+    pub connection: Option<BandwidthLimit>,
+    /// The frame encoding of this key, if it differs from the default.
+    pub encoding: Option<FrameEncoding>,
+    /// Counts the traffic of every session of this key.
+    pub counter: TrafficCounter,
+}
+
+type ConnectionMap = Arc<RwLock<HashMap<String, Target>>>;
 
 /// The global state of the server.
 #[derive(Clone, FromRef)]
 pub struct GlobalState {
     pub secret: Option<String>,
     pub connections: ConnectionMap,
+    pub limits: ServerLimits,
+    pub encoding: FrameEncoding,
+    pub compression: Option<Compression>,
+    pub encryption: Option<EncryptionKey>,
+    pub frame_limits: FrameLimits,
+    pub keepalive: Option<Keepalive>,
+    pub resume: Option<ResumeRegistry>,
+    pub observer: Option<Arc<dyn Observer>>,
 }
 
-/// Build the router with the given secret.
-fn build_router(secret: Option<String>) -> axum::Router {
-    let state = GlobalState {
-        secret,
-        connections: Default::default(),
-    };
+/// Build the router serving the state.
+fn build_router(state: GlobalState) -> axum::Router {
     axum::Router::new()
         .route(
             "/pool",
             get(get_tunnels).post(launch_tunnel).delete(close_tunnel),
         )
+        .route("/pool/traffic", get(get_traffic))
         .layer(axum::middleware::from_fn_with_state(
             state.clone(),
             |State(secret): State<Option<String>>, req: ExtractRequest, next: Next| async move {
@@ -100,25 +245,54 @@ fn build_router(secret: Option<String>) -> axum::Router {
 }
 
 /// The request body for launching a tunnel.
+///
+/// Targets prefixed with `udp:` are reached over UDP, others over TCP.
+///
+/// `limit` is shared by all connections of the tunnel, `connection_limit`
+/// overrides the default limit of each connection, and `encoding` overrides
+/// the default frame encoding, it can't be `text` if the server encrypts.
 #[derive(Deserialize)]
 struct TunnelRequest {
     pub from: String,
     pub to: String,
+    #[serde(default)]
+    pub limit: BandwidthLimit,
+    #[serde(default)]
+    pub connection_limit: Option<BandwidthLimit>,
+    #[serde(default)]
+    pub encoding: Option<FrameEncoding>,
 }
 
 /// Launch a tunnel from the given address to the given address.
 async fn launch_tunnel(
-    State(connections): State<ConnectionMap>, axum::Json(req): axum::Json<TunnelRequest>,
+    State(connections): State<ConnectionMap>, State(encryption): State<Option<EncryptionKey>>,
+    axum::Json(req): axum::Json<TunnelRequest>,
 ) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    if encryption.is_some() && req.encoding == Some(FrameEncoding::Text) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "encryption can't be used with the text encoding",
+        ));
+    }
     let mut pool = connections.write().await;
-    pool.insert(req.from, req.to);
+    pool.insert(
+        req.from,
+        Target {
+            to: req.to,
+            limits: req.limit.shared(),
+            connection: req.connection_limit,
+            encoding: req.encoding,
+            counter: TrafficCounter::new(),
+        },
+    );
     Ok(StatusCode::CREATED)
 }
 
 /// Get the list of tunnels.
 async fn get_tunnels(State(connections): State<ConnectionMap>) -> impl IntoResponse {
     let pool = connections.read().await;
-    let resp = serde_json::to_string::<HashMap<String, String>>(&pool).map_err(|e| {
+    let pool: HashMap<&String, &String> = pool.iter().map(|(k, v)| (k, &v.to)).collect();
+    let resp = serde_json::to_string(&pool).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("failed to serialize pool: {e}"),
@@ -131,6 +305,16 @@ async fn get_tunnels(State(connections): State<ConnectionMap>) -> impl IntoRespo
         .unwrap()
 }
 
+/// Get the traffic of every tunnel, since it was launched.
+async fn get_traffic(State(connections): State<ConnectionMap>) -> impl IntoResponse {
+    let pool = connections.read().await;
+    let traffic: HashMap<String, TrafficSnapshot> = pool
+        .iter()
+        .map(|(k, v)| (k.clone(), v.counter.snapshot()))
+        .collect();
+    axum::Json(traffic)
+}
+
 /// The request body for closing a tunnel.
 #[derive(Deserialize)]
 struct CloseTunnelRequest {
@@ -150,28 +334,240 @@ async fn close_tunnel(
 }
 
 /// Process the traffic between the WebSocket and TCP connection.
+///
+/// TCP targets also accept the mux subprotocol, which carries many TCP
+/// connections over one WebSocket, and the resume subprotocol if enabled,
+/// which keeps the TCP connection while the client reconnects.
+///
+/// Clients that send the deflate header get their data compressed if the
+/// server enables compression and the encoding allows it. If the server
+/// encrypts, clients that don't send the encrypt header are rejected.
+/// Clients that send the pool header open pooled connections, whose target
+/// is connected once the client attaches them.
+#[allow(clippy::too_many_arguments)]
 async fn process_traffic(
-    State(connections): State<ConnectionMap>, Path(key): Path<String>, ws: WebSocketUpgrade,
-) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    State(connections): State<ConnectionMap>, State(limits): State<ServerLimits>,
+    State(encoding): State<FrameEncoding>, State(compression): State<Option<Compression>>,
+    State(encryption): State<Option<EncryptionKey>>, State(frame_limits): State<FrameLimits>,
+    State(keepalive): State<Option<Keepalive>>, State(resume): State<Option<ResumeRegistry>>,
+    State(observer): State<Option<Arc<dyn Observer>>>, Path(key): Path<String>, headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let ws = frame_limits.upgrade(ws);
     let pool = connections.read().await;
     if let Some(conn) = pool.get(&key) {
-        let tcp_addr = conn.to_owned();
-        Ok(ws.on_upgrade(move |socket| async move {
-            let tcp = TcpStream::connect(&tcp_addr).await;
-            if let Err(e) = tcp {
-                error!("failed to connect to tcp server: {e:?}");
-                return;
+        let target = conn.to.to_owned();
+        let encoding = conn.encoding.unwrap_or(encoding);
+        let compression = compression.filter(|_| {
+            encoding != FrameEncoding::Text
+                && headers
+                    .get(DEFLATE_HEADER)
+                    .is_some_and(|version| version == DEFLATE_VERSION)
+        });
+        let deflate = move || compression.map(|c| c.deflate(&frame_limits));
+        let pooled = headers
+            .get(POOL_HEADER)
+            .is_some_and(|version| version == POOL_VERSION);
+        let (cipher, answer) = match &encryption {
+            Some(key) => {
+                let offer = headers
+                    .get(ENCRYPT_HEADER)
+                    .and_then(|offer| offer.to_str().ok())
+                    .ok_or((StatusCode::FORBIDDEN, "encryption required"))?;
+                let (cipher, answer) = key
+                    .accept(offer)
+                    .map_err(|_| (StatusCode::BAD_REQUEST, "invalid encryption handshake"))?;
+                (Some(cipher), Some(answer))
             }
-            let tcp = tcp.unwrap();
-            proxy(socket.into(), tcp, CancellationToken::new())
-                .await
-                .ok();
-        }))
+            None => (None, None),
+        };
+        let limiter = SessionLimiter {
+            connection: conn.connection.unwrap_or(limits.connection),
+            shared: conn.limits.clone().merge(&limits.global),
+        };
+        let observer = SessionObserver {
+            observer,
+            counter: conn.counter.clone(),
+            key: key.clone(),
+        };
+        if let Some(udp_addr) = target.strip_prefix(UDP_PREFIX) {
+            let udp_addr = udp_addr.to_owned();
+            let response = ws.on_upgrade(move |mut socket| async move {
+                if pooled && !pool::attached(&mut socket).await {
+                    return;
+                }
+                let ws = WrappedWsStream::from(socket)
+                    .with_encoding(encoding)
+                    .with_deflate(deflate())
+                    .with_cipher(cipher)
+                    .with_keepalive(keepalive);
+                proxy_udp_backend(ws, &udp_addr, limiter, observer).await
+            });
+            return Ok(accept(response, compression.is_some(), answer, pooled));
+        }
+        let protocols = match resume {
+            Some(_) => vec![MUX_PROTOCOL, RESUME_PROTOCOL],
+            None => vec![MUX_PROTOCOL],
+        };
+        let response = ws
+            .protocols(protocols)
+            .on_upgrade(move |mut socket| async move {
+                let protocol = socket.protocol().cloned();
+                if pooled && protocol.is_none() && !pool::attached(&mut socket).await {
+                    return;
+                }
+                let ws = WrappedWsStream::from(socket)
+                    .with_encoding(encoding)
+                    .with_deflate(deflate())
+                    .with_cipher(cipher)
+                    .with_keepalive(keepalive);
+                match (protocol, resume) {
+                    (Some(p), _) if p == MUX_PROTOCOL => {
+                        proxy_mux_backend(ws, &target, limiter, observer, frame_limits).await
+                    }
+                    (Some(p), Some(resume)) if p == RESUME_PROTOCOL => {
+                        match resume.accept(&key, ws).await {
+                            Ok(Some(session)) => {
+                                proxy_tcp_backend(session, &target, limiter, observer, frame_limits)
+                                    .await
+                            }
+                            Ok(None) => debug!("reattached a resumable session"),
+                            Err(e) => debug!("failed to accept a resumable session: {e}"),
+                        }
+                    }
+                    _ => proxy_tcp_backend(ws, &target, limiter, observer, frame_limits).await,
+                }
+            });
+        Ok(accept(response, compression.is_some(), answer, pooled))
     } else {
         Err((StatusCode::NOT_FOUND, "not found"))
     }
 }
 
+/// Tells the client its data is compressed and its connection is pooled,
+/// and answers its encryption handshake.
+fn accept(
+    mut response: Response, deflate: bool, encryption: Option<String>, pooled: bool,
+) -> Response {
+    if deflate {
+        response
+            .headers_mut()
+            .insert(DEFLATE_HEADER, HeaderValue::from_static(DEFLATE_VERSION));
+    }
+    if pooled {
+        response
+            .headers_mut()
+            .insert(POOL_HEADER, HeaderValue::from_static(POOL_VERSION));
+    }
+    if let Some(answer) = encryption.and_then(|answer| HeaderValue::from_str(&answer).ok()) {
+        response.headers_mut().insert(ENCRYPT_HEADER, answer);
+    }
+    response
+}
+
+/// Builds the rate limits of each connection to a tunnel.
+#[derive(Clone)]
+struct SessionLimiter {
+    connection: BandwidthLimit,
+    shared: RateLimits,
+}
+
+impl SessionLimiter {
+    /// Wraps the backend of a new connection with its own buckets and the
+    /// buckets shared by its tunnel and the server.
+    fn limit<S>(&self, backend: S) -> RateLimited<S> {
+        RateLimited::new(backend, self.connection.shared().merge(&self.shared))
+    }
+}
+
+/// The observer of the sessions of a tunnel key, if any, and the counter
+/// of their traffic.
+#[derive(Clone)]
+struct SessionObserver {
+    observer: Option<Arc<dyn Observer>>,
+    counter: TrafficCounter,
+    key: String,
+}
+
+impl SessionObserver {
+    /// Proxies a session, telling the observer about it.
+    async fn proxy<S, T>(&self, s1: S, s2: T) -> Result<TrafficStats, Error>
+    where
+        S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+        T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        let observer = self.observer.as_deref();
+        proxy_stream_observed(
+            s1,
+            s2,
+            CancellationToken::new(),
+            &self.counter,
+            observer,
+            &self.key,
+        )
+        .await
+    }
+}
+
+/// Proxy the WebSocket, or a session carried by it, with a TCP backend.
+async fn proxy_tcp_backend<S>(
+    ws: S, tcp_addr: &str, limiter: SessionLimiter, observer: SessionObserver,
+    frame_limits: FrameLimits,
+) where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    let tcp = TcpStream::connect(tcp_addr).await;
+    if let Err(e) = tcp {
+        error!("failed to connect to tcp server: {e:?}");
+        return;
+    }
+    let tcp = limiter.limit(frame_limits.framed(tcp.unwrap()));
+    if let Err(e) = observer.proxy(ws, tcp).await {
+        error!("failed to proxy tcp session: {e}");
+    }
+}
+
+/// Proxy every stream of a mux session with its own TCP backend connection.
+async fn proxy_mux_backend(
+    ws: WrappedWsStream, tcp_addr: &str, limiter: SessionLimiter, observer: SessionObserver,
+    frame_limits: FrameLimits,
+) {
+    let session = MuxSession::server(ws);
+    while let Some(stream) = session.accept().await {
+        let tcp_addr = tcp_addr.to_owned();
+        let limiter = limiter.clone();
+        let observer = observer.clone();
+        tokio::spawn(async move {
+            let tcp = match TcpStream::connect(&tcp_addr).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    error!("failed to connect to tcp server: {e:?}");
+                    return;
+                }
+            };
+            let tcp = limiter.limit(frame_limits.framed(tcp));
+            if let Err(e) = observer.proxy(stream, tcp).await {
+                error!("failed to proxy mux stream: {e}");
+            }
+        });
+    }
+}
+
+/// Proxy the WebSocket with a UDP backend, one datagram per message.
+async fn proxy_udp_backend(
+    ws: WrappedWsStream, udp_addr: &str, limiter: SessionLimiter, observer: SessionObserver,
+) {
+    let udp = connect_udp(udp_addr).await;
+    if let Err(e) = udp {
+        error!("failed to connect to udp server: {e:?}");
+        return;
+    }
+    let udp = limiter.limit(DatagramStream::connected(udp.unwrap()));
+    if let Err(e) = observer.proxy(ws, udp).await {
+        error!("failed to proxy udp session: {e}");
+    }
+}
+
 /// Ping the server to check if the connection is alive.
 async fn ping(
     State(connections): State<ConnectionMap>, Path(key): Path<String>,
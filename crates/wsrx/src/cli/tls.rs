@@ -0,0 +1,307 @@
+//! TLS termination of the serve and daemon HTTP servers.
+
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use axum::{Router, serve::Listener};
+use rustls::{
+    ServerConfig,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tracing::{debug, error, info, warn};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a client may take to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many finished handshakes can wait for the HTTP server.
+const ACCEPT_QUEUE: usize = 128;
+
+/// The PEM files of the certificate chain and private key of the server.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Returns the files if both are set.
+    pub fn new(cert: Option<PathBuf>, key: Option<PathBuf>) -> Option<Self> {
+        Some(Self {
+            cert: cert?,
+            key: key?,
+        })
+    }
+
+    fn load(&self, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| io::Error::other(format!("{}: {e}", self.cert.display())))?;
+        if certs.is_empty() {
+            return Err(io::Error::other(format!(
+                "{}: no certificates found",
+                self.cert.display()
+            )));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| io::Error::other(format!("{}: {e}", self.key.display())))?;
+        CertifiedKey::from_der(certs, key, provider).map_err(io::Error::other)
+    }
+
+    /// Returns the last modification times of the files.
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+/// Serves the router over plain HTTP, or over HTTPS if TLS files are given.
+///
+/// Exits the process if the certificate can't be loaded.
+pub async fn serve(listener: TcpListener, router: Router, tls: Option<TlsFiles>) {
+    let Some(files) = tls else {
+        return axum::serve(listener, router)
+            .await
+            .expect("failed to launch server");
+    };
+    let listener = match TlsListener::new(listener, files, RELOAD_INTERVAL) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to load the TLS certificate: {e}");
+            std::process::exit(1);
+        }
+    };
+    axum::serve(listener, router)
+        .await
+        .expect("failed to launch server");
+}
+
+/// Resolves the current certificate, which is replaced when its files
+/// change on disk.
+#[derive(Debug)]
+struct ReloadingResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Polls the certificate files every `interval`, a broken update keeps the
+/// old certificate.
+async fn watch_files(
+    files: TlsFiles, resolver: Arc<ReloadingResolver>, provider: Arc<CryptoProvider>,
+    interval: Duration,
+) {
+    let mut modified = files.modified();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = files.modified();
+        if current.is_none() || current == modified {
+            continue;
+        }
+        match files.load(&provider) {
+            Ok(key) => {
+                *resolver.current.write().unwrap() = Arc::new(key);
+                modified = current;
+                info!("Reloaded the TLS certificate {}", files.cert.display());
+            }
+            // The files may be half written, try again on the next tick.
+            Err(e) => warn!("Failed to reload the TLS certificate: {e}"),
+        }
+    }
+}
+
+/// A listener that accepts TCP connections and finishes their TLS handshakes
+/// in the background, so slow clients don't hold up others.
+struct TlsListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    /// Creates a listener that serves the certificate of the files, which
+    /// are checked for changes every `reload_interval`.
+    fn new(
+        mut listener: TcpListener, files: TlsFiles, reload_interval: Duration,
+    ) -> io::Result<Self> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+        let resolver = Arc::new(ReloadingResolver {
+            current: RwLock::new(Arc::new(files.load(&provider)?)),
+        });
+        let mut config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        // WebSocket upgrades need HTTP/1.1.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        tokio::spawn(watch_files(files, resolver, provider, reload_interval));
+
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = Listener::accept(&mut listener).await;
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            tx.send((stream, addr)).await.ok();
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(conn) => conn,
+            // The accept task never stops while the listener is alive.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    const CA: &str = include_str!("testdata/ca.pem");
+    const FIRST: (&str, &str) = (
+        include_str!("testdata/first.pem"),
+        include_str!("testdata/first.key"),
+    );
+    const SECOND: (&str, &str) = (
+        include_str!("testdata/second.pem"),
+        include_str!("testdata/second.key"),
+    );
+
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    fn install(files: &TlsFiles, (cert, key): (&str, &str)) {
+        fs::write(&files.cert, cert).unwrap();
+        fs::write(&files.key, key).unwrap();
+    }
+
+    /// Accepts connections on the listener, so their handshakes finish.
+    fn accept(mut listener: TlsListener) {
+        tokio::spawn(async move {
+            loop {
+                let conn = Listener::accept(&mut listener).await;
+                tokio::spawn(async move {
+                    let _conn = conn;
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+    }
+
+    /// Returns the certificate the server at `addr` presents.
+    async fn served(addr: SocketAddr) -> CertificateDer<'static> {
+        let mut roots = RootCertStore::empty();
+        roots.add(der(CA)).unwrap();
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    fn der(cert: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()
+    }
+
+    /// Waits until the server presents the certificate.
+    async fn wait_for(addr: SocketAddr, cert: &str) {
+        for _ in 0..100 {
+            if served(addr).await == der(cert) {
+                return;
+            }
+            tokio::time::sleep(INTERVAL).await;
+        }
+        panic!("the certificate was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded_and_broken_files_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("wsrx-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        install(&files, FIRST);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, files.clone(), INTERVAL).unwrap();
+        let addr = listener.local_addr().unwrap();
+        accept(listener);
+        wait_for(addr, FIRST.0).await;
+
+        install(&files, SECOND);
+        wait_for(addr, SECOND.0).await;
+
+        // A broken certificate, or a key of another certificate, is not
+        // served and the last good one stays.
+        install(&files, ("not a certificate", SECOND.1));
+        tokio::time::sleep(INTERVAL * 4).await;
+        assert_eq!(served(addr).await, der(SECOND.0));
+        install(&files, (SECOND.0, FIRST.1));
+        tokio::time::sleep(INTERVAL * 4).await;
+        assert_eq!(served(addr).await, der(SECOND.0));
+
+        install(&files, FIRST);
+        wait_for(addr, FIRST.0).await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+//! Datagram support for WebSocket Reflector X.
+//!
+//! UDP packets are tunneled one by one, every datagram is carried as its own
+//! binary WebSocket message so that packet boundaries are preserved.
+//!
+//! ICMP errors, like a refused port of a backend that is restarting, are
+//! ignored on both sides of a tunnel. They are reported for some earlier
+//! datagram, and UDP applications expect lost datagrams anyway, so a session
+//! only ends when its WebSocket closes or it stays idle.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{sink::Sink, stream::Stream};
+use tokio::{
+    io::ReadBuf,
+    net::{UdpSocket, lookup_host},
+    sync::mpsc,
+    time::{Instant, Sleep},
+};
+use tokio_util::bytes::Bytes;
+#[cfg(feature = "log")]
+use tracing::debug;
+
+use crate::proxy::{Error, Message};
+
+/// The prefix of addresses that carry UDP datagrams, e.g. `udp:127.0.0.1:53`.
+pub const UDP_PREFIX: &str = "udp:";
+
+/// The largest payload a single UDP datagram can carry.
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// How long a datagram session may stay silent before it is closed.
+///
+/// UDP has no notion of connection close, so idle sessions are reaped after
+/// this timeout.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Creates a UDP socket connected to the specified remote address.
+///
+/// The socket is bound to an unspecified local address of the same family as
+/// the resolved remote address.
+pub async fn connect_udp(remote: &str) -> std::io::Result<UdpSocket> {
+    let remote = lookup_host(remote).await?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            "failed to resolve udp address",
+        )
+    })?;
+    let local = if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    Ok(socket)
+}
+
+/// Returns whether an error was caused by an ICMP message for an earlier
+/// datagram, rather than by the socket itself.
+fn is_icmp_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Where the datagrams of a `DatagramStream` come from.
+enum Source {
+    /// The socket is connected to a single peer, datagrams are received
+    /// from the socket directly.
+    Connected { buf: Box<[u8]> },
+    /// The socket is shared by many peers, datagrams for this peer are
+    /// dispatched through a channel by the owner of the socket.
+    Peer {
+        addr: SocketAddr,
+        rx: mpsc::Receiver<Bytes>,
+    },
+}
+
+/// A UDP socket that implements `Sink` and `Stream` of messages.
+///
+/// Each received datagram becomes exactly one `Message::Binary`, and each
+/// binary message sent into the sink becomes exactly one datagram.
+pub struct DatagramStream {
+    socket: Arc<UdpSocket>,
+    source: Source,
+    pending: Option<Bytes>,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+}
+
+impl DatagramStream {
+    /// Creates a new `DatagramStream` from a UDP socket that is already
+    /// connected to its peer.
+    pub fn connected(socket: UdpSocket) -> Self {
+        Self::new(
+            Arc::new(socket),
+            Source::Connected {
+                buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+            },
+        )
+    }
+
+    /// Creates a new `DatagramStream` for one peer of a shared UDP socket.
+    ///
+    /// * `socket` - The shared socket, used to reply to the peer.
+    /// * `addr` - The address of the peer.
+    /// * `rx` - The channel that receives the datagrams sent by the peer.
+    ///
+    /// Once the channel is closed and its datagrams are read, the stream
+    /// yields a close instead of ending, since no more datagrams can come
+    /// and there is nothing left to wait for.
+    pub fn peer(socket: Arc<UdpSocket>, addr: SocketAddr, rx: mpsc::Receiver<Bytes>) -> Self {
+        Self::new(socket, Source::Peer { addr, rx })
+    }
+
+    fn new(socket: Arc<UdpSocket>, source: Source) -> Self {
+        Self {
+            socket,
+            source,
+            pending: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            idle: Box::pin(tokio::time::sleep(DEFAULT_IDLE_TIMEOUT)),
+        }
+    }
+
+    /// Sets the idle timeout of the stream.
+    ///
+    /// The stream ends when no datagram is sent or received within the
+    /// timeout.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self.idle.as_mut().reset(Instant::now() + timeout);
+        self
+    }
+
+    fn touch(&mut self) {
+        let deadline = Instant::now() + self.idle_timeout;
+        self.idle.as_mut().reset(deadline);
+    }
+}
+
+/// A wrapper around UDP socket that implements `Stream` trait.
+impl Stream for DatagramStream {
+    type Item = Result<Message, Error>;
+
+    /// Polls the next datagram from the UDP socket.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let polled = match &mut this.source {
+            Source::Connected { buf } => loop {
+                let mut buf = ReadBuf::new(&mut buf[..]);
+                match this.socket.poll_recv(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => {
+                        break Poll::Ready(Some(Ok(Bytes::copy_from_slice(buf.filled()))));
+                    }
+                    Poll::Ready(Err(e)) if is_icmp_error(&e) => {
+                        #[cfg(feature = "log")]
+                        debug!("Ignoring icmp error of udp socket: {e}");
+                    }
+                    Poll::Ready(Err(e)) => break Poll::Ready(Some(Err(e))),
+                    Poll::Pending => break Poll::Pending,
+                }
+            },
+            Source::Peer { rx, .. } => match rx.poll_recv(cx) {
+                Poll::Ready(None) => return Poll::Ready(Some(Ok(Message::Close(None)))),
+                polled => polled.map(|data| data.map(Ok)),
+            },
+        };
+        match polled {
+            Poll::Ready(Some(Ok(data))) => {
+                this.touch();
+                Poll::Ready(Some(Ok(Message::Binary(data))))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => match this.idle.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+/// A wrapper around UDP socket that implements `Sink` trait.
+impl Sink<Message> for DatagramStream {
+    type Error = Error;
+
+    /// Polls the UDP socket if it is ready to send a datagram.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    /// Queues a message to be sent as a single datagram.
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let Message::Binary(data) = item {
+            self.pending = Some(data);
+        }
+        Ok(())
+    }
+
+    /// Polls the UDP socket to send the queued datagram.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = &mut *self;
+        let Some(data) = &this.pending else {
+            return Poll::Ready(Ok(()));
+        };
+        let sent = match &this.source {
+            Source::Connected { .. } => this.socket.poll_send(cx, data),
+            Source::Peer { addr, .. } => this.socket.poll_send_to(cx, data, *addr),
+        };
+        match futures_util::ready!(sent) {
+            Ok(_) => {
+                this.pending = None;
+                this.touch();
+                Poll::Ready(Ok(()))
+            }
+            Err(e) if is_icmp_error(&e) => {
+                // The datagram is lost like any other, the error belongs to an
+                // earlier one.
+                #[cfg(feature = "log")]
+                debug!("Ignoring icmp error of udp socket: {e}");
+                this.pending = None;
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => Poll::Ready(Err(e.into())),
+        }
+    }
+
+    /// Polls the UDP socket to send the queued datagram before closing.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+
+    fn binary(msg: Option<Result<Message, Error>>) -> Bytes {
+        match msg {
+            Some(Ok(Message::Binary(data))) => data,
+            _ => panic!("expected a binary message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connected_datagrams_round_trip() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = backend.local_addr().unwrap().to_string();
+        let mut stream = DatagramStream::connected(connect_udp(&addr).await.unwrap());
+
+        let mut buf = [0; 16];
+        for data in [&b"first"[..], b"", b"second"] {
+            stream
+                .send(Message::Binary(Bytes::copy_from_slice(data)))
+                .await
+                .unwrap();
+            let (len, peer) = backend.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], data);
+            backend.send_to(&buf[..len], peer).await.unwrap();
+            assert_eq!(binary(stream.next().await), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_datagrams_round_trip() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        let mut stream = DatagramStream::peer(socket.clone(), peer.local_addr().unwrap(), rx);
+
+        tx.send(Bytes::from_static(b"request")).await.unwrap();
+        assert_eq!(binary(stream.next().await), Bytes::from_static(b"request"));
+        stream
+            .send(Message::Binary(Bytes::from_static(b"reply")))
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            (&buf[..len], from),
+            (&b"reply"[..], socket.local_addr().unwrap())
+        );
+
+        drop(tx);
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(Message::Close(None)))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_streams_end() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (tx, rx) = mpsc::channel(4);
+        let mut stream = DatagramStream::peer(socket.clone(), socket.local_addr().unwrap(), rx)
+            .with_idle_timeout(Duration::from_secs(10));
+
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        tx.send(Bytes::from_static(b"data")).await.unwrap();
+        stream.next().await.unwrap().unwrap();
+
+        let started = Instant::now();
+        assert!(stream.next().await.is_none());
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn refused_datagrams_do_not_end_the_stream() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = backend.local_addr().unwrap();
+        drop(backend);
+        let mut stream = DatagramStream::connected(connect_udp(&addr.to_string()).await.unwrap());
+
+        // The first datagram is refused, the error shows up on the next
+        // send or receive.
+        for _ in 0..2 {
+            stream
+                .send(Message::Binary(Bytes::from_static(b"lost")))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let received = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(received.is_err());
+
+        let backend = UdpSocket::bind(addr).await.unwrap();
+        stream
+            .send(Message::Binary(Bytes::from_static(b"ping")))
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, peer) = backend.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        backend.send_to(b"pong", peer).await.unwrap();
+        assert_eq!(binary(stream.next().await), Bytes::from_static(b"pong"));
+    }
+}
@@ -0,0 +1,335 @@
+//! Per-message compression of WebSocket data.
+//!
+//! Neither tungstenite nor axum implement the `permessage-deflate` extension
+//! of RFC 7692, so compression is negotiated with the [`DEFLATE_HEADER`]
+//! handshake header instead: the client sends it, and the server echoes it
+//! back if it compresses too. Once negotiated, every data message starts with
+//! a flag byte telling whether the rest is raw or deflated, empty data
+//! included.
+//!
+//! Each side decides on its own what to compress. Messages smaller than
+//! [`Compression::min_size`] are sent raw, and so are messages that would not
+//! shrink by [`Compression::min_savings`] percent, so already compressed or
+//! encrypted data isn't inflated. After such a message the next few ones are
+//! sent raw without trying.
+
+use std::io;
+
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio_util::bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{
+    FrameLimits,
+    proxy::Error,
+    stats::{self, TrafficCounter},
+};
+
+/// The handshake header that negotiates compression.
+pub const DEFLATE_HEADER: &str = "wsrx-deflate";
+
+/// The value of [`DEFLATE_HEADER`], the version of the wire format.
+pub const DEFLATE_VERSION: &str = "1";
+
+/// The flag byte of a message sent as is.
+const RAW: u8 = 0;
+
+/// The flag byte of a deflated message.
+const DEFLATED: u8 = 1;
+
+/// How many messages are sent raw without trying after one did not compress
+/// well.
+const BACKOFF_MESSAGES: u32 = 16;
+
+/// When data is worth compressing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "binary", serde(default))]
+pub struct Compression {
+    /// Messages smaller than this many bytes are sent raw.
+    pub min_size: usize,
+    /// The percentage a message has to shrink by to be sent deflated.
+    pub min_savings: u8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: 256,
+            min_savings: 10,
+        }
+    }
+}
+
+impl Compression {
+    /// Creates the compressor and decompressor of a connection, deflated
+    /// messages that grow beyond `max_message_size` are rejected.
+    pub fn deflate(&self, frame_limits: &FrameLimits) -> Deflate {
+        Deflate {
+            compression: *self,
+            max_message_size: frame_limits.max_message_size,
+            compress: Compress::new(flate2::Compression::fast(), false),
+            decompress: Decompress::new(false),
+            backoff: 0,
+            counter: None,
+        }
+    }
+}
+
+/// The compression state of a WebSocket connection.
+///
+/// Every message is compressed on its own, the state is only kept to reuse
+/// its buffers.
+pub struct Deflate {
+    compression: Compression,
+    max_message_size: usize,
+    compress: Compress,
+    decompress: Decompress,
+    /// Messages left to send raw without trying.
+    backoff: u32,
+    counter: Option<TrafficCounter>,
+}
+
+impl Deflate {
+    /// Counts the data before and after compression in both directions.
+    pub fn with_counter(mut self, counter: Option<TrafficCounter>) -> Self {
+        self.counter = counter;
+        self
+    }
+
+    /// Compresses the data of a message if it is worth it, and prepends its
+    /// flag byte.
+    pub fn compress(&mut self, data: Bytes) -> Bytes {
+        let msg = self.try_compress(&data).unwrap_or_else(|| {
+            let mut msg = BytesMut::with_capacity(1 + data.len());
+            msg.put_u8(RAW);
+            msg.extend_from_slice(&data);
+            msg.freeze()
+        });
+        self.count(data.len(), msg.len());
+        msg
+    }
+
+    fn try_compress(&mut self, data: &[u8]) -> Option<Bytes> {
+        if data.len() < self.compression.min_size {
+            return None;
+        }
+        if self.backoff > 0 {
+            self.backoff -= 1;
+            return None;
+        }
+        // The output buffer never grows, so running out of room means the
+        // data doesn't shrink enough.
+        let savings = usize::from(self.compression.min_savings.min(100));
+        let mut msg = Vec::with_capacity(1 + data.len() * (100 - savings) / 100);
+        msg.push(DEFLATED);
+        self.compress.reset();
+        match self
+            .compress
+            .compress_vec(data, &mut msg, FlushCompress::Finish)
+        {
+            Ok(Status::StreamEnd) => Some(msg.into()),
+            _ => {
+                self.backoff = BACKOFF_MESSAGES;
+                None
+            }
+        }
+    }
+
+    /// Strips the flag byte of a message, and decompresses its data if it
+    /// was deflated.
+    pub fn decompress(&mut self, msg: Bytes) -> Result<Bytes, Error> {
+        let wire_size = msg.len();
+        let data = match msg.first() {
+            Some(&RAW) => msg.slice(1..),
+            Some(&DEFLATED) => self.inflate(&msg[1..])?,
+            _ => return Err(invalid_data("unknown compression flag")),
+        };
+        self.count(data.len(), wire_size);
+        Ok(data)
+    }
+
+    /// Counts a message into the counter and the current session.
+    fn count(&self, uncompressed: usize, compressed: usize) {
+        if let Some(counter) = &self.counter {
+            counter.add_compression(uncompressed, compressed);
+        }
+        stats::add_session_compression(uncompressed, compressed);
+    }
+
+    fn inflate(&mut self, input: &[u8]) -> Result<Bytes, Error> {
+        let limit = self.max_message_size;
+        let mut data = Vec::with_capacity(input.len().saturating_mul(4).clamp(1, limit));
+        self.decompress.reset(false);
+        loop {
+            let (consumed, produced) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(
+                    &input[consumed as usize..],
+                    &mut data,
+                    FlushDecompress::None,
+                )
+                .map_err(|_| invalid_data("corrupt deflated message"))?;
+            if status == Status::StreamEnd {
+                return Ok(data.into());
+            }
+            if data.len() == data.capacity() {
+                if data.len() >= limit {
+                    return Err(invalid_data("deflated message is too large"));
+                }
+                data.reserve_exact(data.len().min(limit - data.len()));
+            } else if (consumed, produced)
+                == (self.decompress.total_in(), self.decompress.total_out())
+            {
+                return Err(invalid_data("truncated deflated message"));
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Asks the server to compress.
+#[cfg(feature = "client")]
+pub(crate) fn offer(request: &mut tokio_tungstenite::tungstenite::handshake::client::Request) {
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    request
+        .headers_mut()
+        .insert(DEFLATE_HEADER, HeaderValue::from_static(DEFLATE_VERSION));
+}
+
+/// Returns whether the server agreed to compress.
+#[cfg(feature = "client")]
+pub(crate) fn accepted(
+    response: &tokio_tungstenite::tungstenite::handshake::client::Response,
+) -> bool {
+    response
+        .headers()
+        .get(DEFLATE_HEADER)
+        .is_some_and(|version| version == DEFLATE_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deflate() -> Deflate {
+        Compression::default().deflate(&FrameLimits::default())
+    }
+
+    /// Data that deflate can't shrink.
+    fn noise(len: usize) -> Bytes {
+        let mut state = 0x2545_F491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_below_the_size_threshold_are_raw() {
+        let mut deflate = deflate();
+        let data = Bytes::from(vec![b'a'; Compression::default().min_size - 1]);
+        let msg = deflate.compress(data.clone());
+        assert_eq!((msg[0], &msg[1..]), (RAW, &data[..]));
+
+        let msg = deflate.compress(Bytes::new());
+        assert_eq!(&msg[..], &[RAW]);
+        assert_eq!(deflate.decompress(msg).unwrap(), Bytes::new());
+    }
+
+    #[test]
+    fn compressible_messages_round_trip() {
+        let (mut sender, mut receiver) = (deflate(), deflate());
+        for _ in 0..3 {
+            let data = Bytes::from(b"wsrx ".repeat(1000));
+            let msg = sender.compress(data.clone());
+            assert_eq!(msg[0], DEFLATED);
+            assert!(msg.len() < data.len() / 10);
+            assert_eq!(receiver.decompress(msg).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn incompressible_messages_back_off() {
+        let (mut sender, mut receiver) = (deflate(), deflate());
+        let data = noise(4096);
+        let msg = sender.compress(data.clone());
+        assert_eq!(msg[0], RAW);
+        assert_eq!(receiver.decompress(msg).unwrap(), data);
+
+        let compressible = Bytes::from(vec![0; 4096]);
+        for _ in 0..BACKOFF_MESSAGES {
+            assert_eq!(sender.compress(compressible.clone())[0], RAW);
+        }
+        assert_eq!(sender.compress(compressible)[0], DEFLATED);
+    }
+
+    #[test]
+    fn invalid_messages_are_refused() {
+        let mut deflate = deflate();
+        assert!(deflate.decompress(Bytes::new()).is_err());
+        assert!(deflate.decompress(Bytes::from_static(&[7, 1, 2])).is_err());
+        assert!(
+            deflate
+                .decompress(Bytes::from_static(&[DEFLATED, 0xFF, 0xFF]))
+                .is_err()
+        );
+
+        let msg = deflate.compress(Bytes::from(vec![0; 64 * 1024]));
+        assert!(deflate.decompress(msg.slice(..msg.len() / 2)).is_err());
+        let limits = FrameLimits {
+            max_message_size: 1024,
+            ..Default::default()
+        };
+        let mut small = Compression::default().deflate(&limits);
+        assert!(small.decompress(msg).is_err());
+    }
+
+    #[tokio::test]
+    async fn compression_is_counted_into_the_session() {
+        let (counter, session) = (TrafficCounter::new(), TrafficCounter::new());
+        let mut deflate = deflate().with_counter(Some(counter.clone()));
+        let msg = session
+            .scope(async { deflate.compress(Bytes::from(vec![0; 4096])) })
+            .await;
+        deflate.decompress(msg.clone()).unwrap();
+
+        let session = session.snapshot();
+        assert_eq!(session.uncompressed_bytes, 4096);
+        assert_eq!(session.compressed_bytes, msg.len() as u64);
+        let counter = counter.snapshot();
+        assert_eq!(counter.uncompressed_bytes, 2 * 4096);
+        assert_eq!(counter.compressed_bytes, 2 * msg.len() as u64);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn compression_needs_the_header_of_the_server() {
+        use tokio_tungstenite::tungstenite::{
+            client::IntoClientRequest, handshake::client::Response, http::HeaderValue,
+        };
+
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        offer(&mut request);
+        assert_eq!(request.headers()[DEFLATE_HEADER], DEFLATE_VERSION);
+
+        let mut response = Response::new(None);
+        assert!(!accepted(&response));
+        response
+            .headers_mut()
+            .insert(DEFLATE_HEADER, HeaderValue::from_static("2"));
+        assert!(!accepted(&response));
+        response
+            .headers_mut()
+            .insert(DEFLATE_HEADER, HeaderValue::from_static(DEFLATE_VERSION));
+        assert!(accepted(&response));
+    }
+}
@@ -0,0 +1,390 @@
+//! End-to-end encryption of WebSocket data.
+//!
+//! TLS ends wherever the WebSocket is terminated, which may be a CDN or a
+//! load balancer in front of the server rather than the server itself. With a
+//! key shared by both ends, the data of every message is also sealed with
+//! ChaCha20-Poly1305, so only the two ends of a tunnel can read or alter it.
+//!
+//! The key is never used directly. Both sides send a random salt with the
+//! [`ENCRYPT_HEADER`] handshake header, and each connection derives its own
+//! key per direction from the shared key and both salts with HKDF-SHA256.
+//! Nonces count the messages of a direction, so a message that is dropped,
+//! replayed or reordered fails the connection. The end of data is sealed
+//! too, an unsealed message is never accepted.
+//!
+//! Neither side falls back to plain data: a client with a key fails if the
+//! server doesn't answer with its salt, and a server with a key rejects
+//! clients without one. Pings and close frames are not encrypted.
+
+use std::{fmt, io, str::FromStr};
+
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use ring::{
+    aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey},
+    hkdf::{self, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio_util::bytes::{Bytes, BytesMut};
+
+use crate::proxy::Error;
+
+/// The handshake header that carries the salts of both sides.
+pub const ENCRYPT_HEADER: &str = "wsrx-encrypt";
+
+/// The version of the wire format, the first part of [`ENCRYPT_HEADER`].
+pub const ENCRYPT_VERSION: &str = "1";
+
+/// The length of the random salt each side sends.
+const SALT_LEN: usize = 16;
+
+/// The salt of keys derived from a secret.
+const SECRET_SALT: &[u8] = b"wsrx-encrypt";
+
+const CLIENT_TO_SERVER: &[u8] = b"wsrx client to server";
+const SERVER_TO_CLIENT: &[u8] = b"wsrx server to client";
+
+/// A key shared by a client and a server.
+///
+/// Keys are written as 32 bytes of base64, e.g. from
+/// `openssl rand -base64 32`.
+#[derive(Clone, Eq, PartialEq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Creates a key from its bytes.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Generates a random key.
+    pub fn generate() -> Result<Self, Error> {
+        let mut key = [0; 32];
+        fill_random(&mut key)?;
+        Ok(Self(key))
+    }
+
+    /// Derives a key from a secret, such as the secret of a server.
+    ///
+    /// The secret is not stretched, so it should be long and random.
+    pub fn from_secret(secret: &str) -> Self {
+        let mut key = [0; 32];
+        hkdf::Salt::new(HKDF_SHA256, SECRET_SALT)
+            .extract(secret.as_bytes())
+            .expand(&[b"pre-shared key"], HKDF_SHA256)
+            .and_then(|okm| okm.fill(&mut key))
+            .expect("HKDF output is one hash long");
+        Self(key)
+    }
+
+    /// Starts the handshake of a client.
+    ///
+    /// Returns the value of [`ENCRYPT_HEADER`] to send, and the state that
+    /// derives the cipher from the answer of the server.
+    pub fn offer(&self) -> Result<(KeyExchange, String), Error> {
+        let mut salt = [0; SALT_LEN];
+        fill_random(&mut salt)?;
+        let exchange = KeyExchange {
+            key: self.clone(),
+            salt,
+        };
+        Ok((exchange, header_value(&salt)))
+    }
+
+    /// Answers the handshake of a client with the value of its
+    /// [`ENCRYPT_HEADER`].
+    ///
+    /// Returns the cipher of the server and the value of the header to
+    /// answer with.
+    pub fn accept(&self, offer: &str) -> Result<(Cipher, String), Error> {
+        let client_salt = parse_header(offer)?;
+        let mut salt = [0; SALT_LEN];
+        fill_random(&mut salt)?;
+        let cipher = self.cipher(&client_salt, &salt, SERVER_TO_CLIENT, CLIENT_TO_SERVER);
+        Ok((cipher, header_value(&salt)))
+    }
+
+    /// Derives the cipher of a connection, sealing with the key of one
+    /// direction and opening with the other.
+    fn cipher(
+        &self, client_salt: &[u8; SALT_LEN], server_salt: &[u8; SALT_LEN], sealing: &[u8],
+        opening: &[u8],
+    ) -> Cipher {
+        let salt = [client_salt.as_slice(), server_salt.as_slice()].concat();
+        let prk = hkdf::Salt::new(HKDF_SHA256, &salt).extract(&self.0);
+        let key = |info: &[u8]| {
+            let info = [info];
+            let okm = prk
+                .expand(&info, &CHACHA20_POLY1305)
+                .expect("HKDF output is one key long");
+            LessSafeKey::new(UnboundKey::from(okm))
+        };
+        Cipher {
+            sealing: key(sealing),
+            opening: key(opening),
+            sealed: 0,
+            opened: 0,
+        }
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl fmt::Display for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_STANDARD.encode(self.0))
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BASE64_STANDARD
+            .decode(s.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .map(Self)
+            .ok_or_else(|| String::from("an encryption key must be 32 bytes of base64"))
+    }
+}
+
+#[cfg(feature = "binary")]
+impl<'de> serde::Deserialize<'de> for EncryptionKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The handshake of a client, waiting for the answer of the server.
+pub struct KeyExchange {
+    key: EncryptionKey,
+    salt: [u8; SALT_LEN],
+}
+
+impl KeyExchange {
+    /// Derives the cipher of the client from the value of the
+    /// [`ENCRYPT_HEADER`] the server answered with, which is an error if it
+    /// didn't.
+    pub fn finish(self, answer: Option<&str>) -> Result<Cipher, Error> {
+        let answer = answer.ok_or_else(|| invalid_data("the server does not encrypt data"))?;
+        let server_salt = parse_header(answer)?;
+        let cipher = self
+            .key
+            .cipher(&self.salt, &server_salt, CLIENT_TO_SERVER, SERVER_TO_CLIENT);
+        Ok(cipher)
+    }
+}
+
+/// The encryption state of a WebSocket connection.
+pub struct Cipher {
+    sealing: LessSafeKey,
+    opening: LessSafeKey,
+    /// Messages sealed so far, the nonce of the next one.
+    sealed: u64,
+    /// Messages opened so far, the nonce of the next one.
+    opened: u64,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("sealed", &self.sealed)
+            .field("opened", &self.opened)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    /// Seals the data of a message, appending its tag.
+    pub fn seal(&mut self, data: &[u8]) -> Result<Bytes, Error> {
+        let nonce = next_nonce(&mut self.sealed)?;
+        let mut msg = BytesMut::with_capacity(data.len() + CHACHA20_POLY1305.tag_len());
+        msg.extend_from_slice(data);
+        self.sealing
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut msg)
+            .map_err(|_| invalid_data("failed to encrypt message"))?;
+        Ok(msg.freeze())
+    }
+
+    /// Opens a sealed message, failing if it was not sealed by the peer or
+    /// not in order.
+    pub fn open(&mut self, msg: Bytes) -> Result<Bytes, Error> {
+        let nonce = next_nonce(&mut self.opened)?;
+        let mut msg = BytesMut::from(msg);
+        let len = self
+            .opening
+            .open_in_place(nonce, Aad::empty(), &mut msg)
+            .map_err(|_| invalid_data("failed to decrypt message"))?
+            .len();
+        msg.truncate(len);
+        Ok(msg.freeze())
+    }
+}
+
+fn next_nonce(counter: &mut u64) -> Result<Nonce, Error> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter = counter
+        .checked_add(1)
+        .ok_or_else(|| invalid_data("too many encrypted messages"))?;
+    Ok(Nonce::assume_unique_for_key(nonce))
+}
+
+fn fill_random(buf: &mut [u8]) -> Result<(), Error> {
+    SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| io::Error::other("failed to generate random bytes").into())
+}
+
+fn header_value(salt: &[u8; SALT_LEN]) -> String {
+    format!("{ENCRYPT_VERSION} {}", BASE64_URL_SAFE_NO_PAD.encode(salt))
+}
+
+fn parse_header(value: &str) -> Result<[u8; SALT_LEN], Error> {
+    value
+        .split_once(' ')
+        .filter(|(version, _)| *version == ENCRYPT_VERSION)
+        .and_then(|(_, salt)| BASE64_URL_SAFE_NO_PAD.decode(salt).ok())
+        .and_then(|salt| salt.try_into().ok())
+        .ok_or_else(|| invalid_data("invalid encryption handshake"))
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Offers encryption to the server.
+#[cfg(feature = "client")]
+pub(crate) fn offer(
+    key: &EncryptionKey, request: &mut tokio_tungstenite::tungstenite::handshake::client::Request,
+) -> Result<KeyExchange, Error> {
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    let (exchange, value) = key.offer()?;
+    let value = HeaderValue::from_str(&value).expect("salts are url-safe base64");
+    request.headers_mut().insert(ENCRYPT_HEADER, value);
+    Ok(exchange)
+}
+
+/// Derives the cipher of the client from the handshake response.
+#[cfg(feature = "client")]
+pub(crate) fn accepted(
+    exchange: KeyExchange, response: &tokio_tungstenite::tungstenite::handshake::client::Response,
+) -> Result<Cipher, Error> {
+    let answer = response
+        .headers()
+        .get(ENCRYPT_HEADER)
+        .and_then(|value| value.to_str().ok());
+    exchange.finish(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the handshake of both sides with their own keys, returning the
+    /// ciphers of the client and the server.
+    fn handshake(client: &EncryptionKey, server: &EncryptionKey) -> (Cipher, Cipher) {
+        let (exchange, offer) = client.offer().unwrap();
+        let (server, answer) = server.accept(&offer).unwrap();
+        (exchange.finish(Some(&answer)).unwrap(), server)
+    }
+
+    #[test]
+    fn messages_round_trip_both_ways() {
+        let key = EncryptionKey::generate().unwrap();
+        let (mut client, mut server) = handshake(&key, &key);
+        for data in [&b"hello"[..], b"", b"world"] {
+            let sealed = client.seal(data).unwrap();
+            assert_eq!(sealed.len(), data.len() + CHACHA20_POLY1305.tag_len());
+            assert_eq!(server.open(sealed).unwrap(), data);
+            let sealed = server.seal(data).unwrap();
+            assert_eq!(client.open(sealed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn bad_tag_is_rejected() {
+        let key = EncryptionKey::generate().unwrap();
+        let (mut client, mut server) = handshake(&key, &key);
+        let mut sealed = BytesMut::from(client.seal(b"hello").unwrap());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(server.open(sealed.freeze()).is_err());
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let key = EncryptionKey::generate().unwrap();
+        let (mut client, mut server) = handshake(&key, &key);
+        let sealed = client.seal(b"hello").unwrap();
+        assert!(server.open(sealed.slice(..4)).is_err());
+    }
+
+    #[test]
+    fn reordered_and_replayed_messages_are_rejected() {
+        let key = EncryptionKey::generate().unwrap();
+        let (mut client, mut server) = handshake(&key, &key);
+        client.seal(b"first").unwrap();
+        let second = client.seal(b"second").unwrap();
+        assert!(server.open(second).is_err());
+
+        let (mut client, mut server) = handshake(&key, &key);
+        let first = client.seal(b"first").unwrap();
+        assert_eq!(server.open(first.clone()).unwrap(), "first");
+        assert!(server.open(first).is_err());
+    }
+
+    #[test]
+    fn messages_only_open_in_their_direction() {
+        let key = EncryptionKey::generate().unwrap();
+        let (mut client, _server) = handshake(&key, &key);
+        let sealed = client.seal(b"hello").unwrap();
+        assert!(client.open(sealed).is_err());
+    }
+
+    #[test]
+    fn different_keys_cannot_talk() {
+        let client_key = EncryptionKey::generate().unwrap();
+        let server_key = EncryptionKey::generate().unwrap();
+        let (mut client, mut server) = handshake(&client_key, &server_key);
+        let sealed = client.seal(b"hello").unwrap();
+        assert!(server.open(sealed).is_err());
+    }
+
+    #[test]
+    fn invalid_handshakes_are_rejected() {
+        let key = EncryptionKey::generate().unwrap();
+        let (exchange, offer) = key.offer().unwrap();
+        assert!(exchange.finish(None).is_err());
+        assert!(key.accept("2 AAAAAAAAAAAAAAAAAAAAAA").is_err());
+        assert!(key.accept("1 AAAA").is_err());
+        assert!(key.accept("1").is_err());
+        assert!(key.accept(&offer.replacen('1', "0", 1)).is_err());
+    }
+
+    #[test]
+    fn keys_parse_and_print_as_base64() {
+        let key = EncryptionKey::generate().unwrap();
+        assert_eq!(key.to_string().parse::<EncryptionKey>().unwrap(), key);
+        assert!("c2hvcnQ=".parse::<EncryptionKey>().is_err());
+        assert!("not base64".parse::<EncryptionKey>().is_err());
+        assert_eq!(
+            EncryptionKey::from_secret("secret"),
+            EncryptionKey::from_secret("secret")
+        );
+        assert_ne!(
+            EncryptionKey::from_secret("secret"),
+            EncryptionKey::from_secret("other")
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Handshake options of outgoing WebSocket connections.
+//!
+//! Some platforms gate their traffic endpoint behind a session cookie, an
+//! `Authorization` header or a `Sec-WebSocket-Protocol` value, so tunnels can
+//! send extra data with the handshake request.
+
+use std::collections::BTreeMap;
+
+use tokio_tungstenite::tungstenite::{
+    Error as TgError,
+    client::IntoClientRequest,
+    handshake::client::{Request, Response},
+    http::{
+        HeaderName, HeaderValue,
+        header::{AUTHORIZATION, COOKIE, SEC_WEBSOCKET_PROTOCOL},
+    },
+};
+
+use crate::proxy::Error;
+
+/// Extra data sent with the handshake of outgoing WebSocket connections.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "binary", serde(default))]
+pub struct Handshake {
+    /// Extra request headers, by name.
+    pub headers: BTreeMap<String, String>,
+    /// Cookies as `name=value` pairs, sent in one `Cookie` header.
+    pub cookies: Vec<String>,
+    /// Subprotocols offered in the `Sec-WebSocket-Protocol` header, the
+    /// server must accept one of them.
+    pub subprotocols: Vec<String>,
+    /// A token sent as `Authorization: Bearer <token>`.
+    pub bearer_token: Option<String>,
+}
+
+impl Handshake {
+    /// Returns `true` if nothing is added to the handshake.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+            && self.cookies.is_empty()
+            && self.subprotocols.is_empty()
+            && self.bearer_token.is_none()
+    }
+
+    /// Returns the HTTP headers of the handshake, without the subprotocols.
+    ///
+    /// These are also useful for plain HTTP requests to the same endpoint,
+    /// such as latency probes.
+    pub fn http_headers(&self) -> Vec<(String, String)> {
+        let mut headers: Vec<_> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        if !self.cookies.is_empty() {
+            headers.push((COOKIE.to_string(), self.cookies.join("; ")));
+        }
+        if let Some(token) = &self.bearer_token {
+            headers.push((AUTHORIZATION.to_string(), format!("Bearer {token}")));
+        }
+        headers
+    }
+
+    /// Builds the handshake request to the WebSocket url.
+    pub fn request(&self, url: &str) -> Result<Request, Error> {
+        self.request_with_protocols(url, &[])
+    }
+
+    /// Builds the handshake request to the WebSocket url, offering the given
+    /// subprotocols before the configured ones.
+    pub fn request_with_protocols(&self, url: &str, protocols: &[&str]) -> Result<Request, Error> {
+        let mut request = url.into_client_request()?;
+        let headers = request.headers_mut();
+        for (name, value) in self.http_headers() {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(http_error)?;
+            let value = HeaderValue::from_str(&value).map_err(http_error)?;
+            headers.append(name, value);
+        }
+        let protocols: Vec<&str> = protocols
+            .iter()
+            .copied()
+            .chain(self.subprotocols.iter().map(String::as_str))
+            .collect();
+        if !protocols.is_empty() {
+            let value = HeaderValue::from_str(&protocols.join(", ")).map_err(http_error)?;
+            headers.insert(SEC_WEBSOCKET_PROTOCOL, value);
+        }
+        Ok(request)
+    }
+}
+
+/// Returns whether the server picked the subprotocol from the offered ones.
+pub(crate) fn accepted_protocol(response: &Response, protocol: &str) -> bool {
+    response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .is_some_and(|p| p == protocol)
+}
+
+fn http_error(e: impl Into<tokio_tungstenite::tungstenite::http::Error>) -> Error {
+    TgError::HttpFormat(e.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        deflate::{self, DEFLATE_HEADER, DEFLATE_VERSION},
+        encrypt::{self, ENCRYPT_HEADER, EncryptionKey},
+        mux::MUX_PROTOCOL,
+        resume::RESUME_PROTOCOL,
+    };
+
+    fn header(request: &Request, name: impl AsRef<str>) -> Option<&str> {
+        request
+            .headers()
+            .get(name.as_ref())
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn requests_carry_the_extra_data() {
+        let handshake = Handshake {
+            headers: BTreeMap::from([("X-Team".into(), "blue".into())]),
+            cookies: vec!["session=abc".into(), "lang=en".into()],
+            subprotocols: vec![],
+            bearer_token: Some("token".into()),
+        };
+        let request = handshake.request("ws://localhost/traffic/a").unwrap();
+        assert_eq!(header(&request, "x-team"), Some("blue"));
+        assert_eq!(header(&request, COOKIE), Some("session=abc; lang=en"));
+        assert_eq!(header(&request, AUTHORIZATION), Some("Bearer token"));
+        assert_eq!(header(&request, SEC_WEBSOCKET_PROTOCOL), None);
+
+        assert!(Handshake::default().is_empty());
+        assert!(!handshake.is_empty());
+    }
+
+    #[test]
+    fn protocols_of_the_tunnel_are_offered_first() {
+        let handshake = Handshake {
+            subprotocols: vec!["chat".into()],
+            ..Default::default()
+        };
+        let request = handshake
+            .request_with_protocols("ws://localhost/", &[MUX_PROTOCOL])
+            .unwrap();
+        assert_eq!(
+            header(&request, SEC_WEBSOCKET_PROTOCOL),
+            Some("wsrx-mux, chat")
+        );
+        let request = Handshake::default()
+            .request_with_protocols("ws://localhost/", &[RESUME_PROTOCOL])
+            .unwrap();
+        assert_eq!(
+            header(&request, SEC_WEBSOCKET_PROTOCOL),
+            Some("wsrx-resume")
+        );
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let handshake = Handshake {
+            headers: BTreeMap::from([("bad name".into(), "value".into())]),
+            ..Default::default()
+        };
+        assert!(handshake.request("ws://localhost/").is_err());
+        let handshake = Handshake {
+            bearer_token: Some("line\nbreak".into()),
+            ..Default::default()
+        };
+        assert!(handshake.request("ws://localhost/").is_err());
+    }
+
+    #[test]
+    fn negotiation_headers_are_offered_together() {
+        let key = EncryptionKey::from_secret("secret");
+        let mut request = Handshake::default()
+            .request_with_protocols("ws://localhost/", &[MUX_PROTOCOL])
+            .unwrap();
+        deflate::offer(&mut request);
+        encrypt::offer(&key, &mut request).unwrap();
+        assert_eq!(header(&request, SEC_WEBSOCKET_PROTOCOL), Some(MUX_PROTOCOL));
+        assert_eq!(header(&request, DEFLATE_HEADER), Some(DEFLATE_VERSION));
+        let offer = header(&request, ENCRYPT_HEADER).unwrap();
+        assert!(key.accept(offer).is_ok());
+    }
+
+    #[test]
+    fn nothing_is_negotiated_without_the_headers_of_the_server() {
+        let key = EncryptionKey::from_secret("secret");
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        let exchange = encrypt::offer(&key, &mut request).unwrap();
+
+        let response = Response::new(None);
+        assert!(!accepted_protocol(&response, MUX_PROTOCOL));
+        assert!(!accepted_protocol(&response, RESUME_PROTOCOL));
+        assert!(!deflate::accepted(&response));
+        assert!(encrypt::accepted(exchange, &response).is_err());
+    }
+
+    #[test]
+    fn unknown_answers_of_the_server_are_not_accepted() {
+        let key = EncryptionKey::from_secret("secret");
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        let exchange = encrypt::offer(&key, &mut request).unwrap();
+
+        let mut response = Response::new(None);
+        let headers = response.headers_mut();
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("chat"));
+        headers.insert(DEFLATE_HEADER, HeaderValue::from_static("2"));
+        headers.insert(ENCRYPT_HEADER, HeaderValue::from_static("2 AAAA"));
+        assert!(!accepted_protocol(&response, MUX_PROTOCOL));
+        assert!(!deflate::accepted(&response));
+        assert!(encrypt::accepted(exchange, &response).is_err());
+    }
+
+    #[test]
+    fn answers_of_the_server_are_accepted() {
+        let key = EncryptionKey::from_secret("secret");
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        let exchange = encrypt::offer(&key, &mut request).unwrap();
+        let (_, answer) = key
+            .accept(header(&request, ENCRYPT_HEADER).unwrap())
+            .unwrap();
+
+        let mut response = Response::new(None);
+        let headers = response.headers_mut();
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(RESUME_PROTOCOL),
+        );
+        headers.insert(DEFLATE_HEADER, HeaderValue::from_static(DEFLATE_VERSION));
+        headers.insert(ENCRYPT_HEADER, HeaderValue::from_str(&answer).unwrap());
+        assert!(accepted_protocol(&response, RESUME_PROTOCOL));
+        assert!(!accepted_protocol(&response, MUX_PROTOCOL));
+        assert!(deflate::accepted(&response));
+        assert!(encrypt::accepted(exchange, &response).is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+//! Idle timeouts of proxied sessions.
+//!
+//! [`IdleTimeout`] wraps one side of a session and notes every message that
+//! passes through it, in either direction. Once no message passed for the
+//! timeout, the token of the session is cancelled, which closes it like any
+//! other cancellation. The timer runs on its own task, so a session is timed
+//! out even if one direction is already closed and the other one is stuck.
+
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{sink::Sink, stream::Stream};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::proxy::{Error, Message};
+
+/// When the last message of a session passed.
+struct Activity {
+    started: Instant,
+    /// Milliseconds from `started` to the last message.
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// A stream that cancels its session once it stays idle for too long.
+pub struct IdleTimeout<S> {
+    inner: S,
+    activity: Option<Arc<Activity>>,
+    token: CancellationToken,
+}
+
+impl<S> IdleTimeout<S> {
+    /// Wraps a stream, `None` disables the timeout.
+    ///
+    /// The session must be run with [`IdleTimeout::token`], a child of
+    /// `token`. Must be called within a tokio runtime.
+    pub fn new(inner: S, timeout: Option<Duration>, token: CancellationToken) -> Self {
+        let Some(timeout) = timeout else {
+            return Self {
+                inner,
+                activity: None,
+                token,
+            };
+        };
+        let activity = Arc::new(Activity {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+        });
+        let token = token.child_token();
+        tokio::spawn(watch(activity.clone(), timeout, token.clone()));
+        Self {
+            inner,
+            activity: Some(activity),
+            token,
+        }
+    }
+
+    /// Returns the token to run the session with, cancelled once it is idle.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    fn touch(&self) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+    }
+}
+
+/// Cancels the token once no message passed for the timeout.
+async fn watch(activity: Arc<Activity>, timeout: Duration, token: CancellationToken) {
+    loop {
+        let deadline = activity.last() + timeout;
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {}
+            _ = token.cancelled() => return,
+        }
+        if activity.last() + timeout <= Instant::now() {
+            token.cancel();
+            return;
+        }
+    }
+}
+
+impl<S> Drop for IdleTimeout<S> {
+    /// Stops the timer, the session is over.
+    fn drop(&mut self) {
+        if self.activity.is_some() {
+            self.token.cancel();
+        }
+    }
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let msg = futures_util::ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(_)) = msg {
+            self.touch();
+        }
+        Poll::Ready(msg)
+    }
+}
+
+impl<S> Sink<Message> for IdleTimeout<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.touch();
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+    use crate::proxy::tests::message_pipe;
+
+    fn data() -> Message {
+        Message::Binary(Bytes::from_static(b"data"))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_are_cancelled_once_idle() {
+        let (mut peer, pipe) = message_pipe();
+        let token = CancellationToken::new();
+        let mut conn = IdleTimeout::new(pipe, Some(Duration::from_secs(10)), token.clone());
+        let idle = conn.token();
+
+        // Messages in either direction keep the session going.
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        peer.send(data()).await.unwrap();
+        conn.next().await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        conn.send(data()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(8)).await;
+        assert!(!idle.is_cancelled());
+
+        let started = Instant::now();
+        idle.cancelled().await;
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert!(!token.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_without_a_timeout_are_never_cancelled() {
+        let (_peer, pipe) = message_pipe();
+        let conn = IdleTimeout::new(pipe, None, CancellationToken::new());
+        let idle = conn.token();
+
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert!(!idle.is_cancelled());
+        drop(conn);
+        assert!(!idle.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_the_session_stops_the_timer() {
+        let (_peer, pipe) = message_pipe();
+        let token = CancellationToken::new();
+        let conn = IdleTimeout::new(pipe, Some(Duration::from_secs(10)), token.clone());
+        let idle = conn.token();
+
+        drop(conn);
+        assert!(idle.is_cancelled());
+        assert!(!token.is_cancelled());
+    }
+}
@@ -1,14 +1,38 @@
 //! WebSocket Reflector X
 //!
-//! A simple crate that proxies pure TCP connections to WebSocket connections
-//! and vice versa.
+//! A simple crate that proxies pure TCP connections and UDP datagrams to
+//! WebSocket connections and vice versa.
 
+pub mod capture;
+pub mod datagram;
+pub mod deflate;
+pub mod encrypt;
+pub mod idle;
+pub mod limit;
+pub mod mux;
+pub mod observe;
+pub mod pool;
 pub mod proxy;
+pub mod resume;
+pub mod stats;
+pub mod unix;
+
+#[cfg(feature = "client")]
+pub mod handshake;
 
 #[cfg(feature = "client")]
 pub mod utils;
 
+#[cfg(feature = "client")]
+pub mod tls;
+
 #[cfg(feature = "client")]
 pub mod tunnel;
 
-pub use proxy::{Error, Message, WrappedWsStream, proxy};
+#[cfg(feature = "client")]
+pub mod upstream;
+
+pub use proxy::{
+    Error, FrameEncoding, FrameLimits, Keepalive, Message, WrappedWsStream, proxy, proxy_io,
+    proxy_udp,
+};
@@ -0,0 +1,315 @@
+//! Bandwidth limiting for WebSocket Reflector X.
+//!
+//! Limits are token buckets shared by any number of sessions: one bucket per
+//! connection, per tunnel key or for the whole process, depending on how
+//! widely it is cloned. A session that runs out of tokens is not dropped, it
+//! simply stops reading (or accepting writes) until the bucket refills, which
+//! pushes back on the sender through TCP and WebSocket flow control.
+//!
+//! Directions follow [`stats`](crate::stats): `inbound` is traffic read from
+//! the WebSocket, `outbound` is traffic written to it.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{sink::Sink, stream::Stream};
+use tokio::time::{Instant, Sleep};
+
+use crate::proxy::{Error, Message};
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket that limits throughput to a number of bytes per second.
+///
+/// Cloning the limiter yields a handle to the same bucket.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` allowing `bytes_per_second`, with a burst
+    /// of one second worth of traffic.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self::with_burst(bytes_per_second, bytes_per_second)
+    }
+
+    /// Creates a new `RateLimiter` allowing `bytes_per_second`, with a burst
+    /// of `burst` bytes.
+    pub fn with_burst(bytes_per_second: u64, burst: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        let capacity = burst.max(1) as f64;
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                capacity,
+                tokens: capacity,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes `bytes` tokens from the bucket.
+    ///
+    /// The bucket may go into debt, the returned instant is when the debt is
+    /// paid off and the caller may proceed. `None` means it may proceed now.
+    fn reserve(&self, bytes: usize) -> Option<Instant> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.capacity);
+        bucket.updated = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            Some(now + Duration::from_secs_f64(-bucket.tokens / bucket.rate))
+        }
+    }
+}
+
+/// The rate limiters applied to each direction of a session.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    inbound: Vec<RateLimiter>,
+    outbound: Vec<RateLimiter>,
+}
+
+impl RateLimits {
+    /// Creates an empty set of limits, which doesn't limit anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a limiter for the traffic read from the WebSocket.
+    pub fn inbound(mut self, limiter: RateLimiter) -> Self {
+        self.inbound.push(limiter);
+        self
+    }
+
+    /// Adds a limiter for the traffic written to the WebSocket.
+    pub fn outbound(mut self, limiter: RateLimiter) -> Self {
+        self.outbound.push(limiter);
+        self
+    }
+
+    /// Adds a limiter for the traffic read from the WebSocket, if a rate is
+    /// given.
+    pub fn inbound_rate(self, bytes_per_second: Option<u64>) -> Self {
+        match bytes_per_second {
+            Some(rate) => self.inbound(RateLimiter::new(rate)),
+            None => self,
+        }
+    }
+
+    /// Adds a limiter for the traffic written to the WebSocket, if a rate is
+    /// given.
+    pub fn outbound_rate(self, bytes_per_second: Option<u64>) -> Self {
+        match bytes_per_second {
+            Some(rate) => self.outbound(RateLimiter::new(rate)),
+            None => self,
+        }
+    }
+
+    /// Combines two sets of limits, traffic has to pass both of them.
+    pub fn merge(mut self, other: &RateLimits) -> Self {
+        self.inbound.extend(other.inbound.iter().cloned());
+        self.outbound.extend(other.outbound.iter().cloned());
+        self
+    }
+
+    /// Returns `true` if no limiter is set.
+    pub fn is_empty(&self) -> bool {
+        self.inbound.is_empty() && self.outbound.is_empty()
+    }
+
+    fn reserve(limiters: &[RateLimiter], bytes: usize) -> Option<Instant> {
+        limiters
+            .iter()
+            .filter_map(|limiter| limiter.reserve(bytes))
+            .max()
+    }
+}
+
+/// A wrapper that applies rate limits to the stream side of a session.
+///
+/// Messages read from the wrapped stream are held back until the outbound
+/// limiters allow them, and after a message is written to it, the next write
+/// waits until the inbound limiters allow it.
+pub struct RateLimited<S> {
+    inner: S,
+    limits: RateLimits,
+    held: Option<(Pin<Box<Sleep>>, Message)>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimited<S> {
+    /// Wraps a stream with the given limits.
+    pub fn new(inner: S, limits: RateLimits) -> Self {
+        Self {
+            inner,
+            limits,
+            held: None,
+            write_delay: None,
+        }
+    }
+}
+
+/// A wrapper around rate limited stream that implements `Stream` trait.
+impl<S> Stream for RateLimited<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    /// Polls the next message, once the outbound limiters allow it.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some((delay, _)) = &mut this.held {
+            futures_util::ready!(delay.as_mut().poll(cx));
+            let (_, msg) = this.held.take().unwrap();
+            return Poll::Ready(Some(Ok(msg)));
+        }
+        let msg = match futures_util::ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+            Some(Ok(msg)) => msg,
+            other => return Poll::Ready(other),
+        };
+        let deadline = match &msg {
+            Message::Binary(data) => RateLimits::reserve(&this.limits.outbound, data.len()),
+            _ => None,
+        };
+        match deadline {
+            Some(deadline) => {
+                let mut delay = Box::pin(tokio::time::sleep_until(deadline));
+                if delay.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                this.held = Some((delay, msg));
+                Poll::Pending
+            }
+            None => Poll::Ready(Some(Ok(msg))),
+        }
+    }
+}
+
+/// A wrapper around rate limited stream that implements `Sink` trait.
+impl<S> Sink<Message> for RateLimited<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    /// Waits for the inbound limiters, then polls the wrapped sink.
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(delay) = &mut self.write_delay {
+            futures_util::ready!(delay.as_mut().poll(cx));
+            self.write_delay = None;
+        }
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    /// Sends a message to the wrapped sink, charging the inbound limiters.
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let Message::Binary(data) = &item
+            && let Some(deadline) = RateLimits::reserve(&self.limits.inbound, data.len())
+        {
+            self.write_delay = Some(Box::pin(tokio::time::sleep_until(deadline)));
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    /// Polls the wrapped sink to flush the message.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    /// Polls the wrapped sink to close the connection.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::bytes::Bytes;
+
+    use super::*;
+    use crate::proxy::tests::message_pipe;
+
+    fn data(len: usize) -> Message {
+        Message::Binary(Bytes::from(vec![0; len]))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_up_to_the_capacity_pass() {
+        let limiter = RateLimiter::with_burst(1000, 4000);
+        for _ in 0..4 {
+            assert_eq!(limiter.reserve(1000), None);
+        }
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(500), Some(now + Duration::from_millis(500)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_bucket_refills_over_time() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.reserve(1000), None);
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(limiter.reserve(250), None);
+        // The bucket never holds more than its capacity.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(limiter.reserve(1000), None);
+        assert!(limiter.reserve(1).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_past_the_burst_wait_for_the_refill() {
+        let (mut peer, stream) = message_pipe();
+        let mut limited = RateLimited::new(stream, RateLimits::new().outbound_rate(Some(100)));
+        peer.send(data(100)).await.unwrap();
+        peer.send(data(50)).await.unwrap();
+
+        let start = Instant::now();
+        limited.next().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limited.next().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn inbound_and_outbound_limits_apply_separately() {
+        let (mut peer, stream) = message_pipe();
+        let limits = RateLimits::new().inbound_rate(Some(100));
+        let mut limited = RateLimited::new(stream, limits);
+
+        // Reads are outbound traffic, which is not limited.
+        let start = Instant::now();
+        for _ in 0..3 {
+            peer.send(data(1000)).await.unwrap();
+            limited.next().await.unwrap().unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The first write takes the burst, the next one waits for it.
+        limited.send(data(200)).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limited.send(data(1)).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(matches!(
+            peer.next().await,
+            Some(Ok(Message::Binary(data))) if data.len() == 200
+        ));
+    }
+}
@@ -0,0 +1,802 @@
+//! Stream multiplexing for WebSocket Reflector X.
+//!
+//! A mux session runs many logical streams over one long-lived WebSocket
+//! connection, so short connections don't pay a WebSocket (and TLS) handshake
+//! each. The session is negotiated with the `wsrx-mux` subprotocol, peers that
+//! don't speak it keep using one WebSocket per connection.
+//!
+//! Every binary WebSocket message carries exactly one frame:
+//!
+//! ```text
+//! +----------+--------------------+-----------------+
+//! | kind: u8 | stream id: u32 BE  | payload ...     |
+//! +----------+--------------------+-----------------+
+//! ```
+//!
+//! * `OPEN` - the client opens a new stream, no payload.
+//! * `DATA` - the payload of a stream.
+//! * `CLOSE` - the sender will not send more data on the stream.
+//! * `WINDOW` - the receiver consumed data, the payload is the returned credit
+//!   as a `u32` BE.
+//! * `RESET` - the stream was refused or aborted, both directions fail. A
+//!   stream that is dropped before both directions were closed is reset.
+//!
+//! Each direction of a stream starts with [`INITIAL_WINDOW`] bytes of credit,
+//! a sender never has more unacknowledged bytes in flight than its credit. A
+//! peer that sends beyond its credit breaks the protocol and the session is
+//! closed, so a stream never buffers more than its window. The server side
+//! keeps at most [`MAX_STREAMS`] streams open and resets further ones. Frames
+//! wait in a queue of at most [`OUTGOING_FRAMES`] for the WebSocket, and
+//! streams stop sending while it is full.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use futures_util::{SinkExt, StreamExt, sink::Sink, stream::Stream};
+use tokio::sync::{
+    Semaphore,
+    mpsc::{self, error::TrySendError},
+};
+use tokio_util::{
+    bytes::{BufMut, Bytes, BytesMut},
+    sync::{CancellationToken, PollSemaphore, PollSender},
+};
+
+use crate::proxy::{Error, Message};
+
+/// The WebSocket subprotocol that negotiates a mux session.
+pub const MUX_PROTOCOL: &str = "wsrx-mux";
+
+/// The flow control credit each direction of a stream starts with.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The most streams the server side of a session keeps open at once.
+pub const MAX_STREAMS: usize = 1024;
+
+/// The most frames queued for the WebSocket of a session.
+pub const OUTGOING_FRAMES: usize = 64;
+
+/// The largest payload of a single `DATA` frame.
+const MAX_FRAME_PAYLOAD: usize = 32 * 1024;
+
+/// The size of the frame header.
+const HEADER_LEN: usize = 5;
+
+/// The kind of a mux frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum FrameKind {
+    Open = 0,
+    Data = 1,
+    Close = 2,
+    Window = 3,
+    Reset = 4,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameKind::Open),
+            1 => Ok(FrameKind::Data),
+            2 => Ok(FrameKind::Close),
+            3 => Ok(FrameKind::Window),
+            4 => Ok(FrameKind::Reset),
+            _ => Err(invalid_frame("unknown frame kind")),
+        }
+    }
+}
+
+/// A mux frame.
+struct Frame {
+    kind: FrameKind,
+    id: u32,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(kind: FrameKind, id: u32, payload: Bytes) -> Self {
+        Self { kind, id, payload }
+    }
+
+    fn encode(self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(HEADER_LEN + self.payload.len());
+        buf.put_u8(self.kind as u8);
+        buf.put_u32(self.id);
+        buf.extend_from_slice(&self.payload);
+        buf.freeze()
+    }
+
+    fn decode(buf: Bytes) -> Result<Self, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(invalid_frame("frame is too short"));
+        }
+        let kind = FrameKind::try_from(buf[0])?;
+        let id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let payload = buf.slice(HEADER_LEN..);
+        Ok(Self { kind, id, payload })
+    }
+}
+
+fn invalid_frame(reason: &'static str) -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid mux frame: {reason}"),
+    )
+    .into()
+}
+
+fn session_closed() -> Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed").into()
+}
+
+fn stream_reset() -> Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "mux stream reset").into()
+}
+
+/// What the session delivers to the receiving side of a stream.
+enum Incoming {
+    Data(Bytes),
+    Reset,
+}
+
+/// The receiving side of a stream, owned by the session.
+struct Slot {
+    /// Delivers `DATA` payloads to the stream, dropped when the peer closes
+    /// its sending side.
+    tx: Option<mpsc::UnboundedSender<Incoming>>,
+    /// The sending credit of the stream.
+    credit: Arc<Semaphore>,
+    /// The credit the peer has left to send to the stream.
+    window: usize,
+}
+
+/// The state shared by a session and its streams.
+struct Shared {
+    outgoing: mpsc::Sender<Frame>,
+    slots: Mutex<HashMap<u32, Slot>>,
+    token: CancellationToken,
+}
+
+impl Shared {
+    /// Resets a stream without waiting, the frame is queued by a task if the
+    /// queue is full.
+    fn reset(&self, id: u32) {
+        let frame = Frame::new(FrameKind::Reset, id, Bytes::new());
+        if let Err(TrySendError::Full(frame)) = self.outgoing.try_send(frame)
+            && let Ok(handle) = tokio::runtime::Handle::try_current()
+        {
+            let outgoing = self.outgoing.clone();
+            handle.spawn(async move { outgoing.send(frame).await.ok() });
+        }
+    }
+
+    /// Registers a new stream with the given id.
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        self.slots.lock().unwrap().insert(
+            id,
+            Slot {
+                tx: Some(tx),
+                credit: credit.clone(),
+                window: INITIAL_WINDOW as usize,
+            },
+        );
+        MuxStream {
+            id,
+            shared: self.clone(),
+            rx,
+            credit: PollSemaphore::new(credit),
+            granted: 0,
+            pending: Bytes::new(),
+            unacked: 0,
+            sink: PollSender::new(self.outgoing.clone()),
+            source: PollSender::new(self.outgoing.clone()),
+            sent_close: false,
+        }
+    }
+
+    /// Dispatches a frame received from the peer, returning the frame to
+    /// reply with, if any.
+    ///
+    /// Fails if the peer sent more data than the credit of the stream allows.
+    fn dispatch(
+        self: &Arc<Self>, frame: Frame, incoming: Option<&mpsc::UnboundedSender<MuxStream>>,
+    ) -> Result<Option<Frame>, Error> {
+        match frame.kind {
+            FrameKind::Open => {
+                let Some(incoming) = incoming else {
+                    return Ok(None);
+                };
+                let slots = self.slots.lock().unwrap();
+                if slots.contains_key(&frame.id) {
+                    return Ok(None);
+                }
+                let full = slots.len() >= MAX_STREAMS;
+                drop(slots);
+                if full {
+                    return Ok(Some(Frame::new(FrameKind::Reset, frame.id, Bytes::new())));
+                }
+                let stream = self.register(frame.id);
+                incoming.send(stream).ok();
+            }
+            FrameKind::Data => {
+                if let Some(slot) = self.slots.lock().unwrap().get_mut(&frame.id) {
+                    slot.window = slot
+                        .window
+                        .checked_sub(frame.payload.len())
+                        .ok_or_else(|| invalid_frame("stream window exceeded"))?;
+                    if let Some(tx) = &slot.tx {
+                        tx.send(Incoming::Data(frame.payload)).ok();
+                    }
+                }
+            }
+            FrameKind::Close => {
+                if let Some(slot) = self.slots.lock().unwrap().get_mut(&frame.id) {
+                    slot.tx = None;
+                }
+            }
+            FrameKind::Reset => {
+                if let Some(slot) = self.slots.lock().unwrap().remove(&frame.id) {
+                    if let Some(tx) = slot.tx {
+                        tx.send(Incoming::Reset).ok();
+                    }
+                    slot.credit.close();
+                }
+            }
+            FrameKind::Window => {
+                let Ok(credit) = <[u8; 4]>::try_from(frame.payload.as_ref()) else {
+                    return Ok(None);
+                };
+                if let Some(slot) = self.slots.lock().unwrap().get(&frame.id) {
+                    slot.credit.add_permits(u32::from_be_bytes(credit) as usize);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tears down every stream of the session.
+    fn shutdown(&self) {
+        self.token.cancel();
+        for (_, slot) in self.slots.lock().unwrap().drain() {
+            slot.credit.close();
+        }
+    }
+}
+
+/// A session that multiplexes many streams over one WebSocket connection.
+///
+/// The session is driven by a background task, which stops when the WebSocket
+/// closes or the session is dropped.
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<MuxStream>>,
+}
+
+impl MuxSession {
+    /// Creates the client side of a mux session, which opens streams.
+    pub fn client<S>(ws: S) -> Self
+    where
+        S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Send + 'static,
+    {
+        Self::new(ws, false)
+    }
+
+    /// Creates the server side of a mux session, which accepts streams.
+    pub fn server<S>(ws: S) -> Self
+    where
+        S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Send + 'static,
+    {
+        Self::new(ws, true)
+    }
+
+    fn new<S>(ws: S, accept: bool) -> Self
+    where
+        S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Send + 'static,
+    {
+        let (outgoing, mut outgoing_rx) = mpsc::channel::<Frame>(OUTGOING_FRAMES);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            outgoing,
+            slots: Mutex::new(HashMap::new()),
+            token: CancellationToken::new(),
+        });
+
+        let (mut sink, mut stream) = ws.split();
+
+        let writer_token = shared.token.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    frame = outgoing_rx.recv() => frame,
+                    _ = writer_token.cancelled() => None,
+                };
+                let Some(frame) = frame else {
+                    break;
+                };
+                if sink.send(Message::Binary(frame.encode())).await.is_err() {
+                    break;
+                }
+            }
+            writer_token.cancel();
+            sink.send(Message::Close(None)).await.ok();
+            sink.close().await.ok();
+        });
+
+        let reader = shared.clone();
+        tokio::spawn(async move {
+            let incoming_tx = accept.then_some(incoming_tx);
+            loop {
+                let msg = tokio::select! {
+                    msg = stream.next() => msg,
+                    _ = reader.token.cancelled() => None,
+                };
+                match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        let dispatched = Frame::decode(data)
+                            .and_then(|frame| reader.dispatch(frame, incoming_tx.as_ref()));
+                        let sent = match dispatched {
+                            Ok(Some(reply)) => tokio::select! {
+                                sent = reader.outgoing.send(reply) => sent.is_ok(),
+                                _ = reader.token.cancelled() => false,
+                            },
+                            Ok(None) => true,
+                            Err(_) => false,
+                        };
+                        if !sent {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Others)) => {}
+                    _ => break,
+                }
+            }
+            reader.shutdown();
+        });
+
+        Self {
+            shared,
+            next_id: AtomicU32::new(1),
+            incoming: tokio::sync::Mutex::new(incoming),
+        }
+    }
+
+    /// Opens a new stream to the server side of the session, waiting while
+    /// the queue of the session is full.
+    pub async fn open(&self) -> Result<MuxStream, Error> {
+        if self.is_closed() {
+            return Err(session_closed());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.shared.register(id);
+        self.shared
+            .outgoing
+            .send(Frame::new(FrameKind::Open, id, Bytes::new()))
+            .await
+            .map_err(|_| session_closed())?;
+        Ok(stream)
+    }
+
+    /// Accepts the next stream opened by the client side of the session.
+    ///
+    /// Returns `None` when the session is closed.
+    pub async fn accept(&self) -> Option<MuxStream> {
+        let mut incoming = self.incoming.lock().await;
+        tokio::select! {
+            stream = incoming.recv() => stream,
+            _ = self.shared.token.cancelled() => None,
+        }
+    }
+
+    /// Returns `true` if the underlying WebSocket connection is gone.
+    pub fn is_closed(&self) -> bool {
+        self.shared.token.is_cancelled()
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        self.shared.shutdown();
+    }
+}
+
+/// A logical stream of a mux session.
+///
+/// It implements `Sink` and `Stream` of messages, so it can be proxied like
+/// any other WebSocket stream.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    rx: mpsc::UnboundedReceiver<Incoming>,
+    credit: PollSemaphore,
+    /// The credit acquired for the next `DATA` frame.
+    granted: usize,
+    pending: Bytes,
+    /// The credit consumed by reading, not yet returned to the peer.
+    unacked: usize,
+    /// Queues the frames of the `Sink` side.
+    sink: PollSender<Frame>,
+    /// Queues the frames of the `Stream` side.
+    source: PollSender<Frame>,
+    sent_close: bool,
+}
+
+impl MuxStream {
+    /// Returns the id of the stream inside its session.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+/// Queues a frame once the queue of the session has room for it.
+fn poll_send(
+    sender: &mut PollSender<Frame>, cx: &mut Context<'_>, frame: impl FnOnce() -> Frame,
+) -> Poll<Result<(), Error>> {
+    futures_util::ready!(sender.poll_reserve(cx)).map_err(|_| session_closed())?;
+    sender.send_item(frame()).map_err(|_| session_closed())?;
+    Poll::Ready(Ok(()))
+}
+
+/// A wrapper around mux stream that implements `Stream` trait.
+impl Stream for MuxStream {
+    type Item = Result<Message, Error>;
+
+    /// Polls the next message of the stream, returning its credit to the peer.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // The credit goes back once the queue has room, a full queue does not
+        // hold up reading what was received.
+        if this.unacked > 0 && this.source.poll_reserve(cx).is_ready() {
+            let credit = Bytes::copy_from_slice(&(this.unacked as u32).to_be_bytes());
+            if this
+                .source
+                .send_item(Frame::new(FrameKind::Window, this.id, credit))
+                .is_ok()
+                && let Some(slot) = this.shared.slots.lock().unwrap().get_mut(&this.id)
+            {
+                slot.window += this.unacked;
+            }
+            this.unacked = 0;
+        }
+        match futures_util::ready!(this.rx.poll_recv(cx)) {
+            Some(Incoming::Data(data)) => {
+                this.unacked += data.len();
+                Poll::Ready(Some(Ok(Message::Binary(data))))
+            }
+            Some(Incoming::Reset) => Poll::Ready(Some(Err(stream_reset()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// A wrapper around mux stream that implements `Sink` trait.
+impl Sink<Message> for MuxStream {
+    type Error = Error;
+
+    /// Polls the stream if it is ready to send a message.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    /// Queues a message to be sent on the stream.
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let Message::Binary(data) = item {
+            if self.sent_close {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+            }
+            self.pending = if self.pending.is_empty() {
+                data
+            } else {
+                [self.pending.as_ref(), data.as_ref()].concat().into()
+            };
+        }
+        Ok(())
+    }
+
+    /// Sends the queued data as `DATA` frames, as far as the credit and the
+    /// queue of the session allow.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        while !this.pending.is_empty() {
+            let len = this.pending.len().min(MAX_FRAME_PAYLOAD);
+            if this.granted < len {
+                let missing = (len - this.granted) as u32;
+                match futures_util::ready!(this.credit.poll_acquire_many(cx, missing)) {
+                    Some(permit) => permit.forget(),
+                    None if this.shared.token.is_cancelled() => {
+                        return Poll::Ready(Err(session_closed()));
+                    }
+                    None => return Poll::Ready(Err(stream_reset())),
+                }
+                this.granted = len;
+            }
+            let (id, pending) = (this.id, &mut this.pending);
+            futures_util::ready!(poll_send(&mut this.sink, cx, || {
+                Frame::new(FrameKind::Data, id, pending.split_to(len))
+            }))?;
+            this.granted = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends the queued data, then tells the peer no more data will follow.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures_util::ready!(self.as_mut().poll_flush(cx))?;
+        let this = self.get_mut();
+        if !this.sent_close {
+            let id = this.id;
+            futures_util::ready!(poll_send(&mut this.sink, cx, || {
+                Frame::new(FrameKind::Close, id, Bytes::new())
+            }))?;
+            this.sent_close = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Resets the stream unless both directions were closed, so the peer does not
+/// wait for data of an aborted stream.
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let slot = self.shared.slots.lock().unwrap().remove(&self.id);
+        let finished = self.sent_close && slot.as_ref().is_some_and(|slot| slot.tx.is_none());
+        // Without a slot the stream was reset already.
+        if slot.is_some() && !finished {
+            self.shared.reset(self.id);
+        }
+    }
+}
+
+/// Opens streams over a shared mux session, dialing the session on demand.
+///
+/// The session's WebSocket is connected like any other connection of the
+/// tunnel configuration, with its handshake data, upstream proxy, TLS
+/// options, encoding, compression, encryption and keepalive pings.
+///
+/// If the server does not speak the mux protocol, [`MuxClient::open`]
+/// returns `None` from then on, and callers should fall back to one plain
+/// WebSocket per connection.
+#[cfg(feature = "client")]
+pub struct MuxClient {
+    config: Arc<crate::tunnel::TunnelConfig>,
+    counter: Option<crate::stats::TrafficCounter>,
+    session: Mutex<Option<Arc<MuxSession>>>,
+    unsupported: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(feature = "client")]
+impl MuxClient {
+    /// Creates a new `MuxClient` for the remote of the configuration.
+    pub fn new(config: Arc<crate::tunnel::TunnelConfig>) -> Self {
+        Self {
+            config,
+            counter: None,
+            session: Mutex::new(None),
+            unsupported: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Counts the compression of the session's WebSocket connection into the
+    /// counter.
+    pub fn with_counter(mut self, counter: crate::stats::TrafficCounter) -> Self {
+        self.counter = Some(counter);
+        self
+    }
+
+    /// Opens a new stream, reconnecting the session if it is closed.
+    ///
+    /// Returns `None` if the server doesn't support the mux protocol.
+    pub async fn open(&self) -> Result<Option<MuxStream>, Error> {
+        if self.unsupported.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        if let Some(session) = self.live_session() {
+            return session.open().await.map(Some);
+        }
+
+        // The session is dialed without holding the lock, so a slow handshake
+        // doesn't hold up other callers.
+        let ws = self
+            .config
+            .connect_with_protocol(MUX_PROTOCOL, self.counter.as_ref())
+            .await?;
+        let Some(ws) = ws else {
+            // The server picked one of the configured subprotocols instead.
+            self.unsupported.store(true, Ordering::Relaxed);
+            return Ok(None);
+        };
+        let session = {
+            let mut session = self.session.lock().unwrap();
+            match session.as_ref() {
+                // Another caller dialed a session meanwhile, the new
+                // connection is dropped.
+                Some(live) if !live.is_closed() => live.clone(),
+                _ => session.insert(Arc::new(MuxSession::client(ws))).clone(),
+            }
+        };
+        session.open().await.map(Some)
+    }
+
+    /// Returns the current session, if it is still alive.
+    fn live_session(&self) -> Option<Arc<MuxSession>> {
+        let session = self.session.lock().unwrap();
+        session
+            .as_ref()
+            .filter(|session| !session.is_closed())
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::proxy::tests::message_pipe;
+
+    fn binary(msg: Option<Result<Message, Error>>) -> Bytes {
+        match msg {
+            Some(Ok(Message::Binary(data))) => data,
+            _ => panic!("expected a binary message"),
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = Frame::new(FrameKind::Data, 7, Bytes::from_static(b"hello"));
+        let frame = Frame::decode(frame.encode()).unwrap();
+        assert_eq!(frame.kind, FrameKind::Data);
+        assert_eq!(frame.id, 7);
+        assert_eq!(frame.payload, Bytes::from_static(b"hello"));
+    }
+
+    #[test]
+    fn truncated_and_unknown_frames_are_rejected() {
+        assert!(Frame::decode(Bytes::from_static(&[1, 0, 0])).is_err());
+        assert!(Frame::decode(Bytes::from_static(&[9, 0, 0, 0, 1])).is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_round_trip() {
+        let (a, b) = message_pipe();
+        let client = MuxSession::client(a);
+        let server = MuxSession::server(b);
+
+        let mut outbound = client.open().await.unwrap();
+        outbound
+            .send(Message::Binary(Bytes::from_static(b"ping")))
+            .await
+            .unwrap();
+        let mut inbound = server.accept().await.unwrap();
+        assert_eq!(binary(inbound.next().await), Bytes::from_static(b"ping"));
+
+        inbound
+            .send(Message::Binary(Bytes::from_static(b"pong")))
+            .await
+            .unwrap();
+        inbound.close().await.unwrap();
+        assert_eq!(binary(outbound.next().await), Bytes::from_static(b"pong"));
+        assert!(outbound.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_dropped_before_they_are_closed_are_reset() {
+        let (a, b) = message_pipe();
+        let client = MuxSession::client(a);
+        let server = MuxSession::server(b);
+
+        let mut outbound = client.open().await.unwrap();
+        outbound
+            .send(Message::Binary(Bytes::from_static(b"ping")))
+            .await
+            .unwrap();
+        let mut inbound = server.accept().await.unwrap();
+        assert_eq!(binary(inbound.next().await), Bytes::from_static(b"ping"));
+        drop(outbound);
+        assert!(matches!(inbound.next().await, Some(Err(_))));
+
+        let mut outbound = client.open().await.unwrap();
+        let mut inbound = server.accept().await.unwrap();
+        outbound.close().await.unwrap();
+        inbound.close().await.unwrap();
+        assert!(outbound.next().await.is_none());
+        drop(outbound);
+        assert!(inbound.next().await.is_none());
+    }
+
+    /// A WebSocket that never takes a message.
+    struct Stalled;
+
+    impl Stream for Stalled {
+        type Item = Result<Message, Error>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Sink<Message> for Stalled {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_queue_holds_up_the_streams() {
+        let client = MuxSession::client(Stalled);
+        let wait = Duration::from_secs(1);
+
+        // The writer holds one frame, the queue the others.
+        let mut streams = Vec::new();
+        while let Ok(stream) = tokio::time::timeout(wait, client.open()).await {
+            streams.push(stream.unwrap());
+        }
+        assert_eq!(streams.len(), OUTGOING_FRAMES + 1);
+
+        let data = Message::Binary(Bytes::from_static(b"data"));
+        assert!(
+            tokio::time::timeout(wait, streams[0].send(data))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_past_the_cap_are_reset() {
+        let (a, b) = message_pipe();
+        let client = MuxSession::client(a);
+        let _server = MuxSession::server(b);
+
+        let mut streams = Vec::new();
+        for _ in 0..MAX_STREAMS {
+            streams.push(client.open().await.unwrap());
+        }
+        let mut refused = client.open().await.unwrap();
+        assert!(matches!(refused.next().await, Some(Err(_))));
+        let data = Message::Binary(Bytes::from_static(b"data"));
+        assert!(refused.send(data).await.is_err());
+        drop(streams);
+    }
+
+    #[tokio::test]
+    async fn data_past_the_credit_closes_the_session() {
+        let (mut a, b) = message_pipe();
+        let server = MuxSession::server(b);
+
+        let open = Frame::new(FrameKind::Open, 1, Bytes::new());
+        a.send(Message::Binary(open.encode())).await.unwrap();
+        let payload = Bytes::from(vec![0; INITIAL_WINDOW as usize + 1]);
+        let data = Frame::new(FrameKind::Data, 1, payload);
+        a.send(Message::Binary(data.encode())).await.unwrap();
+
+        while server.accept().await.is_some() {}
+        assert!(server.is_closed());
+    }
+}
@@ -0,0 +1,299 @@
+//! Observers of proxied traffic.
+//!
+//! An [`Observer`] is told when a session opens and closes, and sees every
+//! message passed on in either direction, e.g. to log hexdumps, raise alerts
+//! on patterns or save transcripts. Attach one with
+//! [`proxy_stream_observed`](crate::proxy::proxy_stream_observed) or to a
+//! whole [`Tunnel`](crate::tunnel::Tunnel). Sessions without an observer run
+//! the same code as before, with nothing to check per message.
+//!
+//! Directions follow [`stats`](crate::stats): `inbound` is traffic read from
+//! the WebSocket, `outbound` is traffic written to it.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use crate::{proxy::Error, stats::TrafficStats};
+
+/// The direction of a message in a proxied session.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize))]
+#[cfg_attr(feature = "binary", serde(rename_all = "snake_case"))]
+pub enum Direction {
+    /// Read from the WebSocket and written to the stream.
+    Inbound,
+    /// Read from the stream and written to the WebSocket.
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Inbound => f.write_str("inbound"),
+            Direction::Outbound => f.write_str("outbound"),
+        }
+    }
+}
+
+/// A proxied session, as seen by observers.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// A number unique to the session within the process.
+    pub id: u64,
+    /// What the session serves, e.g. the address of the local peer.
+    pub label: String,
+    /// When the session was opened.
+    pub started: SystemTime,
+}
+
+impl SessionInfo {
+    /// Creates the info of a new session with the next id.
+    pub fn new(label: impl Into<String>) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            label: label.into(),
+            started: SystemTime::now(),
+        }
+    }
+}
+
+/// Callbacks into proxied sessions.
+///
+/// The callbacks run on the task of the session, in line with its traffic,
+/// so they should be quick and hand slow work off to another task. All of
+/// them do nothing by default.
+pub trait Observer: Send + Sync + 'static {
+    /// Called when a session opens, before any message is passed on.
+    fn session_opened(&self, _session: &SessionInfo) {}
+
+    /// Called with the data of every message before it is passed on.
+    fn message(&self, _session: &SessionInfo, _direction: Direction, _data: &[u8]) {}
+
+    /// Called when a session is over, with its statistics or the error that
+    /// ended it.
+    fn session_closed(&self, _session: &SessionInfo, _result: Result<&TrafficStats, &Error>) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// An observer that writes a hexdump of every message, in the format of
+/// `hexdump -C`.
+pub struct HexDump {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl HexDump {
+    /// Writes the hexdumps to any writer.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Writes the hexdumps to stderr.
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+
+    fn write(&self, text: &str) {
+        let mut out = self.out.lock().unwrap();
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.flush())
+            .ok();
+    }
+}
+
+impl Observer for HexDump {
+    fn session_opened(&self, session: &SessionInfo) {
+        self.write(&format!("[#{} {}] opened\n", session.id, session.label));
+    }
+
+    fn message(&self, session: &SessionInfo, direction: Direction, data: &[u8]) {
+        let mut text = format!(
+            "[#{} {}] {direction} {} bytes\n",
+            session.id,
+            session.label,
+            data.len()
+        );
+        for (i, line) in data.chunks(16).enumerate() {
+            write!(text, "{:08x} ", i * 16).ok();
+            for j in 0..16 {
+                if j == 8 {
+                    text.push(' ');
+                }
+                match line.get(j) {
+                    Some(byte) => write!(text, " {byte:02x}").ok(),
+                    None => write!(text, "   ").ok(),
+                };
+            }
+            text.push_str("  |");
+            text.extend(line.iter().map(|&b| match b {
+                0x20..=0x7E => b as char,
+                _ => '.',
+            }));
+            text.push_str("|\n");
+        }
+        self.write(&text);
+    }
+
+    fn session_closed(&self, session: &SessionInfo, result: Result<&TrafficStats, &Error>) {
+        let text = match result {
+            Ok(stats) => format!(
+                "[#{} {}] closed: {} bytes in, {} bytes out in {:?}\n",
+                session.id,
+                session.label,
+                stats.inbound_bytes,
+                stats.outbound_bytes,
+                stats.duration
+            ),
+            Err(e) => format!("[#{} {}] failed: {e}\n", session.id, session.label),
+        };
+        self.write(&text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::{bytes::Bytes, sync::CancellationToken};
+
+    use super::*;
+    use crate::{
+        proxy::{Message, proxy_stream_observed, tests::message_pipe},
+        stats::TrafficCounter,
+    };
+
+    /// Records the callbacks of the sessions it observes.
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Observer for Recorder {
+        fn session_opened(&self, session: &SessionInfo) {
+            let event = format!("opened {}", session.label);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn message(&self, _session: &SessionInfo, direction: Direction, data: &[u8]) {
+            let event = format!("{direction} {}", String::from_utf8_lossy(data));
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn session_closed(&self, _session: &SessionInfo, result: Result<&TrafficStats, &Error>) {
+            let event = match result {
+                Ok(stats) => format!("closed {} {}", stats.inbound_bytes, stats.outbound_bytes),
+                Err(e) => format!("failed {e}"),
+            };
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    /// A writer that can be read back after it was handed to a `HexDump`.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn message(data: &'static [u8]) -> Message {
+        Message::Binary(Bytes::from_static(data))
+    }
+
+    #[test]
+    fn sessions_get_increasing_ids() {
+        let (first, second) = (SessionInfo::new("a"), SessionInfo::new("b"));
+        assert!(second.id > first.id);
+        assert_eq!(second.label, "b");
+    }
+
+    #[test]
+    fn hexdump_matches_hexdump_c() {
+        let out = Shared::default();
+        let dump = HexDump::new(out.clone());
+        let session = SessionInfo {
+            id: 7,
+            label: "127.0.0.1:4000".to_string(),
+            started: SystemTime::now(),
+        };
+
+        dump.session_opened(&session);
+        dump.message(
+            &session,
+            Direction::Inbound,
+            b"hello, world! \x000123456\x7f\xff",
+        );
+        dump.message(&session, Direction::Outbound, b"");
+        dump.session_closed(&session, Err(&Error::from(io::Error::other("gone"))));
+        assert_eq!(
+            out.text(),
+            "[#7 127.0.0.1:4000] opened\n\
+             [#7 127.0.0.1:4000] inbound 24 bytes\n\
+             00000000  68 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 20 00 30  |hello, world! .0|\n\
+             00000010  31 32 33 34 35 36 7f ff                           |123456..|\n\
+             [#7 127.0.0.1:4000] outbound 0 bytes\n\
+             [#7 127.0.0.1:4000] failed: IO error: gone\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn observers_see_every_message_of_a_session() {
+        let (mut remote, ws) = message_pipe();
+        let (mut local, stream) = message_pipe();
+        let recorder = Arc::new(Recorder::default());
+        let observer = recorder.clone();
+        let proxy = tokio::spawn(async move {
+            let counter = TrafficCounter::new();
+            let observer = Some(observer.as_ref() as &dyn Observer);
+            proxy_stream_observed(
+                ws,
+                stream,
+                CancellationToken::new(),
+                &counter,
+                observer,
+                "peer",
+            )
+            .await
+        });
+
+        remote.send(message(b"in")).await.unwrap();
+        assert!(matches!(local.next().await, Some(Ok(Message::Binary(_)))));
+        local.send(message(b"out")).await.unwrap();
+        assert!(matches!(remote.next().await, Some(Ok(Message::Binary(_)))));
+        remote.send(Message::Close(None)).await.unwrap();
+        proxy.await.unwrap().unwrap();
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            ["opened peer", "inbound in", "outbound out", "closed 2 3"]
+        );
+    }
+}
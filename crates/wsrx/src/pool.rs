@@ -0,0 +1,479 @@
+//! A pool of pre-warmed WebSocket connections.
+//!
+//! Opening a WebSocket takes a DNS lookup, a TCP handshake, maybe a TLS
+//! handshake and the HTTP upgrade before the first byte is forwarded, which
+//! adds up for far-away remotes. A `WsPool` keeps a few connections
+//! handshaken ahead of time, so accepted connections can start right away.
+//!
+//! Pooled connections are opened with the [`POOL_HEADER`], which asks the
+//! server to wait for the [`ATTACH_MARKER`] before it connects the backend,
+//! so idle connections hold no backend connection and receive no data. The
+//! marker is sent as a pong, which servers that don't know it ignore. Servers
+//! that don't echo the header connect the backend right away, the pool is
+//! disabled for them.
+//!
+//! Idle connections are swept regularly: pings from the server are answered,
+//! a ping is sent to keep proxies in between from closing them, and closed
+//! ones are dropped.
+
+#[cfg(feature = "client")]
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::Duration;
+
+#[cfg(feature = "client")]
+use futures_util::{FutureExt, SinkExt, StreamExt, future::join_all};
+#[cfg(feature = "client")]
+use tokio::{net::TcpStream, sync::Notify, time::Instant};
+#[cfg(feature = "client")]
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{Message as TgMessage, handshake::client::Request, handshake::client::Response},
+};
+#[cfg(feature = "client")]
+use tokio_util::{bytes::Bytes, sync::CancellationToken};
+#[cfg(feature = "client")]
+use tracing::{debug, warn};
+
+#[cfg(feature = "client")]
+use crate::{
+    Error, WrappedWsStream,
+    stats::TrafficCounter,
+    tunnel::{Negotiated, TunnelConfig},
+};
+
+/// The handshake header a client sends to open a pooled connection, and the
+/// server echoes if it waits for the [`ATTACH_MARKER`].
+pub const POOL_HEADER: &str = "wsrx-pool";
+
+/// The value of [`POOL_HEADER`], the version of the wire format.
+pub const POOL_VERSION: &str = "1";
+
+/// The payload of the pong a client sends when a pooled connection is handed
+/// out, before any data.
+pub const ATTACH_MARKER: &[u8] = b"wsrx-attach";
+
+/// How long a server waits for the [`ATTACH_MARKER`] before it closes a
+/// pooled connection, idle connections are replaced by clients before.
+pub const ATTACH_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long idle connections are kept if not configured.
+#[cfg(feature = "client")]
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often idle connections are swept.
+#[cfg(feature = "client")]
+const SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The longest wait between failed refills.
+#[cfg(feature = "client")]
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A handshaken WebSocket connection, not yet wrapped for proxying.
+#[cfg(feature = "client")]
+pub(crate) type RawWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Keeps idle WebSocket connections to the remote of a tunnel ready.
+#[cfg(feature = "client")]
+#[derive(Debug)]
+pub(crate) struct WsPool {
+    config: Arc<TunnelConfig>,
+    size: usize,
+    idle_timeout: Duration,
+    /// Idle connections with the time they were opened and what the remote
+    /// agreed to, oldest first.
+    idle: Mutex<Vec<(Instant, RawWebSocket, Negotiated)>>,
+    /// Wakes the refill task when a connection is taken.
+    taken: Notify,
+    /// The remote doesn't support pooled connections, so every connection
+    /// is connected when needed.
+    unsupported: AtomicBool,
+    counter: TrafficCounter,
+}
+
+#[cfg(feature = "client")]
+impl WsPool {
+    /// Creates the pool of the tunnel configuration, `None` if disabled.
+    ///
+    /// Idle connections are replaced before the server stops waiting for
+    /// them to be attached, whatever the configured idle timeout.
+    pub fn new(config: Arc<TunnelConfig>, counter: TrafficCounter) -> Option<Arc<Self>> {
+        let size = config.pool_size.filter(|size| *size > 0)?;
+        let idle_timeout = config
+            .pool_idle_timeout
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT)
+            .min(ATTACH_TIMEOUT - SWEEP_INTERVAL);
+        Some(Arc::new(Self {
+            config,
+            size,
+            idle_timeout,
+            idle: Mutex::new(Vec::with_capacity(size)),
+            taken: Notify::new(),
+            unsupported: AtomicBool::new(false),
+            counter,
+        }))
+    }
+
+    /// Hands out an idle connection, or connects a new one if none is ready.
+    pub async fn connect(&self) -> Result<WrappedWsStream, Error> {
+        if self.unsupported.load(Ordering::Relaxed) {
+            return self.config.connect_with_counter(Some(&self.counter)).await;
+        }
+        loop {
+            let ws = {
+                let mut idle = self.idle.lock().unwrap();
+                let expired = self.expired(&idle);
+                idle.drain(..expired);
+                idle.pop()
+            };
+            self.taken.notify_one();
+            let Some((_, mut ws, negotiated)) = ws else {
+                self.counter.add_pool_miss();
+                return self.config.connect_with_counter(Some(&self.counter)).await;
+            };
+            match ws
+                .send(TgMessage::Pong(Bytes::from_static(ATTACH_MARKER)))
+                .await
+            {
+                Ok(()) => {
+                    self.counter.add_pool_hit();
+                    return Ok(self.config.wrap(ws, negotiated, Some(&self.counter)));
+                }
+                Err(e) => debug!("Dropped a pooled connection that failed to attach: {e}"),
+            }
+        }
+    }
+
+    /// Keeps the pool filled until the token is cancelled, or until the
+    /// remote turns out not to support pooled connections.
+    ///
+    /// Failed connections are retried with an exponential backoff, new
+    /// connections are opened as soon as one is taken.
+    pub async fn refill(self: Arc<Self>, token: CancellationToken) {
+        let mut retry_delay = Duration::from_secs(1);
+        loop {
+            let (missing, next_expiry) = {
+                let mut idle = self.idle.lock().unwrap();
+                let expired = self.expired(&idle);
+                idle.drain(..expired);
+                idle.retain_mut(|(_, ws, _)| sweep(ws));
+                let next_expiry = idle.first().map(|(opened, ..)| *opened + self.idle_timeout);
+                (self.size.saturating_sub(idle.len()), next_expiry)
+            };
+
+            if missing == 0 {
+                let next_sweep = Instant::now() + SWEEP_INTERVAL;
+                let wake = next_expiry.map_or(next_sweep, |expiry| expiry.min(next_sweep));
+                tokio::select! {
+                    _ = self.taken.notified() => {}
+                    _ = tokio::time::sleep_until(wake) => {}
+                    _ = token.cancelled() => break,
+                }
+                continue;
+            }
+
+            let connects = join_all((0..missing).map(|_| self.config.open_pooled_websocket()));
+            let results = tokio::select! {
+                results = connects => results,
+                _ = token.cancelled() => break,
+            };
+            let mut failed = None;
+            for res in results {
+                match res {
+                    Ok(Some((ws, negotiated))) => {
+                        self.idle
+                            .lock()
+                            .unwrap()
+                            .push((Instant::now(), ws, negotiated))
+                    }
+                    Ok(None) => {
+                        warn!(
+                            "{} does not support pooled connections, disabling the pool",
+                            self.config.remote
+                        );
+                        self.unsupported.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(e) => failed = Some(e),
+                }
+            }
+            if self.unsupported.load(Ordering::Relaxed) {
+                break;
+            }
+            match failed {
+                Some(e) => {
+                    warn!(
+                        "Failed to pre-warm connection to {}: {e}, retrying in {retry_delay:?}",
+                        self.config.remote
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry_delay) => {}
+                        _ = token.cancelled() => break,
+                    }
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
+                None => {
+                    debug!("Pre-warmed {missing} connections to {}", self.config.remote);
+                    retry_delay = Duration::from_secs(1);
+                }
+            }
+        }
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Returns how many of the oldest idle connections have expired.
+    fn expired(&self, idle: &[(Instant, RawWebSocket, Negotiated)]) -> usize {
+        let now = Instant::now();
+        idle.partition_point(|(opened, ..)| *opened + self.idle_timeout <= now)
+    }
+}
+
+/// Reads what an idle connection received so far, which answers the pings
+/// of the server, and pings the server.
+///
+/// Returns whether the connection is still open.
+#[cfg(feature = "client")]
+fn sweep(ws: &mut RawWebSocket) -> bool {
+    loop {
+        match ws.next().now_or_never() {
+            None => break,
+            Some(Some(Ok(TgMessage::Close(_)))) | Some(Some(Err(_))) | Some(None) => {
+                return false;
+            }
+            Some(Some(Ok(_))) => {}
+        }
+    }
+    // A ping that doesn't fit into the socket right away is sent with the
+    // next one.
+    !matches!(
+        ws.send(TgMessage::Ping(Bytes::new())).now_or_never(),
+        Some(Err(_))
+    )
+}
+
+/// Asks the server to wait for the [`ATTACH_MARKER`] before it connects the
+/// backend.
+#[cfg(feature = "client")]
+pub(crate) fn offer(request: &mut Request) {
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    request
+        .headers_mut()
+        .insert(POOL_HEADER, HeaderValue::from_static(POOL_VERSION));
+}
+
+/// Returns whether the server waits for the [`ATTACH_MARKER`].
+#[cfg(feature = "client")]
+pub(crate) fn accepted(response: &Response) -> bool {
+    response
+        .headers()
+        .get(POOL_HEADER)
+        .is_some_and(|version| version == POOL_VERSION)
+}
+
+/// Waits until the client of a pooled connection sends the
+/// [`ATTACH_MARKER`], answering its pings meanwhile.
+///
+/// Returns `false` if the connection is closed, fails, or carries data
+/// before it is attached. A connection that isn't attached within
+/// [`ATTACH_TIMEOUT`] is closed.
+#[cfg(feature = "server")]
+pub async fn attached(ws: &mut axum::extract::ws::WebSocket) -> bool {
+    attached_within(ws, ATTACH_TIMEOUT).await
+}
+
+#[cfg(feature = "server")]
+async fn attached_within(ws: &mut axum::extract::ws::WebSocket, timeout: Duration) -> bool {
+    use axum::extract::ws::{CloseFrame, Message};
+
+    let attach = async {
+        loop {
+            match ws.recv().await {
+                Some(Ok(Message::Pong(payload))) if payload == ATTACH_MARKER => return true,
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(_) | Err(_)) | None => return false,
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, attach).await {
+        Ok(attached) => attached,
+        Err(_) => {
+            let close = CloseFrame {
+                code: axum::extract::ws::close_code::AWAY,
+                reason: "not attached in time".into(),
+            };
+            ws.send(Message::Close(Some(close))).await.ok();
+            false
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests {
+    use axum::{Router, extract::ws::WebSocketUpgrade, routing::get};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    /// Connects both ends of a raw WebSocket over a loopback TCP connection.
+    async fn raw_pair() -> (RawWebSocket, RawWebSocket) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::join!(
+            async {
+                let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+            },
+        )
+    }
+
+    /// Sends the messages to an axum server, returning whether it saw the
+    /// connection attached within the timeout and what the client received
+    /// meanwhile.
+    async fn attach(messages: Vec<TgMessage>, timeout: Duration) -> (bool, Vec<TgMessage>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |mut socket| async move {
+                    tx.send(attached_within(&mut socket, timeout).await).ok();
+                })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) =
+            tokio_tungstenite::client_async(format!("ws://{addr}/"), MaybeTlsStream::Plain(tcp))
+                .await
+                .unwrap();
+        for message in messages {
+            ws.send(message).await.unwrap();
+        }
+        let attached = rx.recv().await.unwrap();
+        let mut received = Vec::new();
+        while let Ok(Some(Ok(message))) =
+            tokio::time::timeout(Duration::from_millis(50), ws.next()).await
+        {
+            received.push(message);
+        }
+        (attached, received)
+    }
+
+    #[tokio::test]
+    async fn server_waits_for_the_attach_marker() {
+        let (attached, received) = attach(
+            vec![
+                TgMessage::Ping(Bytes::from_static(b"idle")),
+                TgMessage::Pong(Bytes::from_static(b"other")),
+                TgMessage::Pong(Bytes::from_static(ATTACH_MARKER)),
+            ],
+            ATTACH_TIMEOUT,
+        )
+        .await;
+        assert!(attached);
+        assert!(
+            received
+                .iter()
+                .any(|message| *message == TgMessage::Pong(Bytes::from_static(b"idle")))
+        );
+    }
+
+    #[tokio::test]
+    async fn data_before_the_attach_marker_is_refused() {
+        let (attached, _) = attach(
+            vec![TgMessage::Binary(Bytes::from_static(b"early"))],
+            ATTACH_TIMEOUT,
+        )
+        .await;
+        assert!(!attached);
+    }
+
+    #[tokio::test]
+    async fn connections_not_attached_in_time_are_closed() {
+        let (attached, received) = attach(Vec::new(), Duration::from_millis(100)).await;
+        assert!(!attached);
+        assert!(matches!(received.last(), Some(TgMessage::Close(Some(_)))));
+    }
+
+    #[tokio::test]
+    async fn sweep_answers_pings_and_pings_back() {
+        let (mut client, mut server) = raw_pair().await;
+        server
+            .send(TgMessage::Ping(Bytes::from_static(b"server")))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(sweep(&mut client));
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            TgMessage::Pong(Bytes::from_static(b"server"))
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            TgMessage::Ping(Bytes::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn sweep_drops_closed_connections() {
+        let (mut client, mut server) = raw_pair().await;
+        assert!(sweep(&mut client));
+        server.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sweep(&mut client));
+    }
+
+    #[tokio::test]
+    async fn pool_is_disabled_if_the_remote_ignores_the_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = TunnelConfig {
+            remote: format!("ws://{}", listener.local_addr().unwrap()),
+            pool_size: Some(1),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio::spawn(tokio_tungstenite::accept_async(tcp));
+            }
+        });
+        let pool = WsPool::new(Arc::new(config), TrafficCounter::new()).unwrap();
+        // The refill task ends by itself.
+        pool.clone().refill(CancellationToken::new()).await;
+        assert!(pool.unsupported.load(Ordering::Relaxed));
+        pool.connect().await.unwrap();
+        let traffic = pool.counter.snapshot();
+        assert_eq!((traffic.pool_hits, traffic.pool_misses), (0, 0));
+    }
+
+    #[test]
+    fn header_is_offered_and_accepted() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        offer(&mut request);
+        let mut response = Response::new(None);
+        assert!(!accepted(&response));
+        response.headers_mut().insert(
+            POOL_HEADER,
+            request.headers().get(POOL_HEADER).unwrap().clone(),
+        );
+        assert!(accepted(&response));
+    }
+}
@@ -1,26 +1,49 @@
 //! The main proxy module for WebSocket Reflector X.
 
 use std::{
+    fmt, io,
     pin::Pin,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 #[cfg(feature = "server")]
-use axum::extract::ws::{Message as AxMessage, WebSocket};
-use futures_util::{StreamExt, sink::Sink, stream::Stream};
+use axum::extract::{
+    WebSocketUpgrade,
+    ws::{CloseFrame as AxCloseFrame, Message as AxMessage, WebSocket},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures_util::{FutureExt, SinkExt, StreamExt, sink::Sink, stream::Stream};
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UdpSocket},
+    time::{Interval, MissedTickBehavior},
+};
 #[cfg(feature = "client")]
 use tokio_tungstenite::{
     MaybeTlsStream, WebSocketStream,
-    tungstenite::{Error as TgError, Message as TgMessage},
+    tungstenite::{
+        Error as TgError, Message as TgMessage,
+        protocol::{CloseFrame as TgCloseFrame, WebSocketConfig},
+    },
 };
 use tokio_util::{
-    bytes::{BufMut, Bytes, BytesMut},
+    bytes::{Bytes, BytesMut},
     codec::{Decoder, Encoder, Framed},
     sync::CancellationToken,
 };
 
+use crate::{
+    datagram::DatagramStream,
+    deflate::Deflate,
+    encrypt::Cipher,
+    observe::{Direction, Observer, SessionInfo},
+    stats::{ClosedBy, TrafficCounter, TrafficStats},
+};
+
 /// An error type for WebSocket Reflector X.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -37,23 +60,313 @@ pub enum Error {
     Axum(#[from] axum::Error),
 }
 
+/// The close code of a session that finished normally.
+const CLOSE_NORMAL: u16 = 1000;
+
+/// The close code of a session that was cancelled.
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// How long closing a finished session may take before it is dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The start of the pong payload that tells the peer no more data follows.
+const EOF_MARKER: &[u8] = b"wsrx-eof";
+
 /// A enum for different type of WebSocket message.
 ///
-/// Just Binary message will be tunneled, other type of websocket message will
-/// just be discarded.
+/// Binary messages will be tunneled and close frames are passed on to the
+/// other side, other type of websocket message will just be discarded.
+///
+/// Payloads are reference counted `Bytes`, so they are handed from the socket
+/// read buffer to the WebSocket frame and back without being copied.
 pub enum Message {
-    Binary(Vec<u8>),
+    Binary(Bytes),
+    /// A close frame, with its close code and reason if it carried one.
+    Close(Option<CloseFrame>),
     Others,
 }
 
+/// The close code and reason of a WebSocket close frame.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize))]
+pub struct CloseFrame {
+    /// The close code, see RFC 6455 section 7.4.
+    pub code: u16,
+    /// The reason of the close, may be empty.
+    pub reason: String,
+}
+
+impl CloseFrame {
+    /// Creates a new `CloseFrame` with the given code and reason.
+    pub fn new(code: u16, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<TgCloseFrame> for CloseFrame {
+    fn from(frame: TgCloseFrame) -> Self {
+        Self::new(frame.code.into(), frame.reason.as_str())
+    }
+}
+
+#[cfg(feature = "client")]
+impl From<CloseFrame> for TgCloseFrame {
+    fn from(frame: CloseFrame) -> Self {
+        TgCloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason.into(),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<AxCloseFrame> for CloseFrame {
+    fn from(frame: AxCloseFrame) -> Self {
+        Self::new(frame.code, frame.reason.as_str())
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<CloseFrame> for AxCloseFrame {
+    fn from(frame: CloseFrame) -> Self {
+        AxCloseFrame {
+            code: frame.code,
+            reason: frame.reason.into(),
+        }
+    }
+}
+
+/// How data is carried in WebSocket frames.
+///
+/// Some proxies and gateways only pass text frames, so data can also be sent
+/// as text, both sides of a tunnel must use the same encoding. Binary frames
+/// are accepted with any encoding.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "binary", serde(rename_all = "lowercase"))]
+pub enum FrameEncoding {
+    /// Data is sent as binary frames, text frames are read as raw bytes.
+    #[default]
+    Binary,
+    /// Data is sent as text frames, it must be valid UTF-8.
+    Text,
+    /// Data is sent as base64 in text frames, and text frames are decoded
+    /// from base64.
+    Base64,
+}
+
+impl FrameEncoding {
+    /// Encodes data into a frame.
+    ///
+    /// `tail` keeps an incomplete UTF-8 sequence at the end of the data for
+    /// the next call, so text may be split anywhere. Returns `None` if there
+    /// is nothing to send yet.
+    fn encode(self, data: Bytes, tail: &mut Bytes) -> Result<Option<Frame>, Error> {
+        match self {
+            FrameEncoding::Binary => Ok(Some(Frame::Binary(data))),
+            FrameEncoding::Base64 => Ok(Some(Frame::Text(BASE64.encode(data).into()))),
+            FrameEncoding::Text => {
+                let mut data = if tail.is_empty() {
+                    data
+                } else {
+                    let mut buf = BytesMut::with_capacity(tail.len() + data.len());
+                    buf.extend_from_slice(tail);
+                    buf.extend_from_slice(&data);
+                    buf.freeze()
+                };
+                if let Err(e) = std::str::from_utf8(&data) {
+                    if e.error_len().is_some() {
+                        return Err(invalid_data("data is not valid UTF-8, use base64 instead"));
+                    }
+                    *tail = data.split_off(e.valid_up_to());
+                } else {
+                    tail.clear();
+                }
+                if data.is_empty() && !tail.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Frame::Text(data)))
+            }
+        }
+    }
+
+    /// Decodes the data of a text frame.
+    #[cfg(any(feature = "client", feature = "server"))]
+    fn decode_text(self, text: Bytes) -> Result<Bytes, Error> {
+        match self {
+            FrameEncoding::Base64 => BASE64
+                .decode(text)
+                .map(Bytes::from)
+                .map_err(|_| invalid_data("text frame is not valid base64")),
+            _ => Ok(text),
+        }
+    }
+}
+
+impl FromStr for FrameEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "binary" => Ok(FrameEncoding::Binary),
+            "text" => Ok(FrameEncoding::Text),
+            "base64" => Ok(FrameEncoding::Base64),
+            _ => Err(format!(
+                "unknown frame encoding `{s}`, expected `binary`, `text` or `base64`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FrameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameEncoding::Binary => "binary",
+            FrameEncoding::Text => "text",
+            FrameEncoding::Base64 => "base64",
+        })
+    }
+}
+
+/// Size limits of WebSocket messages and of the data buffered by a
+/// connection.
+///
+/// Data read from a socket is split into messages of at most
+/// `max_frame_size` bytes, and writes wait once `write_buffer_size` bytes are
+/// buffered, so a fast sender toward a slow reader is pushed back instead of
+/// growing the buffers. Datagrams are never split.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "binary", serde(default))]
+pub struct FrameLimits {
+    /// The largest chunk of data carried by a single message.
+    pub max_frame_size: usize,
+    /// The largest message accepted from the WebSocket, a bigger one fails
+    /// the connection.
+    pub max_message_size: usize,
+    /// How many bytes a connection buffers for writing before the sender has
+    /// to wait.
+    pub write_buffer_size: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 64 * 1024,
+            max_message_size: 16 * 1024 * 1024,
+            write_buffer_size: 128 * 1024,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Creates a codec that splits data into messages of at most
+    /// `max_frame_size` bytes.
+    pub fn codec(&self) -> MessageCodec {
+        MessageCodec::with_max_frame_size(self.max_frame_size)
+    }
+
+    /// Frames a byte stream into messages, reading at most `max_frame_size`
+    /// bytes at once and buffering at most `write_buffer_size` bytes before
+    /// writes wait.
+    pub fn framed<T: AsyncRead + AsyncWrite>(&self, io: T) -> Framed<T, MessageCodec> {
+        let mut framed = Framed::with_capacity(io, self.codec(), self.max_frame_size);
+        framed.set_backpressure_boundary(self.write_buffer_size);
+        framed
+    }
+
+    /// Returns the tungstenite configuration that enforces these limits.
+    #[cfg(feature = "client")]
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(Some(self.max_message_size))
+            .max_frame_size(Some(self.max_message_size))
+            .write_buffer_size(self.write_buffer_size)
+            .max_write_buffer_size(self.max_write_buffer_size())
+    }
+
+    /// Applies these limits to an axum WebSocket upgrade.
+    #[cfg(feature = "server")]
+    pub fn upgrade<F>(&self, ws: WebSocketUpgrade<F>) -> WebSocketUpgrade<F> {
+        ws.max_message_size(self.max_message_size)
+            .max_frame_size(self.max_message_size)
+            .write_buffer_size(self.write_buffer_size)
+            .max_write_buffer_size(self.max_write_buffer_size())
+    }
+
+    /// The hard cap of the WebSocket write buffer, which leaves room for one
+    /// more message once the buffer is full.
+    #[cfg(any(feature = "client", feature = "server"))]
+    fn max_write_buffer_size(&self) -> usize {
+        self.write_buffer_size
+            .saturating_add(self.max_message_size.max(self.max_frame_size))
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// A frame sent by `WrappedWsStream`, data frames are encoded by a
+/// `FrameEncoding`.
+#[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+enum Frame {
+    Binary(Bytes),
+    /// The data of a text frame, always valid UTF-8.
+    Text(Bytes),
+    /// A keepalive ping.
+    Ping,
+    /// The end of data, sent as a pong that starts with [`EOF_MARKER`].
+    Eof(Bytes),
+}
+
+#[cfg(feature = "client")]
+impl TryFrom<Frame> for TgMessage {
+    type Error = Error;
+
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        Ok(match frame {
+            Frame::Binary(data) => TgMessage::Binary(data),
+            Frame::Text(text) => TgMessage::Text(
+                text.try_into()
+                    .map_err(|_| invalid_data("text frame is not valid UTF-8"))?,
+            ),
+            Frame::Ping => TgMessage::Ping(Bytes::new()),
+            Frame::Eof(payload) => TgMessage::Pong(payload),
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+impl TryFrom<Frame> for AxMessage {
+    type Error = Error;
+
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        Ok(match frame {
+            Frame::Binary(data) => AxMessage::Binary(data),
+            Frame::Text(text) => AxMessage::Text(
+                text.try_into()
+                    .map_err(|_| invalid_data("text frame is not valid UTF-8"))?,
+            ),
+            Frame::Ping => AxMessage::Ping(Bytes::new()),
+            Frame::Eof(payload) => AxMessage::Pong(payload),
+        })
+    }
+}
+
 /// A enum for different type of WebSocket message.
 #[cfg(feature = "client")]
 impl From<TgMessage> for Message {
     /// Converts a `TgMessage` to a `Message`.
     fn from(msg: TgMessage) -> Self {
         match msg {
-            TgMessage::Binary(data) => Message::Binary(data.into()),
-            TgMessage::Text(data) => Message::Binary(data.as_bytes().to_vec()),
+            TgMessage::Binary(data) => Message::Binary(data),
+            TgMessage::Text(data) => Message::Binary(data.into()),
+            TgMessage::Close(frame) => Message::Close(frame.map(Into::into)),
             _ => Message::Others,
         }
     }
@@ -65,13 +378,61 @@ impl From<AxMessage> for Message {
     /// Converts a `AxMessage` to a `Message`.
     fn from(msg: AxMessage) -> Self {
         match msg {
-            AxMessage::Binary(data) => Message::Binary(data.into()),
-            AxMessage::Text(data) => Message::Binary(data.as_bytes().to_vec()),
+            AxMessage::Binary(data) => Message::Binary(data),
+            AxMessage::Text(data) => Message::Binary(data.into()),
+            AxMessage::Close(frame) => Message::Close(frame.map(Into::into)),
             _ => Message::Others,
         }
     }
 }
 
+/// Keepalive pings of a WebSocket connection.
+///
+/// A ping is sent every `interval`, and the connection fails once
+/// `max_missed` pings in a row are left unanswered. Any frame from the peer
+/// counts as an answer, so a busy connection never times out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Keepalive {
+    /// The time between two pings.
+    pub interval: Duration,
+    /// How many pings in a row may go unanswered.
+    pub max_missed: u32,
+}
+
+impl Keepalive {
+    /// Creates a new `Keepalive` that pings every `interval` and gives up
+    /// after three missed pongs.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            max_missed: 3,
+        }
+    }
+
+    /// Sets how many pings in a row may go unanswered.
+    pub fn with_max_missed(mut self, max_missed: u32) -> Self {
+        self.max_missed = max_missed.max(1);
+        self
+    }
+}
+
+/// The keepalive state of a `WrappedWsStream`.
+struct KeepaliveState {
+    keepalive: Keepalive,
+    ticker: Interval,
+    /// Pings sent since the peer was last heard from.
+    missed: u32,
+    ping: Ping,
+}
+
+/// The progress of the next ping.
+#[derive(Copy, Clone)]
+enum Ping {
+    Idle,
+    Due,
+    Flushing,
+}
+
 /// A enum for different type of WebSocket stream.
 ///
 /// honestly, this is a bit of a hack, but it works.
@@ -87,18 +448,170 @@ pub enum WsStream {
 }
 
 /// A wrapper around WebSocket stream.
+///
+/// WebSocket has no half-close: once a close frame is sent or received,
+/// tungstenite refuses to send anything else. So a pong that starts with
+/// `wsrx-eof` tells the peer that no more data will follow in one direction,
+/// while the other direction keeps flowing. Closing the sink sends that pong,
+/// the stream ends when it is received, and sending a [`Message::Close`]
+/// closes the whole connection. Peers ignore unsolicited pongs, so a peer
+/// that doesn't know the marker just never sees the half-close, and empty
+/// messages are plain data, e.g. empty datagrams.
+///
+/// With [`Keepalive`] enabled, pings are sent while the stream is polled and
+/// it fails with a `TimedOut` error once the peer stops answering.
+///
+/// With [`Deflate`] enabled, data is compressed before it is encoded and
+/// decompressed after it is decoded, see [`crate::deflate`]. With a [`Cipher`],
+/// data is sealed after it is compressed and opened before it is
+/// decompressed, see [`crate::encrypt`].
 pub struct WrappedWsStream {
     /// The WebSocket stream.
     stream: WsStream,
+    /// How data is carried in frames.
+    encoding: FrameEncoding,
+    /// An incomplete UTF-8 sequence waiting for the rest of its bytes.
+    utf8_tail: Bytes,
+    /// A close frame was sent or received.
+    closing: bool,
+    /// The end of data was sent to the peer.
+    half_closed: bool,
+    /// Keepalive pings, if enabled.
+    keepalive: Option<KeepaliveState>,
+    /// Per-message compression, if negotiated.
+    deflate: Option<Deflate>,
+    /// End-to-end encryption, if negotiated, boxed as its keys are large.
+    cipher: Option<Box<Cipher>>,
+}
+
+impl WrappedWsStream {
+    #[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+    fn new(stream: WsStream) -> Self {
+        WrappedWsStream {
+            stream,
+            encoding: FrameEncoding::default(),
+            utf8_tail: Bytes::new(),
+            closing: false,
+            half_closed: false,
+            keepalive: None,
+            deflate: None,
+            cipher: None,
+        }
+    }
+
+    /// Sets how data is carried in frames.
+    pub fn with_encoding(mut self, encoding: FrameEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Returns how data is carried in frames.
+    pub fn encoding(&self) -> FrameEncoding {
+        self.encoding
+    }
+
+    /// Compresses the data of every message, `None` disables compression.
+    ///
+    /// Both sides must agree on it, and it can't be used with the text
+    /// encoding as deflated data is not valid UTF-8.
+    pub fn with_deflate(mut self, deflate: Option<Deflate>) -> Self {
+        self.deflate = deflate;
+        self
+    }
+
+    /// Encrypts the data of every message, `None` disables encryption.
+    ///
+    /// Both sides must agree on it, and it can't be used with the text
+    /// encoding as encrypted data is not valid UTF-8.
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher.map(Box::new);
+        self
+    }
+
+    /// Sends keepalive pings to detect a dead peer, `None` disables them.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive.map(|keepalive| {
+            let start = tokio::time::Instant::now() + keepalive.interval;
+            let mut ticker = tokio::time::interval_at(start, keepalive.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            KeepaliveState {
+                keepalive,
+                ticker,
+                missed: 0,
+                ping: Ping::Idle,
+            }
+        });
+        self
+    }
+
+    /// Sends a ping when one is due, and fails if too many pings went
+    /// unanswered.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        let Some(state) = &mut self.keepalive else {
+            return Ok(());
+        };
+        if self.closing {
+            return Ok(());
+        }
+        while state.ticker.poll_tick(cx).is_ready() {
+            if state.missed >= state.keepalive.max_missed {
+                let msg = format!("WebSocket peer did not answer {} pings", state.missed);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, msg).into());
+            }
+            state.missed += 1;
+            state.ping = Ping::Due;
+        }
+        let mut ping = state.ping;
+        loop {
+            match ping {
+                Ping::Due => match Pin::new(&mut *self).poll_ready(cx) {
+                    Poll::Ready(res) => {
+                        res?;
+                        self.start_send_frame(Frame::Ping)?;
+                        ping = Ping::Flushing;
+                    }
+                    Poll::Pending => break,
+                },
+                Ping::Flushing => match Pin::new(&mut *self).poll_flush(cx) {
+                    Poll::Ready(res) => {
+                        res?;
+                        ping = Ping::Idle;
+                    }
+                    Poll::Pending => break,
+                },
+                Ping::Idle => break,
+            }
+        }
+        if let Some(state) = &mut self.keepalive {
+            state.ping = ping;
+        }
+        Ok(())
+    }
+
+    /// Queues an encoded frame.
+    fn start_send_frame(&mut self, _frame: Frame) -> Result<(), Error> {
+        match &mut self.stream {
+            #[cfg(feature = "client")]
+            WsStream::Tungstenite(stream) => Pin::new(stream)
+                .start_send(_frame.try_into()?)
+                .map_err(|e| e.into()),
+            #[cfg(feature = "server")]
+            WsStream::AxumWebsocket(stream) => Pin::new(stream)
+                .start_send(_frame.try_into()?)
+                .map_err(|e| e.into()),
+            #[allow(unreachable_patterns)]
+            _ => Ok(()),
+        }
+    }
 }
 
 #[cfg(feature = "client")]
 impl From<WebSocketStream<MaybeTlsStream<TcpStream>>> for WrappedWsStream {
     /// Creates a new `WrappedWsStream` from tungstenite's WebSocket stream.
     fn from(stream: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Self {
-        WrappedWsStream {
-            stream: WsStream::Tungstenite(Box::new(stream)),
-        }
+        WrappedWsStream::new(WsStream::Tungstenite(Box::new(stream)))
     }
 }
 
@@ -106,9 +619,23 @@ impl From<WebSocketStream<MaybeTlsStream<TcpStream>>> for WrappedWsStream {
 impl From<WebSocket> for WrappedWsStream {
     /// Creates a new `WrappedWsStream` from axum's WebSocket stream.
     fn from(stream: WebSocket) -> Self {
-        WrappedWsStream {
-            stream: WsStream::AxumWebsocket(Box::new(stream)),
-        }
+        WrappedWsStream::new(WsStream::AxumWebsocket(Box::new(stream)))
+    }
+}
+
+/// Checks the payload of an end of data pong, which is sealed too with a
+/// [`Cipher`], and ends the stream if it is valid.
+#[cfg(any(feature = "client", feature = "server"))]
+fn open_eof(cipher: &mut Option<Box<Cipher>>, payload: Bytes) -> Option<Result<Message, Error>> {
+    let sealed = payload.slice(EOF_MARKER.len()..);
+    let data = match cipher {
+        Some(cipher) => cipher.open(sealed),
+        None => Ok(sealed),
+    };
+    match data {
+        Ok(data) if data.is_empty() => None,
+        Ok(_) => Some(Err(invalid_data("invalid end of data"))),
+        Err(e) => Some(Err(e)),
     }
 }
 
@@ -117,27 +644,71 @@ impl Stream for WrappedWsStream {
     type Item = Result<Message, Error>;
 
     /// Polls the next message from the WebSocket stream.
+    ///
+    /// The stream ends when the peer sends the end of data pong, which must be
+    /// sealed with a [`Cipher`]. Keepalive pings are sent from here.
     fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match &mut self.stream {
+        if let Err(e) = self.poll_keepalive(_cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let this = self.as_mut().get_mut();
+        let _encoding = this.encoding;
+        let _cipher = &mut this.cipher;
+        let msg = match &mut this.stream {
             #[cfg(feature = "client")]
             WsStream::Tungstenite(stream) => {
                 match futures_util::ready!(Pin::new(stream).poll_next(_cx)) {
-                    Some(Ok(msg)) => Poll::Ready(Some(Ok(msg.into()))),
-                    Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
-                    None => Poll::Ready(None),
+                    Some(Ok(TgMessage::Text(text))) => {
+                        Some(_encoding.decode_text(text.into()).map(Message::Binary))
+                    }
+                    Some(Ok(TgMessage::Pong(payload))) if payload.starts_with(EOF_MARKER) => {
+                        open_eof(_cipher, payload)
+                    }
+                    Some(Ok(msg)) => Some(Ok(msg.into())),
+                    Some(Err(e)) => Some(Err(e.into())),
+                    None => None,
                 }
             }
             #[cfg(feature = "server")]
             WsStream::AxumWebsocket(stream) => {
                 match futures_util::ready!(Pin::new(stream).poll_next(_cx)) {
-                    Some(Ok(msg)) => Poll::Ready(Some(Ok(msg.into()))),
-                    Some(Err(e)) => Poll::Ready(Some(Err(e.into()))),
-                    None => Poll::Ready(None),
+                    Some(Ok(AxMessage::Text(text))) => {
+                        Some(_encoding.decode_text(text.into()).map(Message::Binary))
+                    }
+                    Some(Ok(AxMessage::Pong(payload))) if payload.starts_with(EOF_MARKER) => {
+                        open_eof(_cipher, payload)
+                    }
+                    Some(Ok(msg)) => Some(Ok(msg.into())),
+                    Some(Err(e)) => Some(Err(e.into())),
+                    None => None,
                 }
             }
             #[allow(unreachable_patterns)]
-            _ => Poll::Ready(None),
+            _ => None,
+        };
+        if let Some(state) = &mut this.keepalive {
+            state.missed = 0;
+        }
+        let msg = match msg {
+            Some(Ok(Message::Binary(data))) => {
+                let data = match &mut this.cipher {
+                    Some(cipher) => cipher.open(data),
+                    None => Ok(data),
+                };
+                match data {
+                    Ok(data) => match &mut this.deflate {
+                        Some(deflate) => Some(deflate.decompress(data).map(Message::Binary)),
+                        None => Some(Ok(Message::Binary(data))),
+                    },
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            msg => msg,
+        };
+        if let Some(Ok(Message::Close(_))) = msg {
+            self.closing = true;
         }
+        Poll::Ready(msg)
     }
 }
 
@@ -159,25 +730,41 @@ impl Sink<Message> for WrappedWsStream {
         }
     }
 
-    /// Sends a message to the WebSocket stream.
-    fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), Self::Error> {
-        match &mut self.get_mut().stream {
-            #[cfg(feature = "client")]
-            WsStream::Tungstenite(stream) => match _item {
-                Message::Binary(data) => Pin::new(stream)
-                    .start_send(TgMessage::Binary(data.into()))
-                    .map_err(|e| e.into()),
-                Message::Others => Ok(()),
-            },
-            #[cfg(feature = "server")]
-            WsStream::AxumWebsocket(stream) => match _item {
-                Message::Binary(data) => Pin::new(stream)
-                    .start_send(AxMessage::Binary(data.into()))
-                    .map_err(|e| e.into()),
-                Message::Others => Ok(()),
-            },
-            #[allow(unreachable_patterns)]
-            _ => Ok(()),
+    /// Sends a message to the WebSocket stream, compressing, encrypting and
+    /// encoding its data.
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match item {
+            Message::Binary(data) => {
+                let data = match &mut this.deflate {
+                    Some(deflate) => deflate.compress(data),
+                    None => data,
+                };
+                let data = match &mut this.cipher {
+                    Some(cipher) => cipher.seal(&data)?,
+                    None => data,
+                };
+                match this.encoding.encode(data, &mut this.utf8_tail)? {
+                    Some(frame) => this.start_send_frame(frame),
+                    None => Ok(()),
+                }
+            }
+            Message::Close(_frame) => {
+                this.closing = true;
+                match &mut this.stream {
+                    #[cfg(feature = "client")]
+                    WsStream::Tungstenite(stream) => Pin::new(stream)
+                        .start_send(TgMessage::Close(_frame.map(Into::into)))
+                        .map_err(|e| e.into()),
+                    #[cfg(feature = "server")]
+                    WsStream::AxumWebsocket(stream) => Pin::new(stream)
+                        .start_send(AxMessage::Close(_frame.map(Into::into)))
+                        .map_err(|e| e.into()),
+                    #[allow(unreachable_patterns)]
+                    _ => Ok(()),
+                }
+            }
+            Message::Others => Ok(()),
         }
     }
 
@@ -196,8 +783,28 @@ impl Sink<Message> for WrappedWsStream {
     }
 
     /// Polls the WebSocket stream to close the connection.
+    ///
+    /// Unless a close frame was sent or received, this only tells the peer
+    /// that no more data will follow, messages from the peer can still be
+    /// received.
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match &mut self.get_mut().stream {
+        let this = self.get_mut();
+        if !this.closing {
+            if !this.half_closed {
+                if !this.utf8_tail.is_empty() {
+                    return Poll::Ready(Err(invalid_data("data ends with incomplete UTF-8")));
+                }
+                futures_util::ready!(Pin::new(&mut *this).poll_ready(_cx))?;
+                let mut payload = BytesMut::from(EOF_MARKER);
+                if let Some(cipher) = &mut this.cipher {
+                    payload.extend_from_slice(&cipher.seal(&[])?);
+                }
+                this.start_send_frame(Frame::Eof(payload.freeze()))?;
+                this.half_closed = true;
+            }
+            return Pin::new(this).poll_flush(_cx);
+        }
+        match &mut this.stream {
             #[cfg(feature = "client")]
             WsStream::Tungstenite(stream) => Pin::new(stream).poll_close(_cx).map_err(|e| e.into()),
             #[cfg(feature = "server")]
@@ -218,41 +825,320 @@ impl Sink<Message> for WrappedWsStream {
 /// * `token` - The cancellation token to cancel the proxying.
 pub async fn proxy(
     ws: WrappedWsStream, tcp: TcpStream, token: CancellationToken,
-) -> Result<(), Error> {
-    let framed_tcp_stream = Framed::new(tcp, MessageCodec::new());
-    proxy_stream(ws, framed_tcp_stream, token).await
+) -> Result<TrafficStats, Error> {
+    proxy_io(ws, tcp, token).await
+}
+
+/// Proxies a WebSocket stream with any byte stream, such as a Unix socket,
+/// the stdio of a child process, a TLS stream or an in-memory duplex.
+///
+/// The byte stream is framed with the default [`FrameLimits`], use
+/// [`FrameLimits::framed`] and [`proxy_stream`] for other limits.
+///
+/// * `ws` - The WebSocket stream, either axum's stream or tungstenite stream
+///   are supported.
+/// * `io` - The byte stream.
+/// * `token` - The cancellation token to cancel the proxying.
+pub async fn proxy_io<T>(
+    ws: WrappedWsStream, io: T, token: CancellationToken,
+) -> Result<TrafficStats, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let framed = FrameLimits::default().framed(io);
+    proxy_stream(ws, framed, token).await
+}
+
+/// Proxies a WebSocket stream with a UDP socket.
+///
+/// Every datagram is carried as a single binary WebSocket message, so packet
+/// boundaries are preserved in both directions.
+///
+/// * `ws` - The WebSocket stream, either axum's stream or tungstenite stream
+///   are supported.
+/// * `udp` - The UDP socket, which must be connected to its peer.
+/// * `token` - The cancellation token to cancel the proxying.
+pub async fn proxy_udp(
+    ws: WrappedWsStream, udp: UdpSocket, token: CancellationToken,
+) -> Result<TrafficStats, Error> {
+    proxy_stream(ws, DatagramStream::connected(udp), token).await
 }
 
 /// Proxies two streams.
 ///
-/// * `s1` - The first stream.
+/// Returns the traffic statistics of the session once it is finished.
+///
+/// * `s1` - The first stream, usually the WebSocket.
 /// * `s2` - The second stream.
 /// * `token` - The cancellation token to cancel the proxying.
-pub async fn proxy_stream<S, T>(s1: S, s2: T, token: CancellationToken) -> Result<(), Error>
+pub async fn proxy_stream<S, T>(
+    s1: S, s2: T, token: CancellationToken,
+) -> Result<TrafficStats, Error>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    proxy_stream_with_counter(s1, s2, token, &TrafficCounter::new()).await
+}
+
+/// Proxies two streams, counting the traffic into a shared counter.
+///
+/// The counter is updated while the session is running, so it can be read
+/// by others before the session ends. Returns the traffic statistics of this
+/// session once it is finished.
+///
+/// * `s1` - The first stream, usually the WebSocket.
+/// * `s2` - The second stream.
+/// * `token` - The cancellation token to cancel the proxying.
+/// * `counter` - The live counter, usually shared by many sessions.
+pub async fn proxy_stream_with_counter<S, T>(
+    s1: S, s2: T, token: CancellationToken, counter: &TrafficCounter,
+) -> Result<TrafficStats, Error>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    proxy_observed(s1, s2, token, counter, |_, _| {}).await
+}
+
+/// Proxies two streams like [`proxy_stream_with_counter`], and tells the
+/// observer about the session and every message in it.
+///
+/// Without an observer this is the same as [`proxy_stream_with_counter`],
+/// and `label` is never formatted.
+///
+/// * `s1` - The first stream, usually the WebSocket.
+/// * `s2` - The second stream.
+/// * `token` - The cancellation token to cancel the proxying.
+/// * `counter` - The live counter, usually shared by many sessions.
+/// * `observer` - The observer of the session, if any.
+/// * `label` - What the session serves, e.g. the address of the local peer.
+pub async fn proxy_stream_observed<S, T>(
+    s1: S, s2: T, token: CancellationToken, counter: &TrafficCounter,
+    observer: Option<&dyn Observer>, label: impl std::fmt::Display,
+) -> Result<TrafficStats, Error>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    let Some(observer) = observer else {
+        return proxy_observed(s1, s2, token, counter, |_, _| {}).await;
+    };
+    let session = SessionInfo::new(label.to_string());
+    observer.session_opened(&session);
+    let res = proxy_observed(s1, s2, token, counter, |direction, data| {
+        observer.message(&session, direction, data)
+    })
+    .await;
+    observer.session_closed(&session, res.as_ref());
+    res
+}
+
+/// Proxies two streams, calling `observe` with every message passed on.
+async fn proxy_observed<S, T>(
+    s1: S, s2: T, token: CancellationToken, counter: &TrafficCounter,
+    observe: impl Fn(Direction, &[u8]),
+) -> Result<TrafficStats, Error>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    let session = TrafficCounter::new();
+    session
+        .scope(proxy_session(s1, s2, token, counter, &session, observe))
+        .await
+}
+
+/// Proxies two streams like [`proxy_observed`], counting the traffic into
+/// the counter of the session too.
+async fn proxy_session<S, T>(
+    s1: S, s2: T, token: CancellationToken, counter: &TrafficCounter, session: &TrafficCounter,
+    observe: impl Fn(Direction, &[u8]),
+) -> Result<TrafficStats, Error>
 where
     S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
     T: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
 {
-    let (s1sink, s1stream) = s1.split();
-    let (s2sink, s2stream) = s2.split();
-    let f1 = s1stream.forward(s2sink);
-    let f2 = s2stream.forward(s1sink);
+    let _guard = counter.open_session();
+    let started = Instant::now();
 
-    tokio::select! {
-        res = f1 => res,
-        res = f2 => res,
-        _ = token.cancelled() => Ok(())
+    let (mut s1sink, mut s1stream) = s1.split();
+    let (mut s2sink, mut s2stream) = s2.split();
+
+    let (closed_by, close_frame) = {
+        // Once the first stream ends it is still read until the second one
+        // ends too, so its keepalive pings go on and a close frame from it is
+        // not missed.
+        let (s1_eof, s2_eof) = (AtomicBool::new(false), AtomicBool::new(false));
+        let inbound = pipe(
+            &mut s1stream,
+            &mut s2sink,
+            |data| {
+                counter.add_inbound(data.len());
+                session.add_inbound(data.len());
+                observe(Direction::Inbound, data);
+            },
+            &s1_eof,
+            Some(&s2_eof),
+        );
+        let outbound = pipe(
+            &mut s2stream,
+            &mut s1sink,
+            |data| {
+                counter.add_outbound(data.len());
+                session.add_outbound(data.len());
+                observe(Direction::Outbound, data);
+            },
+            &s2_eof,
+            None,
+        );
+        tokio::pin!(inbound, outbound);
+
+        // Each direction keeps flowing until its source ends, the session is
+        // over once both are done or either side sends a close frame.
+        let mut first = None;
+        let (mut inbound_done, mut outbound_done) = (false, false);
+        loop {
+            let end = tokio::select! {
+                end = &mut inbound, if !inbound_done => {
+                    inbound_done = true;
+                    (end?, ClosedBy::WebSocket)
+                }
+                end = &mut outbound, if !outbound_done => {
+                    outbound_done = true;
+                    (end?, ClosedBy::Stream)
+                }
+                _ = token.cancelled() => {
+                    break (
+                        ClosedBy::Cancelled,
+                        Some(CloseFrame::new(CLOSE_GOING_AWAY, "")),
+                    );
+                }
+            };
+            if s1_eof.load(Ordering::Relaxed) {
+                first.get_or_insert(ClosedBy::WebSocket);
+            }
+            let closed_by = *first.get_or_insert(end.1);
+            match end.0 {
+                End::Close(frame) => break (closed_by, frame),
+                End::Eof if outbound_done && (inbound_done || s1_eof.load(Ordering::Relaxed)) => {
+                    break (closed_by, Some(CloseFrame::new(CLOSE_NORMAL, "")));
+                }
+                End::Eof => {}
+            }
+        }
+    };
+
+    // Pass the close frame on to both sides, so a close received from one
+    // WebSocket reaches the other one with its code and reason, and the
+    // replies are flushed.
+    let close = async {
+        s2sink.send(Message::Close(close_frame.clone())).await.ok();
+        s2sink.close().await.ok();
+        s1sink.send(Message::Close(close_frame.clone())).await.ok();
+        s1sink.close().await.ok();
+    };
+    tokio::time::timeout(CLOSE_TIMEOUT, close).await.ok();
+
+    let traffic = session.snapshot();
+    Ok(TrafficStats {
+        inbound_bytes: traffic.inbound_bytes,
+        outbound_bytes: traffic.outbound_bytes,
+        inbound_messages: traffic.inbound_messages,
+        outbound_messages: traffic.outbound_messages,
+        uncompressed_bytes: traffic.uncompressed_bytes,
+        compressed_bytes: traffic.compressed_bytes,
+        duration: started.elapsed(),
+        closed_by,
+        close_frame,
+    })
+}
+
+/// How one direction of a proxied session ended.
+enum End {
+    /// The source has no more data, and the destination was told so.
+    Eof,
+    /// The source sent a close frame.
+    Close(Option<CloseFrame>),
+}
+
+/// Forwards the messages of one direction of a session.
+///
+/// Messages are flushed whenever the source has nothing more to read right
+/// away. `count` is called with the data of every binary message. `eof` is
+/// set once the source ends, and with `drain` the source is read on until
+/// `drain` is set too, ignoring its data.
+async fn pipe<St, Si>(
+    src: &mut St, dst: &mut Si, count: impl Fn(&[u8]), eof: &AtomicBool, drain: Option<&AtomicBool>,
+) -> Result<End, Error>
+where
+    St: Stream<Item = Result<Message, Error>> + Unpin,
+    Si: Sink<Message, Error = Error> + Unpin,
+{
+    loop {
+        let msg = match src.next().now_or_never() {
+            Some(msg) => msg,
+            None => {
+                dst.flush().await?;
+                src.next().await
+            }
+        };
+        match msg {
+            Some(Ok(Message::Binary(data))) => {
+                count(&data);
+                dst.feed(Message::Binary(data)).await?;
+            }
+            Some(Ok(Message::Close(frame))) => {
+                dst.flush().await?;
+                return Ok(End::Close(frame));
+            }
+            Some(Ok(Message::Others)) => {}
+            Some(Err(e)) => return Err(e),
+            None => {
+                dst.close().await?;
+                eof.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+    }
+    while drain.is_some_and(|done| !done.load(Ordering::Relaxed)) {
+        match src.next().await {
+            Some(Ok(Message::Close(frame))) => return Ok(End::Close(frame)),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => break,
+        }
     }
+    Ok(End::Eof)
 }
 
 /// A codec for WebSocket messages.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
-pub struct MessageCodec;
+///
+/// Decoding splits the data read so far into messages of at most
+/// `max_frame_size` bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+}
 
 impl MessageCodec {
     /// Creates a new `MessageCodec` for shipping around raw bytes.
     pub fn new() -> MessageCodec {
-        Self
+        Self::default()
+    }
+
+    /// Creates a new `MessageCodec` that emits messages of at most
+    /// `max_frame_size` bytes.
+    pub fn with_max_frame_size(max_frame_size: usize) -> MessageCodec {
+        Self {
+            max_frame_size: max_frame_size.max(1),
+        }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::with_max_frame_size(FrameLimits::default().max_frame_size)
     }
 }
 
@@ -264,8 +1150,8 @@ impl Decoder for MessageCodec {
     /// Decodes a WebSocket message from the buffer.
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, Error> {
         if !buf.is_empty() {
-            let len = buf.len();
-            Ok(Some(Message::Binary(buf.split_to(len).to_vec())))
+            let len = buf.len().min(self.max_frame_size);
+            Ok(Some(Message::Binary(buf.split_to(len).freeze())))
         } else {
             Ok(None)
         }
@@ -280,11 +1166,286 @@ impl Encoder<Message> for MessageCodec {
     fn encode(&mut self, data: Message, buf: &mut BytesMut) -> Result<(), Error> {
         match data {
             Message::Binary(data) => {
-                buf.reserve(data.len());
-                buf.put(Bytes::from(data));
+                buf.extend_from_slice(&data);
                 Ok(())
             }
-            Message::Others => Ok(()),
+            Message::Close(_) | Message::Others => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::encrypt::EncryptionKey;
+
+    /// Connects both ends of a WebSocket over a loopback TCP connection.
+    #[cfg(feature = "client")]
+    pub(crate) async fn ws_pair() -> (WrappedWsStream, WrappedWsStream) {
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(
+            async {
+                let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                tokio_tungstenite::client_async(url, MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(MaybeTlsStream::Plain(tcp))
+                    .await
+                    .unwrap()
+            },
+        );
+        (client.into(), server.into())
+    }
+
+    /// Derives the ciphers of both ends of a connection.
+    pub(crate) fn cipher_pair() -> (Cipher, Cipher) {
+        let key = EncryptionKey::generate().unwrap();
+        let (exchange, offer) = key.offer().unwrap();
+        let (server, answer) = key.accept(&offer).unwrap();
+        (exchange.finish(Some(&answer)).unwrap(), server)
+    }
+
+    pub(crate) fn binary(msg: Option<Result<Message, Error>>) -> Bytes {
+        match msg {
+            Some(Ok(Message::Binary(data))) => data,
+            Some(Err(e)) => panic!("expected a binary message, got {e}"),
+            _ => panic!("expected a binary message"),
+        }
+    }
+
+    #[test]
+    fn codec_splits_reads_into_frames() {
+        let mut codec = MessageCodec::with_max_frame_size(4);
+        let mut buf = BytesMut::from(&b"0123456789"[..]);
+        let mut frames = Vec::new();
+        while let Some(msg) = codec.decode(&mut buf).unwrap() {
+            frames.push(binary(Some(Ok(msg))));
+        }
+        assert_eq!(frames, [&b"0123"[..], b"4567", b"89"]);
+
+        let mut out = BytesMut::new();
+        for frame in frames {
+            codec.encode(Message::Binary(frame), &mut out).unwrap();
+        }
+        codec.encode(Message::Others, &mut out).unwrap();
+        assert_eq!(&out[..], b"0123456789");
+    }
+
+    #[test]
+    fn text_encoding_keeps_split_utf8_for_the_next_frame() {
+        let mut tail = Bytes::new();
+        let data = Bytes::from_static("é".as_bytes());
+        let frame = FrameEncoding::Text.encode(data.slice(..1), &mut tail);
+        assert!(frame.unwrap().is_none());
+        let frame = FrameEncoding::Text.encode(data.slice(1..), &mut tail);
+        assert!(matches!(frame, Ok(Some(Frame::Text(text))) if text == data));
+        assert!(tail.is_empty());
+
+        let invalid = Bytes::from_static(&[0xFF, b'a']);
+        assert!(FrameEncoding::Text.encode(invalid, &mut tail).is_err());
+    }
+
+    #[test]
+    fn empty_data_is_a_frame_with_every_encoding() {
+        for encoding in [
+            FrameEncoding::Binary,
+            FrameEncoding::Text,
+            FrameEncoding::Base64,
+        ] {
+            let frame = encoding.encode(Bytes::new(), &mut Bytes::new());
+            assert!(frame.unwrap().is_some(), "{encoding}");
+        }
+    }
+
+    #[cfg(any(feature = "client", feature = "server"))]
+    #[test]
+    fn base64_text_round_trip() {
+        let data = Bytes::from_static(&[0, 1, 0xFF]);
+        let Ok(Some(Frame::Text(text))) =
+            FrameEncoding::Base64.encode(data.clone(), &mut Bytes::new())
+        else {
+            panic!("expected a text frame");
+        };
+        assert_eq!(FrameEncoding::Base64.decode_text(text).unwrap(), data);
+        let invalid = Bytes::from_static(b"not base64!");
+        assert!(FrameEncoding::Base64.decode_text(invalid).is_err());
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn invalid_utf8_text_frames_fail_instead_of_panicking() {
+        let frame = Frame::Text(Bytes::from_static(&[0xFF]));
+        assert!(TgMessage::try_from(frame).is_err());
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn half_close_keeps_the_other_direction_open() {
+        let (mut client, mut server) = ws_pair().await;
+        client
+            .send(Message::Binary(Bytes::from_static(b"request")))
+            .await
+            .unwrap();
+        client.close().await.unwrap();
+        assert_eq!(binary(server.next().await), Bytes::from_static(b"request"));
+        assert!(server.next().await.is_none());
+
+        server
+            .send(Message::Binary(Bytes::from_static(b"response")))
+            .await
+            .unwrap();
+        assert_eq!(binary(client.next().await), Bytes::from_static(b"response"));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn websocket_keeps_answering_pings_after_its_end_of_data() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, server) = ws_pair().await;
+        let keepalive = Keepalive::new(Duration::from_millis(50)).with_max_missed(2);
+        let mut client = client.with_keepalive(Some(keepalive));
+        let (io, mut backend) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(proxy_io(server, io, CancellationToken::new()));
+
+        client
+            .send(Message::Binary(Bytes::from_static(b"request")))
+            .await
+            .unwrap();
+        client.close().await.unwrap();
+        let reply = tokio::spawn(async move {
+            loop {
+                match client.next().await {
+                    Some(Ok(Message::Others)) => {}
+                    msg => break binary(msg),
+                }
+            }
+        });
+
+        let mut request = Vec::new();
+        backend.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        backend.write_all(b"response").await.unwrap();
+        backend.shutdown().await.unwrap();
+
+        assert_eq!(reply.await.unwrap(), Bytes::from_static(b"response"));
+        let stats = proxy.await.unwrap().unwrap();
+        assert_eq!(stats.closed_by, ClosedBy::WebSocket);
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn empty_messages_are_data() {
+        let (mut client, mut server) = ws_pair().await;
+        client.send(Message::Binary(Bytes::new())).await.unwrap();
+        client
+            .send(Message::Binary(Bytes::from_static(b"after")))
+            .await
+            .unwrap();
+        assert!(binary(server.next().await).is_empty());
+        assert_eq!(binary(server.next().await), Bytes::from_static(b"after"));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn encrypted_half_close_must_be_sealed() {
+        let (client, server) = ws_pair().await;
+        let (client_cipher, server_cipher) = cipher_pair();
+        let mut client = client.with_cipher(Some(client_cipher));
+        let mut server = server.with_cipher(Some(server_cipher));
+        client.close().await.unwrap();
+        assert!(server.next().await.is_none());
+
+        let (mut client, server) = ws_pair().await;
+        let mut server = server.with_cipher(Some(cipher_pair().1));
+        client.close().await.unwrap();
+        assert!(matches!(server.next().await, Some(Err(_))));
+    }
+
+    /// One end of an in-memory message channel, standing in for a WebSocket.
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn sessions_report_the_compression_of_their_websocket() {
+        use crate::deflate::Compression;
+
+        let (client, server) = ws_pair().await;
+        let deflate = || Some(Compression::default().deflate(&FrameLimits::default()));
+        let (client, mut server) = (
+            client.with_deflate(deflate()),
+            server.with_deflate(deflate()),
+        );
+        let (mut local, stream) = message_pipe();
+        let proxy = tokio::spawn(proxy_stream(client, stream, CancellationToken::new()));
+
+        local
+            .send(Message::Binary(Bytes::from(vec![b'a'; 4096])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.next().await,
+            Some(Ok(Message::Binary(data))) if data.len() == 4096
+        ));
+        server.send(Message::Close(None)).await.unwrap();
+
+        let stats = proxy.await.unwrap().unwrap();
+        assert_eq!(stats.uncompressed_bytes, 4096);
+        assert!(stats.compression_ratio().unwrap() > 10.0);
+    }
+
+    pub(crate) struct MessagePipe {
+        tx: mpsc::UnboundedSender<Message>,
+        rx: mpsc::UnboundedReceiver<Message>,
+    }
+
+    /// Creates both ends of an in-memory message channel.
+    pub(crate) fn message_pipe() -> (MessagePipe, MessagePipe) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (
+            MessagePipe { tx: a_tx, rx: b_rx },
+            MessagePipe { tx: b_tx, rx: a_rx },
+        )
+    }
+
+    impl Stream for MessagePipe {
+        type Item = Result<Message, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_recv(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    impl Sink<Message> for MessagePipe {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+            self.tx
+                .send(item)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
         }
     }
 }
@@ -0,0 +1,1142 @@
+//! Resumable sessions for WebSocket Reflector X.
+//!
+//! A resumable session outlives the WebSocket connection it started on. When
+//! the connection drops, the client dials a new one and reattaches it to the
+//! same session on the server, so the backend connection of the server
+//! survives a flapping network. The session is negotiated with the
+//! `wsrx-resume` subprotocol, peers that don't speak it keep using plain
+//! sessions.
+//!
+//! Every binary WebSocket message carries exactly one frame:
+//!
+//! ```text
+//! +----------+---------------------------------------------------+
+//! | kind: u8 | fields ...                                        |
+//! +----------+---------------------------------------------------+
+//! ```
+//!
+//! * `HELLO` - session id: `u128` BE, received: `u64` BE. The first frame of
+//!   every connection of the client, a zero session id starts a new session.
+//! * `WELCOME` - session id: `u128` BE, received: `u64` BE. The answer of the
+//!   server to `HELLO`.
+//! * `DATA` - seq: `u64` BE, then the payload.
+//! * `EOF` - seq: `u64` BE, the sender will not send more data.
+//! * `CLOSE` - seq: `u64` BE, then the close code as `u16` BE and the reason,
+//!   if the close frame has one.
+//! * `ACK` - consumed: `u64` BE, how many frames of the peer were consumed.
+//!
+//! `DATA`, `EOF` and `CLOSE` are numbered from zero in each direction and kept
+//! in a replay buffer until the peer acknowledges them. `HELLO` and `WELCOME`
+//! carry how many of them the sender has received, and each side replays the
+//! rest on the new connection, duplicates are dropped by their sequence
+//! number. A sender never has more than [`REPLAY_BUFFER`] bytes of data
+//! unacknowledged, and a receiver fails the session if the peer sends more,
+//! so the buffer also bounds what a receiver queues.
+//!
+//! Sessions of a server belong to the key of the tunnel they were started
+//! on, connections of other keys can't reattach to them.
+//!
+//! If no connection is reattached within the grace period, the session
+//! fails. A WebSocket close frame ends the session for good.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{
+    SinkExt, StreamExt,
+    future::BoxFuture,
+    sink::Sink,
+    stream::{SplitSink, Stream},
+};
+use rustls::crypto::CryptoProvider;
+use tokio::{
+    sync::{Notify, Semaphore, mpsc},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_util::{
+    bytes::{Buf, BufMut, Bytes, BytesMut},
+    sync::PollSemaphore,
+};
+
+use crate::proxy::{CloseFrame, Error, Message, WrappedWsStream};
+
+/// The WebSocket subprotocol that negotiates a resumable session.
+pub const RESUME_PROTOCOL: &str = "wsrx-resume";
+
+/// The most data each direction of a session keeps for replay.
+pub const REPLAY_BUFFER: usize = 1024 * 1024;
+
+/// How long a session waits for a new connection by default.
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(60);
+
+/// The close code sent to clients that try to resume an unknown session.
+pub const CLOSE_UNKNOWN_SESSION: u16 = 4404;
+
+/// The largest payload of a single `DATA` frame.
+const MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+
+/// How long the `HELLO` and `WELCOME` exchange may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long closing the connection of a finished session may take.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The first and the longest wait between attempts to reconnect.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The kind of a resume frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum FrameKind {
+    Hello = 0,
+    Welcome = 1,
+    Data = 2,
+    Eof = 3,
+    Close = 4,
+    Ack = 5,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameKind::Hello),
+            1 => Ok(FrameKind::Welcome),
+            2 => Ok(FrameKind::Data),
+            3 => Ok(FrameKind::Eof),
+            4 => Ok(FrameKind::Close),
+            5 => Ok(FrameKind::Ack),
+            _ => Err(invalid_frame("unknown frame kind")),
+        }
+    }
+}
+
+/// A numbered frame, kept for replay until it is acknowledged.
+#[derive(Clone, Debug)]
+enum Packet {
+    Data(Bytes),
+    Eof,
+    Close(Option<CloseFrame>),
+}
+
+impl Packet {
+    /// The credit the packet takes while it is unacknowledged.
+    fn credit(&self) -> usize {
+        match self {
+            Packet::Data(data) => data.len(),
+            Packet::Eof | Packet::Close(_) => 0,
+        }
+    }
+}
+
+/// A resume frame.
+enum Frame {
+    Hello { id: u128, received: u64 },
+    Welcome { id: u128, received: u64 },
+    Packet { seq: u64, packet: Packet },
+    Ack { consumed: u64 },
+}
+
+impl Frame {
+    fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        match self {
+            Frame::Hello { id, received } | Frame::Welcome { id, received } => {
+                let kind = match self {
+                    Frame::Hello { .. } => FrameKind::Hello,
+                    _ => FrameKind::Welcome,
+                };
+                buf.put_u8(kind as u8);
+                buf.put_u128(*id);
+                buf.put_u64(*received);
+            }
+            Frame::Packet { seq, packet } => match packet {
+                Packet::Data(data) => {
+                    buf.put_u8(FrameKind::Data as u8);
+                    buf.put_u64(*seq);
+                    buf.extend_from_slice(data);
+                }
+                Packet::Eof => {
+                    buf.put_u8(FrameKind::Eof as u8);
+                    buf.put_u64(*seq);
+                }
+                Packet::Close(frame) => {
+                    buf.put_u8(FrameKind::Close as u8);
+                    buf.put_u64(*seq);
+                    if let Some(frame) = frame {
+                        buf.put_u16(frame.code);
+                        buf.extend_from_slice(frame.reason.as_bytes());
+                    }
+                }
+            },
+            Frame::Ack { consumed } => {
+                buf.put_u8(FrameKind::Ack as u8);
+                buf.put_u64(*consumed);
+            }
+        }
+        buf.freeze()
+    }
+
+    fn decode(mut buf: Bytes) -> Result<Self, Error> {
+        if buf.is_empty() {
+            return Err(invalid_frame("frame is too short"));
+        }
+        let kind = FrameKind::try_from(buf.get_u8())?;
+        let header = match kind {
+            FrameKind::Hello | FrameKind::Welcome => 24,
+            _ => 8,
+        };
+        if buf.len() < header {
+            return Err(invalid_frame("frame is too short"));
+        }
+        Ok(match kind {
+            FrameKind::Hello => Frame::Hello {
+                id: buf.get_u128(),
+                received: buf.get_u64(),
+            },
+            FrameKind::Welcome => Frame::Welcome {
+                id: buf.get_u128(),
+                received: buf.get_u64(),
+            },
+            FrameKind::Data => Frame::Packet {
+                seq: buf.get_u64(),
+                packet: Packet::Data(buf),
+            },
+            FrameKind::Eof => Frame::Packet {
+                seq: buf.get_u64(),
+                packet: Packet::Eof,
+            },
+            FrameKind::Close => {
+                let seq = buf.get_u64();
+                let frame = match buf.len() {
+                    0 => None,
+                    1 => return Err(invalid_frame("close code is too short")),
+                    _ => {
+                        let code = buf.get_u16();
+                        let reason = String::from_utf8(buf.to_vec())
+                            .map_err(|_| invalid_frame("close reason is not UTF-8"))?;
+                        Some(CloseFrame::new(code, reason))
+                    }
+                };
+                Frame::Packet {
+                    seq,
+                    packet: Packet::Close(frame),
+                }
+            }
+            FrameKind::Ack => Frame::Ack {
+                consumed: buf.get_u64(),
+            },
+        })
+    }
+}
+
+fn invalid_frame(reason: &'static str) -> Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid resume frame: {reason}"),
+    )
+    .into()
+}
+
+fn session_lost() -> Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "resumable session was not reattached in time",
+    )
+    .into()
+}
+
+fn connection_lost() -> Error {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
+
+/// Generates a random, non-zero session id.
+fn new_session_id() -> Result<u128, Error> {
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+    let mut id = [0; 16];
+    while id == [0; 16] {
+        provider
+            .secure_random
+            .fill(&mut id)
+            .map_err(|_| io::Error::other("failed to generate a session id"))?;
+    }
+    Ok(u128::from_be_bytes(id))
+}
+
+/// A WebSocket connection to attach to a session, with how many frames of
+/// the session its peer has received.
+type Attachment = (WrappedWsStream, u64);
+
+/// Dials a new WebSocket connection that negotiated the resume protocol.
+type Connector = Box<dyn FnMut() -> BoxFuture<'static, Result<WrappedWsStream, Error>> + Send>;
+
+/// The state shared by a session and its stream.
+struct Shared {
+    /// The credit to send data, returned when the peer acknowledges it.
+    credit: Arc<Semaphore>,
+    /// How many frames of the peer the stream has consumed.
+    consumed: AtomicU64,
+    /// Wakes the session to acknowledge consumed frames.
+    consumed_changed: Notify,
+}
+
+/// How a connection of a session ended.
+enum Outcome {
+    /// Both sides are done and the connection was closed.
+    Finished,
+    /// The connection broke, the session waits for a new one.
+    Lost,
+    /// A new connection replaced this one.
+    Replaced(Attachment),
+    /// The peer broke the protocol.
+    Failed(Error),
+}
+
+/// How a session gets new connections once one is lost.
+enum Reattach {
+    /// The client dials new connections.
+    Client(Connector),
+    /// The server waits for the client to reattach.
+    Server {
+        attachments: mpsc::UnboundedReceiver<Attachment>,
+        sessions: Sessions,
+    },
+}
+
+/// The background task of a session, which moves frames between its stream
+/// and the current WebSocket connection.
+struct Session {
+    id: u128,
+    grace: Duration,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedReceiver<Packet>,
+    incoming: mpsc::UnboundedSender<Result<Packet, Error>>,
+    /// Frames sent but not acknowledged yet, with their sequence numbers.
+    unacked: VecDeque<(u64, Packet)>,
+    /// The sequence number of the next frame to send.
+    next_seq: u64,
+    /// How many frames were received from the peer.
+    received: u64,
+    /// The sequence numbers and sizes of the received frames the peer
+    /// wasn't told were consumed yet, which take its credit.
+    unreleased: VecDeque<(u64, usize)>,
+    /// The total size of the unreleased frames.
+    unreleased_bytes: usize,
+    /// The consumed count last sent to the peer.
+    acked: u64,
+    /// When the stream was dropped, it has nothing more to send.
+    dropped_at: Option<Instant>,
+}
+
+impl Session {
+    /// Drives the session over the given connection and the ones that
+    /// replace it, until the session ends.
+    async fn run(mut self, mut attachment: Attachment, mut reattach: Reattach) {
+        loop {
+            let attachments = match &mut reattach {
+                Reattach::Server { attachments, .. } => Some(attachments),
+                Reattach::Client(_) => None,
+            };
+            match self.attach(attachment, attachments).await {
+                Outcome::Finished => break,
+                Outcome::Replaced(next) => {
+                    attachment = next;
+                    continue;
+                }
+                Outcome::Lost => {}
+                Outcome::Failed(e) => {
+                    self.incoming.send(Err(e)).ok();
+                    break;
+                }
+            }
+            if self.dropped_at.is_some() && self.unacked.is_empty() {
+                break;
+            }
+            match self.reattach(&mut reattach).await {
+                Some(next) => attachment = next,
+                None => {
+                    self.incoming.send(Err(session_lost())).ok();
+                    break;
+                }
+            }
+        }
+        self.shared.credit.close();
+        if let Reattach::Server { sessions, .. } = reattach {
+            sessions.lock().unwrap().remove(&self.id);
+        }
+    }
+
+    /// Waits for a new connection within the grace period.
+    async fn reattach(&mut self, reattach: &mut Reattach) -> Option<Attachment> {
+        let deadline = Instant::now() + self.grace;
+        match reattach {
+            Reattach::Server { attachments, .. } => {
+                tokio::time::timeout_at(deadline, attachments.recv())
+                    .await
+                    .ok()
+                    .flatten()
+            }
+            Reattach::Client(connect) => {
+                let mut delay = MIN_RETRY_DELAY;
+                loop {
+                    let attempt = async {
+                        let mut ws = connect().await?;
+                        let welcome = hello(&mut ws, self.id, self.received).await?;
+                        Ok::<_, Error>(welcome.map(|(_, received)| (ws, received)))
+                    };
+                    match tokio::time::timeout_at(deadline, attempt).await {
+                        Ok(Ok(Some(attachment))) => return Some(attachment),
+                        // The server no longer knows the session.
+                        Ok(Ok(None)) | Err(_) => return None,
+                        Ok(Err(_)) => {}
+                    }
+                    tokio::time::timeout_at(deadline, tokio::time::sleep(delay))
+                        .await
+                        .ok()?;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Runs the session over one connection, replaying what the peer missed.
+    async fn attach(
+        &mut self, (ws, peer_received): Attachment,
+        mut attachments: Option<&mut mpsc::UnboundedReceiver<Attachment>>,
+    ) -> Outcome {
+        let (sink, mut stream) = ws.split();
+        let (frames, mut writer) = spawn_writer(sink);
+
+        if attachments.is_some() {
+            let welcome = Frame::Welcome {
+                id: self.id,
+                received: self.received,
+            };
+            frames.send(Message::Binary(welcome.encode())).ok();
+        }
+        // Frames the peer received but didn't consume yet keep their credit
+        // until it acknowledges them.
+        for (seq, packet) in self
+            .unacked
+            .iter()
+            .skip_while(|(seq, _)| *seq < peer_received)
+        {
+            let frame = Frame::Packet {
+                seq: *seq,
+                packet: packet.clone(),
+            };
+            frames.send(Message::Binary(frame.encode())).ok();
+        }
+        self.acked = 0;
+        self.send_ack(&frames);
+
+        loop {
+            if self.dropped_at.is_some() && self.unacked.is_empty() {
+                return close(frames, writer).await;
+            }
+            let linger = self.dropped_at.map(|at| at + self.grace);
+            tokio::select! {
+                packet = self.outgoing.recv(), if self.dropped_at.is_none() => match packet {
+                    Some(packet) => self.send_packet(&frames, packet),
+                    None => {
+                        // Nothing will consume the rest, so let the peer
+                        // forget about it.
+                        self.dropped_at = Some(Instant::now());
+                        self.shared.consumed.store(self.received, Ordering::Relaxed);
+                        self.send_ack(&frames);
+                    }
+                },
+                _ = self.shared.consumed_changed.notified() => self.send_ack(&frames),
+                msg = stream.next() => match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        if let Err(e) = self.receive(&frames, data) {
+                            return Outcome::Failed(e);
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) => return close(frames, writer).await,
+                    Some(Ok(Message::Others)) => {}
+                    Some(Err(_)) | None => return Outcome::Lost,
+                },
+                _ = &mut writer => return Outcome::Lost,
+                Some(next) = next_attachment(attachments.as_deref_mut()) => {
+                    return Outcome::Replaced(next);
+                }
+                _ = tokio::time::sleep_until(linger.unwrap_or_else(Instant::now)), if linger.is_some() => {
+                    // The peer never acknowledged the rest, give up on it.
+                    return close(frames, writer).await;
+                }
+            }
+        }
+    }
+
+    /// Numbers a frame of the stream, sends it and keeps it for replay.
+    fn send_packet(&mut self, frames: &mpsc::UnboundedSender<Message>, packet: Packet) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let frame = Frame::Packet {
+            seq,
+            packet: packet.clone(),
+        };
+        frames.send(Message::Binary(frame.encode())).ok();
+        self.unacked.push_back((seq, packet));
+    }
+
+    /// Handles a frame received from the peer.
+    fn receive(
+        &mut self, frames: &mpsc::UnboundedSender<Message>, data: Bytes,
+    ) -> Result<(), Error> {
+        match Frame::decode(data)? {
+            Frame::Packet { seq, packet } => {
+                if seq < self.received {
+                    // Replayed after a reconnect, but it arrived before.
+                    return Ok(());
+                }
+                if seq > self.received {
+                    return Err(invalid_frame("frames are missing"));
+                }
+                let len = packet.credit();
+                if self.unreleased_bytes + len > REPLAY_BUFFER {
+                    return Err(invalid_frame("data exceeds the replay buffer"));
+                }
+                self.unreleased.push_back((seq, len));
+                self.unreleased_bytes += len;
+                self.received += 1;
+                if self.incoming.send(Ok(packet)).is_err() {
+                    self.shared.consumed.store(self.received, Ordering::Relaxed);
+                    self.send_ack(frames);
+                }
+            }
+            Frame::Ack { consumed } => self.acknowledge(consumed),
+            Frame::Hello { .. } | Frame::Welcome { .. } => {
+                return Err(invalid_frame("unexpected handshake"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the frames the peer has consumed from the replay buffer, and
+    /// returns their credit.
+    fn acknowledge(&mut self, consumed: u64) {
+        while let Some((seq, packet)) = self.unacked.front()
+            && *seq < consumed
+        {
+            self.shared.credit.add_permits(packet.credit());
+            self.unacked.pop_front();
+        }
+    }
+
+    /// Tells the peer how many of its frames were consumed, if that changed.
+    fn send_ack(&mut self, frames: &mpsc::UnboundedSender<Message>) {
+        let consumed = self.shared.consumed.load(Ordering::Relaxed);
+        if consumed > self.acked {
+            self.acked = consumed;
+            while let Some((seq, len)) = self.unreleased.front()
+                && *seq < consumed
+            {
+                self.unreleased_bytes -= len;
+                self.unreleased.pop_front();
+            }
+            frames
+                .send(Message::Binary(Frame::Ack { consumed }.encode()))
+                .ok();
+        }
+    }
+}
+
+/// Closes the connection of a finished session, after the queued frames.
+async fn close(
+    frames: mpsc::UnboundedSender<Message>, writer: JoinHandle<Result<(), Error>>,
+) -> Outcome {
+    frames.send(Message::Close(None)).ok();
+    drop(frames);
+    tokio::time::timeout(CLOSE_TIMEOUT, writer).await.ok();
+    Outcome::Finished
+}
+
+/// Waits for the next connection of a server session, forever on clients.
+async fn next_attachment(
+    attachments: Option<&mut mpsc::UnboundedReceiver<Attachment>>,
+) -> Option<Attachment> {
+    match attachments {
+        Some(attachments) => attachments.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes frames to a connection in the background, so a slow peer never
+/// holds up reading from it.
+///
+/// The task ends with an error once the connection breaks.
+fn spawn_writer(
+    mut sink: SplitSink<WrappedWsStream, Message>,
+) -> (
+    mpsc::UnboundedSender<Message>,
+    JoinHandle<Result<(), Error>>,
+) {
+    let (frames, mut frames_rx) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = frames_rx.recv().await {
+            sink.feed(msg).await?;
+            while let Ok(msg) = frames_rx.try_recv() {
+                sink.feed(msg).await?;
+            }
+            sink.flush().await?;
+        }
+        sink.close().await
+    });
+    (frames, writer)
+}
+
+/// Sends `HELLO` on a new connection of the client and waits for `WELCOME`.
+///
+/// Returns `None` if the server rejected the session.
+async fn hello(
+    ws: &mut WrappedWsStream, id: u128, received: u64,
+) -> Result<Option<(u128, u64)>, Error> {
+    let hello = Frame::Hello { id, received };
+    ws.send(Message::Binary(hello.encode())).await?;
+    let welcome = tokio::time::timeout(HANDSHAKE_TIMEOUT, ws.next())
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+    match welcome {
+        Some(Ok(Message::Binary(data))) => match Frame::decode(data)? {
+            Frame::Welcome { id, received } => Ok(Some((id, received))),
+            _ => Err(invalid_frame("expected a welcome")),
+        },
+        Some(Ok(Message::Close(_))) => Ok(None),
+        Some(Ok(Message::Others)) => Err(invalid_frame("expected a welcome")),
+        Some(Err(e)) => Err(e),
+        None => Err(connection_lost()),
+    }
+}
+
+/// A resumable session, which survives reconnects of its WebSocket.
+///
+/// It implements `Sink` and `Stream` of messages, so it can be proxied like
+/// any other WebSocket stream. The session is driven by a background task,
+/// which ends once both sides are done, or with an error from the stream if
+/// the connection could not be reattached within the grace period.
+pub struct ResumableStream {
+    id: u128,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedSender<Packet>,
+    incoming: mpsc::UnboundedReceiver<Result<Packet, Error>>,
+    credit: PollSemaphore,
+    pending: Bytes,
+    sent_eof: bool,
+}
+
+impl ResumableStream {
+    /// Starts a new session on a client connection that negotiated
+    /// [`RESUME_PROTOCOL`].
+    ///
+    /// `connect` dials new connections with the same subprotocol once the
+    /// current one breaks, which is retried until `grace` runs out.
+    pub async fn client<F>(
+        mut ws: WrappedWsStream, connect: F, grace: Duration,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut() -> BoxFuture<'static, Result<WrappedWsStream, Error>> + Send + 'static,
+    {
+        let (id, received) = hello(&mut ws, 0, 0)
+            .await?
+            .ok_or_else(|| invalid_frame("new session was rejected"))?;
+        if id == 0 {
+            return Err(invalid_frame("invalid session id"));
+        }
+        Ok(Self::spawn(
+            id,
+            grace,
+            (ws, received),
+            Reattach::Client(Box::new(connect)),
+        ))
+    }
+
+    fn spawn(id: u128, grace: Duration, attachment: Attachment, reattach: Reattach) -> Self {
+        let credit = Arc::new(Semaphore::new(REPLAY_BUFFER));
+        let shared = Arc::new(Shared {
+            credit: credit.clone(),
+            consumed: AtomicU64::new(0),
+            consumed_changed: Notify::new(),
+        });
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let session = Session {
+            id,
+            grace,
+            shared: shared.clone(),
+            outgoing: outgoing_rx,
+            incoming: incoming_tx,
+            unacked: VecDeque::new(),
+            next_seq: 0,
+            received: 0,
+            unreleased: VecDeque::new(),
+            unreleased_bytes: 0,
+            acked: 0,
+            dropped_at: None,
+        };
+        tokio::spawn(session.run(attachment, reattach));
+        Self {
+            id,
+            shared,
+            outgoing,
+            incoming,
+            credit: PollSemaphore::new(credit),
+            pending: Bytes::new(),
+            sent_eof: false,
+        }
+    }
+
+    /// Returns the id of the session, shared by the client and the server.
+    pub fn id(&self) -> u128 {
+        self.id
+    }
+
+    fn queue(&self, packet: Packet) -> Result<(), Error> {
+        self.outgoing.send(packet).map_err(|_| session_lost())
+    }
+
+    /// Marks a frame of the peer as consumed, so it can be acknowledged.
+    fn consume(&self) {
+        self.shared.consumed.fetch_add(1, Ordering::Relaxed);
+        self.shared.consumed_changed.notify_one();
+    }
+}
+
+/// A wrapper around resumable stream that implements `Stream` trait.
+impl Stream for ResumableStream {
+    type Item = Result<Message, Error>;
+
+    /// Polls the next message of the session, acknowledging it to the peer.
+    ///
+    /// The stream ends when the peer sends no more data.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let msg = match futures_util::ready!(self.incoming.poll_recv(cx)) {
+            Some(Ok(Packet::Data(data))) => Some(Ok(Message::Binary(data))),
+            Some(Ok(Packet::Eof)) => None,
+            Some(Ok(Packet::Close(frame))) => Some(Ok(Message::Close(frame))),
+            Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+            None => return Poll::Ready(None),
+        };
+        self.consume();
+        Poll::Ready(msg)
+    }
+}
+
+/// A wrapper around resumable stream that implements `Sink` trait.
+impl Sink<Message> for ResumableStream {
+    type Error = Error;
+
+    /// Polls the session if it is ready to send a message.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    /// Queues a message to be sent on the session.
+    ///
+    /// Empty messages carry no data and are skipped.
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        match item {
+            Message::Binary(data) if data.is_empty() => Ok(()),
+            Message::Binary(data) => {
+                if self.sent_eof {
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+                }
+                self.pending = if self.pending.is_empty() {
+                    data
+                } else {
+                    [self.pending.as_ref(), data.as_ref()].concat().into()
+                };
+                Ok(())
+            }
+            Message::Close(frame) => {
+                self.sent_eof = true;
+                self.queue(Packet::Close(frame))
+            }
+            Message::Others => Ok(()),
+        }
+    }
+
+    /// Sends the queued data as `DATA` frames, as far as the replay buffer
+    /// has room.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        while !self.pending.is_empty() {
+            let len = self.pending.len().min(MAX_FRAME_PAYLOAD);
+            match futures_util::ready!(self.credit.poll_acquire_many(cx, len as u32)) {
+                Some(permit) => permit.forget(),
+                None => return Poll::Ready(Err(session_lost())),
+            }
+            let payload = self.pending.split_to(len);
+            self.queue(Packet::Data(payload))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends the queued data, then tells the peer no more data will follow.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures_util::ready!(self.as_mut().poll_flush(cx))?;
+        if !self.sent_eof {
+            self.sent_eof = true;
+            self.queue(Packet::Eof)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Closes a connection that tried to resume an unknown session.
+async fn reject(mut ws: WrappedWsStream) {
+    let close = CloseFrame::new(CLOSE_UNKNOWN_SESSION, "unknown session");
+    ws.send(Message::Close(Some(close))).await.ok();
+    ws.close().await.ok();
+}
+
+/// The key of each session of a server, and the connections waiting to be
+/// attached to it.
+type Sessions = Arc<Mutex<HashMap<u128, (String, mpsc::UnboundedSender<Attachment>)>>>;
+
+/// The resumable sessions of a server, which reconnecting clients reattach
+/// their new connections to.
+#[derive(Clone)]
+pub struct ResumeRegistry {
+    sessions: Sessions,
+    grace: Duration,
+}
+
+impl ResumeRegistry {
+    /// Creates a registry whose sessions wait `grace` for their clients to
+    /// reconnect.
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            sessions: Default::default(),
+            grace,
+        }
+    }
+
+    /// Returns how many sessions are alive, connected or not.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Returns `true` if no session is alive.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Accepts a connection that negotiated [`RESUME_PROTOCOL`] on the
+    /// tunnel of the key.
+    ///
+    /// Returns the stream of a new session, which should be proxied to a new
+    /// backend connection, or `None` if the connection was reattached to an
+    /// existing session. Connections to unknown sessions, or to sessions of
+    /// another key, are closed with [`CLOSE_UNKNOWN_SESSION`].
+    pub async fn accept(
+        &self, key: &str, mut ws: WrappedWsStream,
+    ) -> Result<Option<ResumableStream>, Error> {
+        let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, ws.next())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+        let (id, received) = match hello {
+            Some(Ok(Message::Binary(data))) => match Frame::decode(data)? {
+                Frame::Hello { id, received } => (id, received),
+                _ => return Err(invalid_frame("expected a hello")),
+            },
+            Some(Err(e)) => return Err(e),
+            _ => return Err(connection_lost()),
+        };
+
+        if id != 0 {
+            let attachments = self
+                .sessions
+                .lock()
+                .unwrap()
+                .get(&id)
+                .filter(|(session_key, _)| session_key == key)
+                .map(|(_, tx)| tx.clone());
+            let rejected = match attachments {
+                Some(tx) => match tx.send((ws, received)) {
+                    Ok(()) => return Ok(None),
+                    // The session ended while the client reconnected.
+                    Err(mpsc::error::SendError((ws, _))) => ws,
+                },
+                None => ws,
+            };
+            reject(rejected).await;
+            return Ok(None);
+        }
+
+        let id = new_session_id()?;
+        let (tx, attachments) = mpsc::unbounded_channel();
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(id, (key.to_owned(), tx));
+        let reattach = Reattach::Server {
+            attachments,
+            sessions: self.sessions.clone(),
+        };
+        Ok(Some(ResumableStream::spawn(
+            id,
+            self.grace,
+            (ws, received),
+            reattach,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes an encoded frame and encodes it again.
+    fn round_trip(frame: Frame) -> Frame {
+        let encoded = frame.encode();
+        let decoded = Frame::decode(encoded.clone()).unwrap();
+        assert_eq!(decoded.encode(), encoded);
+        decoded
+    }
+
+    /// Creates a session with its incoming messages and the frames it sends.
+    fn session() -> (
+        Session,
+        mpsc::UnboundedReceiver<Result<Packet, Error>>,
+        mpsc::UnboundedSender<Message>,
+    ) {
+        let (_, outgoing) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let session = Session {
+            id: 1,
+            grace: DEFAULT_GRACE,
+            shared: Arc::new(Shared {
+                credit: Arc::new(Semaphore::new(REPLAY_BUFFER)),
+                consumed: AtomicU64::new(0),
+                consumed_changed: Notify::new(),
+            }),
+            outgoing,
+            incoming: incoming_tx,
+            unacked: VecDeque::new(),
+            next_seq: 0,
+            received: 0,
+            unreleased: VecDeque::new(),
+            unreleased_bytes: 0,
+            acked: 0,
+            dropped_at: None,
+        };
+        let (frames, _) = mpsc::unbounded_channel();
+        (session, incoming, frames)
+    }
+
+    fn data(seq: u64, len: usize) -> Bytes {
+        Frame::Packet {
+            seq,
+            packet: Packet::Data(Bytes::from(vec![0; len])),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        assert!(matches!(
+            round_trip(Frame::Hello { id: 7, received: 3 }),
+            Frame::Hello { id: 7, received: 3 }
+        ));
+        assert!(matches!(
+            round_trip(Frame::Welcome { id: 7, received: 3 }),
+            Frame::Welcome { id: 7, received: 3 }
+        ));
+        assert!(matches!(
+            round_trip(Frame::Packet { seq: 2, packet: Packet::Data(Bytes::from_static(b"hi")) }),
+            Frame::Packet { seq: 2, packet: Packet::Data(data) } if data == "hi"
+        ));
+        assert!(matches!(
+            round_trip(Frame::Packet {
+                seq: 2,
+                packet: Packet::Eof
+            }),
+            Frame::Packet {
+                seq: 2,
+                packet: Packet::Eof
+            }
+        ));
+        assert!(matches!(
+            round_trip(Frame::Packet {
+                seq: 2,
+                packet: Packet::Close(None)
+            }),
+            Frame::Packet {
+                seq: 2,
+                packet: Packet::Close(None)
+            }
+        ));
+        let close = CloseFrame::new(1000, "bye");
+        assert!(matches!(
+            round_trip(Frame::Packet { seq: 2, packet: Packet::Close(Some(close.clone())) }),
+            Frame::Packet { seq: 2, packet: Packet::Close(Some(frame)) } if frame == close
+        ));
+        assert!(matches!(
+            round_trip(Frame::Ack { consumed: 9 }),
+            Frame::Ack { consumed: 9 }
+        ));
+    }
+
+    #[test]
+    fn truncated_and_invalid_frames_are_rejected() {
+        let hello = Frame::Hello { id: 7, received: 3 }.encode();
+        let close = Frame::Packet {
+            seq: 2,
+            packet: Packet::Close(None),
+        }
+        .encode();
+        for frame in [
+            Bytes::new(),
+            hello.slice(..hello.len() - 1),
+            data(2, 0).slice(..8),
+            [close.as_ref(), &[3]].concat().into(),
+            [close.as_ref(), &[3, 232, 0xFF]].concat().into(),
+            Bytes::from_static(&[9, 0, 0, 0, 0, 0, 0, 0, 0]),
+        ] {
+            assert!(Frame::decode(frame).is_err());
+        }
+    }
+
+    #[test]
+    fn frames_out_of_order_fail_the_session() {
+        let (mut session, _incoming, frames) = session();
+        assert!(session.receive(&frames, data(1, 1)).is_err());
+    }
+
+    #[test]
+    fn replayed_frames_are_dropped() {
+        let (mut session, mut incoming, frames) = session();
+        session.receive(&frames, data(0, 1)).unwrap();
+        session.receive(&frames, data(0, 1)).unwrap();
+        session.receive(&frames, data(1, 1)).unwrap();
+        assert_eq!(session.received, 2);
+        assert!(incoming.try_recv().is_ok());
+        assert!(incoming.try_recv().is_ok());
+        assert!(incoming.try_recv().is_err());
+    }
+
+    #[test]
+    fn data_past_the_credit_fails_the_session() {
+        let (mut session, _incoming, frames) = session();
+        let frames_per_buffer = REPLAY_BUFFER / MAX_FRAME_PAYLOAD;
+        for seq in 0..frames_per_buffer as u64 {
+            session
+                .receive(&frames, data(seq, MAX_FRAME_PAYLOAD))
+                .unwrap();
+        }
+        let next = frames_per_buffer as u64;
+        assert!(session.receive(&frames, data(next, 1)).is_err());
+
+        // Consuming a frame returns its credit once the peer is told.
+        session.shared.consumed.store(1, Ordering::Relaxed);
+        session.send_ack(&frames);
+        session
+            .receive(&frames, data(next, MAX_FRAME_PAYLOAD))
+            .unwrap();
+        assert!(session.receive(&frames, data(next + 1, 1)).is_err());
+    }
+
+    #[test]
+    fn acknowledged_frames_return_their_credit() {
+        let (mut session, _incoming, _frames) = session();
+        session.shared.credit.forget_permits(3);
+        session
+            .unacked
+            .push_back((0, Packet::Data(Bytes::from_static(b"abc"))));
+        session.unacked.push_back((1, Packet::Eof));
+        session.acknowledge(1);
+        assert_eq!(session.shared.credit.available_permits(), REPLAY_BUFFER);
+        assert_eq!(session.unacked.len(), 1);
+    }
+
+    /// Receives the next resume frame from a connection.
+    #[cfg(feature = "client")]
+    async fn next_frame(ws: &mut WrappedWsStream) -> Frame {
+        Frame::decode(crate::proxy::tests::binary(ws.next().await)).unwrap()
+    }
+
+    /// Starts a session on a registry from a hand-driven client connection.
+    #[cfg(feature = "client")]
+    async fn start(registry: &ResumeRegistry) -> (WrappedWsStream, ResumableStream, u128) {
+        let (mut client, server) = crate::proxy::tests::ws_pair().await;
+        let hello = Frame::Hello { id: 0, received: 0 };
+        client.send(Message::Binary(hello.encode())).await.unwrap();
+        let stream = registry.accept("key", server).await.unwrap().unwrap();
+        let id = match next_frame(&mut client).await {
+            Frame::Welcome { id, .. } => id,
+            _ => panic!("expected a welcome"),
+        };
+        assert_eq!(id, stream.id());
+        (client, stream, id)
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn unacknowledged_data_is_replayed_to_a_new_connection() {
+        let registry = ResumeRegistry::new(DEFAULT_GRACE);
+        let (mut first, mut stream, id) = start(&registry).await;
+        stream
+            .send(Message::Binary(Bytes::from_static(b"data")))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_frame(&mut first).await,
+            Frame::Packet { seq: 0, packet: Packet::Data(data) } if data == "data"
+        ));
+
+        // The data was never acknowledged, so it comes again.
+        let (mut second, server) = crate::proxy::tests::ws_pair().await;
+        let hello = Frame::Hello { id, received: 0 };
+        second.send(Message::Binary(hello.encode())).await.unwrap();
+        assert!(registry.accept("key", server).await.unwrap().is_none());
+        assert!(matches!(
+            next_frame(&mut second).await,
+            Frame::Welcome { id: welcome, received: 0 } if welcome == id
+        ));
+        assert!(matches!(
+            next_frame(&mut second).await,
+            Frame::Packet { seq: 0, packet: Packet::Data(data) } if data == "data"
+        ));
+
+        let data = Frame::Packet {
+            seq: 0,
+            packet: Packet::Data(Bytes::from_static(b"back")),
+        };
+        second.send(Message::Binary(data.encode())).await.unwrap();
+        assert_eq!(
+            crate::proxy::tests::binary(stream.next().await),
+            Bytes::from_static(b"back")
+        );
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn sessions_of_another_key_are_rejected() {
+        let registry = ResumeRegistry::new(DEFAULT_GRACE);
+        let (_first, _stream, id) = start(&registry).await;
+
+        let (mut other, server) = crate::proxy::tests::ws_pair().await;
+        let hello = Frame::Hello { id, received: 0 };
+        other.send(Message::Binary(hello.encode())).await.unwrap();
+        assert!(registry.accept("other", server).await.unwrap().is_none());
+        assert!(matches!(
+            other.next().await,
+            Some(Ok(Message::Close(Some(frame)))) if frame.code == CLOSE_UNKNOWN_SESSION
+        ));
+    }
+}
@@ -0,0 +1,229 @@
+//! Traffic statistics for WebSocket Reflector X.
+//!
+//! The two streams of a proxied session are called "first" and "second" in
+//! [`proxy_stream`](crate::proxy::proxy_stream), the first one is the
+//! WebSocket in every helper of this crate. Traffic read from the WebSocket is
+//! counted as `inbound`, traffic written to it as `outbound`.
+
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::proxy::CloseFrame;
+
+/// Which side of a proxied session finished first.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize))]
+#[cfg_attr(feature = "binary", serde(rename_all = "snake_case"))]
+pub enum ClosedBy {
+    /// The first stream (the WebSocket) ended first.
+    WebSocket,
+    /// The second stream (the TCP connection, UDP socket, ...) ended first.
+    Stream,
+    /// The session was cancelled by its cancellation token.
+    Cancelled,
+}
+
+/// The statistics of a finished proxied session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize))]
+pub struct TrafficStats {
+    /// Bytes read from the WebSocket and written to the stream.
+    pub inbound_bytes: u64,
+    /// Bytes read from the stream and written to the WebSocket.
+    pub outbound_bytes: u64,
+    /// Messages read from the WebSocket and written to the stream.
+    pub inbound_messages: u64,
+    /// Messages read from the stream and written to the WebSocket.
+    pub outbound_messages: u64,
+    /// Data bytes of the WebSocket before compression, in both directions,
+    /// if it compresses and is owned by the session.
+    pub uncompressed_bytes: u64,
+    /// The same data as carried by the WebSocket, after compression.
+    pub compressed_bytes: u64,
+    /// How long the session lasted.
+    pub duration: Duration,
+    /// Which side finished first, by closing its connection or by no
+    /// longer sending data.
+    pub closed_by: ClosedBy,
+    /// The close frame the session ended with.
+    pub close_frame: Option<CloseFrame>,
+}
+
+impl TrafficStats {
+    /// How many times smaller compression made the data of the session,
+    /// `None` if nothing was compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.uncompressed_bytes, self.compressed_bytes)
+    }
+}
+
+/// A point-in-time copy of a [`TrafficCounter`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize))]
+pub struct TrafficSnapshot {
+    /// Bytes read from the WebSocket and written to the stream.
+    pub inbound_bytes: u64,
+    /// Bytes read from the stream and written to the WebSocket.
+    pub outbound_bytes: u64,
+    /// Messages read from the WebSocket and written to the stream.
+    pub inbound_messages: u64,
+    /// Messages read from the stream and written to the WebSocket.
+    pub outbound_messages: u64,
+    /// Sessions that are still open.
+    pub active_sessions: u64,
+    /// Sessions that were ever opened.
+    pub total_sessions: u64,
+    /// Sessions that got a pre-warmed WebSocket from the pool.
+    pub pool_hits: u64,
+    /// Sessions that found the pool empty and connected a new WebSocket.
+    pub pool_misses: u64,
+    /// Data bytes of compressing WebSockets before compression, in both
+    /// directions.
+    pub uncompressed_bytes: u64,
+    /// The same data as carried by the WebSockets, after compression.
+    pub compressed_bytes: u64,
+}
+
+impl TrafficSnapshot {
+    /// How many times smaller compression made the data, `None` if nothing
+    /// was compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.uncompressed_bytes, self.compressed_bytes)
+    }
+}
+
+fn ratio(uncompressed: u64, compressed: u64) -> Option<f64> {
+    (compressed > 0).then(|| uncompressed as f64 / compressed as f64)
+}
+
+tokio::task_local! {
+    /// The counter of the session proxied by the current task.
+    static SESSION: TrafficCounter;
+}
+
+/// Counts data before and after compression into the session proxied by the
+/// current task, if any.
+///
+/// WebSockets are polled by the task proxying their session, unless they
+/// are shared like mux connections, whose compression is only counted for
+/// the tunnel.
+pub(crate) fn add_session_compression(uncompressed: usize, compressed: usize) {
+    SESSION
+        .try_with(|session| session.add_compression(uncompressed, compressed))
+        .ok();
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    inbound_bytes: AtomicU64,
+    outbound_bytes: AtomicU64,
+    inbound_messages: AtomicU64,
+    outbound_messages: AtomicU64,
+    active_sessions: AtomicU64,
+    total_sessions: AtomicU64,
+    pool_hits: AtomicU64,
+    pool_misses: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+/// A live, shared traffic counter.
+///
+/// Cloning the counter yields a handle to the same counters, so it can be
+/// handed to many sessions and read while they are still open.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficCounter {
+    inner: Arc<Counters>,
+}
+
+impl TrafficCounter {
+    /// Creates a new, zeroed `TrafficCounter`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the current counters.
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        let c = &self.inner;
+        TrafficSnapshot {
+            inbound_bytes: c.inbound_bytes.load(Ordering::Relaxed),
+            outbound_bytes: c.outbound_bytes.load(Ordering::Relaxed),
+            inbound_messages: c.inbound_messages.load(Ordering::Relaxed),
+            outbound_messages: c.outbound_messages.load(Ordering::Relaxed),
+            active_sessions: c.active_sessions.load(Ordering::Relaxed),
+            total_sessions: c.total_sessions.load(Ordering::Relaxed),
+            pool_hits: c.pool_hits.load(Ordering::Relaxed),
+            pool_misses: c.pool_misses.load(Ordering::Relaxed),
+            uncompressed_bytes: c.uncompressed_bytes.load(Ordering::Relaxed),
+            compressed_bytes: c.compressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn add_inbound(&self, bytes: usize) {
+        self.inner
+            .inbound_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.inbound_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_outbound(&self, bytes: usize) {
+        self.inner
+            .outbound_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.outbound_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "client")]
+    pub(crate) fn add_pool_hit(&self) {
+        self.inner.pool_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "client")]
+    pub(crate) fn add_pool_miss(&self) {
+        self.inner.pool_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_compression(&self, uncompressed: usize, compressed: usize) {
+        self.inner
+            .uncompressed_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.inner
+            .compressed_bytes
+            .fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    /// Runs the proxying of a session, whose compression is counted into
+    /// this counter by [`add_session_compression`].
+    pub(crate) async fn scope<F: Future>(&self, session: F) -> F::Output {
+        SESSION.scope(self.clone(), session).await
+    }
+
+    /// Marks a session as open until the returned guard is dropped.
+    pub(crate) fn open_session(&self) -> SessionGuard {
+        self.inner.active_sessions.fetch_add(1, Ordering::Relaxed);
+        self.inner.total_sessions.fetch_add(1, Ordering::Relaxed);
+        SessionGuard {
+            counter: self.clone(),
+        }
+    }
+}
+
+/// Keeps a session counted as active while alive.
+pub(crate) struct SessionGuard {
+    counter: TrafficCounter,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.counter
+            .inner
+            .active_sessions
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,303 @@
+//! TLS options of outgoing `wss://` connections.
+//!
+//! By default remotes are verified against the native root certificates.
+//! [`TlsOptions`] adds CA bundles, a client certificate for mTLS, public key
+//! pinning, an SNI override and an explicit insecure mode.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{
+        CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+/// The prefix of pinned keys in curl's `--pinnedpubkey` format.
+const PIN_PREFIX: &str = "sha256//";
+
+/// The client configurations built so far, shared by every connection with
+/// the same options.
+static CLIENT_CONFIGS: LazyLock<Mutex<HashMap<TlsOptions, Arc<ClientConfig>>>> =
+    LazyLock::new(Default::default);
+
+/// TLS options of outgoing `wss://` connections.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "binary", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "binary", serde(default))]
+pub struct TlsOptions {
+    /// PEM files of extra CA certificates, trusted besides the native roots.
+    pub ca_files: Vec<PathBuf>,
+    /// A PEM file of the client certificate chain, sent to remotes that ask
+    /// for one.
+    pub client_cert: Option<PathBuf>,
+    /// A PEM file of the private key of the client certificate.
+    pub client_key: Option<PathBuf>,
+    /// Base64 SHA-256 hashes of the accepted public keys (SPKI) of the
+    /// remote, the certificate must match one of them if any are set.
+    pub pinned_keys: Vec<String>,
+    /// The name sent as SNI and verified against the certificate instead of
+    /// the host of the url.
+    pub server_name: Option<String>,
+    /// Skip verifying the certificate chain and name of the remote, pinned
+    /// keys are still checked.
+    pub insecure: bool,
+}
+
+impl TlsOptions {
+    /// Returns `true` if the options don't change the default TLS setup.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Checks options that come from an untrusted caller, such as a web page
+    /// talking to a local HTTP API.
+    ///
+    /// Such options may pin keys and override the server name, but reading
+    /// local files and skipping verification are left to the user.
+    pub fn check_untrusted(&self) -> Result<(), &'static str> {
+        if self.insecure {
+            return Err("insecure TLS can't be enabled through the API");
+        }
+        if !self.ca_files.is_empty() || self.client_cert.is_some() || self.client_key.is_some() {
+            return Err("TLS files can't be set through the API");
+        }
+        Ok(())
+    }
+
+    /// Returns the rustls client configuration of the options.
+    ///
+    /// It is built once per set of options and shared, so the native roots
+    /// and the files are only read by the first connection.
+    pub fn client_config(&self) -> io::Result<Arc<ClientConfig>> {
+        if let Some(config) = CLIENT_CONFIGS.lock().unwrap().get(self) {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build_client_config()?);
+        CLIENT_CONFIGS
+            .lock()
+            .unwrap()
+            .insert(self.clone(), config.clone());
+        Ok(config)
+    }
+
+    fn build_client_config(&self) -> io::Result<ClientConfig> {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+
+        let webpki = if self.insecure {
+            None
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            for path in &self.ca_files {
+                let certs = CertificateDer::pem_file_iter(path)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| file_error(path, e))?;
+                roots.add_parsable_certificates(certs);
+            }
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(roots.into(), provider.clone())
+                    .build()
+                    .map_err(io::Error::other)?;
+            Some(verifier)
+        };
+        let server_name = match &self.server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.clone())
+                    .map_err(|_| invalid_input(format!("invalid TLS server name `{name}`")))?,
+            ),
+            None => None,
+        };
+        let pins = self
+            .pinned_keys
+            .iter()
+            .map(|pin| parse_pin(pin).map_err(invalid_input))
+            .collect::<io::Result<_>>()?;
+        let verifier = Verifier {
+            webpki,
+            server_name,
+            pins,
+            algorithms: provider.signature_verification_algorithms,
+        };
+
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = CertificateDer::pem_file_iter(cert)
+                    .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| file_error(cert, e))?;
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| file_error(key, e))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(io::Error::other)
+            }
+            (None, None) => Ok(builder.with_no_client_auth()),
+            _ => Err(invalid_input(
+                "the client certificate and key must be set together",
+            )),
+        }
+    }
+
+    /// Performs the TLS handshake with the remote `host` over the stream,
+    /// with a configuration built by [`TlsOptions::client_config`].
+    pub(crate) async fn connect(
+        &self, config: Arc<ClientConfig>, host: &str, stream: TcpStream,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let name = self.server_name.as_deref().unwrap_or(host).to_string();
+        let name = ServerName::try_from(name)
+            .map_err(|e| invalid_input(format!("invalid TLS server name: {e}")))?;
+        TlsConnector::from(config).connect(name, stream).await
+    }
+}
+
+/// Parses a pinned key, the base64 SHA-256 hash of a public key with an
+/// optional `sha256//` prefix.
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], String> {
+    let hash = pin.trim();
+    let hash = hash.strip_prefix(PIN_PREFIX).unwrap_or(hash);
+    BASE64
+        .decode(hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| format!("invalid pinned key `{pin}`, expected a base64 SHA-256 hash"))
+}
+
+/// Verifies remotes with webpki unless insecure, then checks the pinned keys.
+#[derive(Debug)]
+struct Verifier {
+    /// Verifies the chain and name of the certificate, `None` if insecure.
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    /// The name to verify instead of the one connected to.
+    server_name: Option<ServerName<'static>>,
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            let server_name = self.server_name.as_ref().unwrap_or(server_name);
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.is_empty() {
+            let cert = webpki::EndEntityCert::try_from(end_entity).map_err(|_| {
+                rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+            })?;
+            let spki = cert.subject_public_key_info();
+            let hash = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+            if !self.pins.iter().any(|pin| pin == hash.as_ref()) {
+                return Err(rustls::Error::General(format!(
+                    "certificate key {PIN_PREFIX}{} is not pinned",
+                    BASE64.encode(hash)
+                )));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+fn file_error(path: &std::path::Path, e: impl std::fmt::Display) -> io::Error {
+    invalid_input(format!("failed to load {}: {e}", path.display()))
+}
+
+fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_config_is_built_once_per_options() {
+        let options = TlsOptions {
+            server_name: Some(String::from("example.com")),
+            ..Default::default()
+        };
+        let config = options.client_config().unwrap();
+        assert!(Arc::ptr_eq(
+            &config,
+            &options.clone().client_config().unwrap()
+        ));
+
+        let other = TlsOptions {
+            insecure: true,
+            ..options
+        };
+        assert!(!Arc::ptr_eq(&config, &other.client_config().unwrap()));
+    }
+
+    #[test]
+    fn untrusted_options_may_not_read_files_or_skip_verification() {
+        let pinned = TlsOptions {
+            pinned_keys: vec![BASE64.encode([0; 32])],
+            server_name: Some(String::from("example.com")),
+            ..Default::default()
+        };
+        assert!(pinned.check_untrusted().is_ok());
+
+        let insecure = TlsOptions {
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(insecure.check_untrusted().is_err());
+
+        let files = TlsOptions {
+            ca_files: vec![PathBuf::from("/etc/passwd")],
+            ..Default::default()
+        };
+        assert!(files.check_untrusted().is_err());
+    }
+
+    #[test]
+    fn pins_accept_the_curl_prefix() {
+        let hash = BASE64.encode([7; 32]);
+        assert_eq!(parse_pin(&hash).unwrap(), [7; 32]);
+        assert_eq!(parse_pin(&format!("{PIN_PREFIX}{hash}")).unwrap(), [7; 32]);
+        assert!(parse_pin("not a hash").is_err());
+    }
+}
@@ -1,19 +1,609 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
+use futures_util::{sink::Sink, stream::Stream};
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, task::JoinHandle};
-use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    sync::{Semaphore, broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_util::{bytes::Bytes, sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info};
 
-use super::proxy;
+#[cfg(unix)]
+use crate::unix::UNIX_PREFIX;
+use crate::{
+    Error, FrameEncoding, FrameLimits, Keepalive, Message,
+    capture::{Capture, CaptureSession, Captured},
+    datagram::{self, DatagramStream, MAX_DATAGRAM_SIZE, UDP_PREFIX},
+    deflate::{self, Compression},
+    encrypt::{self, Cipher, EncryptionKey, KeyExchange},
+    handshake::{self, Handshake},
+    idle::IdleTimeout,
+    limit::{RateLimited, RateLimits},
+    mux::MuxClient,
+    observe::Observer,
+    pool::{self, RawWebSocket, WsPool},
+    proxy::{WrappedWsStream, proxy_stream_observed},
+    resume::{DEFAULT_GRACE, RESUME_PROTOCOL, ResumableStream},
+    stats::{TrafficCounter, TrafficSnapshot, TrafficStats},
+    tls::TlsOptions,
+    upstream::{DEFAULT_CONNECT_TIMEOUT, Upstream, connect_websocket},
+};
+
+/// How many datagrams of a single peer can be queued before they are dropped.
+const UDP_PEER_QUEUE: usize = 256;
+
+/// The delay before the first retry of a failed connection, doubled for each
+/// further retry.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The longest delay between retries of a failed connection.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// How many events are kept for subscribers that fall behind.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// How long a tunnel that is shut down waits for its sessions by default.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// The largest pool a configuration from an untrusted caller may ask for.
+pub const MAX_UNTRUSTED_POOL_SIZE: usize = 8;
 
 /// Configuration for a tunnel, contains the local and remote addresses.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Local addresses prefixed with `udp:` (e.g. `udp:127.0.0.1:5353`) listen
+/// for UDP datagrams instead of TCP connections, and those prefixed with
+/// `unix:` (e.g. `unix:/run/wsrx/pwn.sock`) listen on a Unix socket.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TunnelConfig {
     #[serde(alias = "from")]
     pub local: String,
     #[serde(alias = "to")]
     pub remote: String,
+    /// Multiplex all TCP connections over one WebSocket connection, if the
+    /// remote server supports it.
+    #[serde(default)]
+    pub mux: bool,
+    /// Keep each TCP connection alive across reconnects of its WebSocket, if
+    /// the remote server supports it. Ignored with `mux`.
+    #[serde(default)]
+    pub resume: bool,
+    /// The upload rate limit in bytes per second, shared by all connections
+    /// of the tunnel.
+    #[serde(default)]
+    pub upload_limit: Option<u64>,
+    /// The download rate limit in bytes per second, shared by all
+    /// connections of the tunnel.
+    #[serde(default)]
+    pub download_limit: Option<u64>,
+    /// How data is carried in WebSocket frames, must match the remote.
+    #[serde(default)]
+    pub encoding: FrameEncoding,
+    /// Size limits of the WebSocket messages and of the data buffered by
+    /// each connection.
+    #[serde(default)]
+    pub frame_limits: FrameLimits,
+    /// The time between keepalive pings on each WebSocket connection, pings
+    /// are disabled if not set. Serialized as seconds, which may have a
+    /// fraction.
+    #[serde(default, with = "secs")]
+    pub ping_interval: Option<Duration>,
+    /// How many pings in a row may go unanswered before the connection is
+    /// dropped, three if not set.
+    #[serde(default)]
+    pub max_missed_pongs: Option<u32>,
+    /// The octal file mode of a Unix socket listener (e.g. `"660"`), `600`
+    /// if not set.
+    #[serde(default, with = "unix_mode")]
+    pub unix_mode: Option<u32>,
+    /// Extra headers, cookies and subprotocols sent with the handshake of
+    /// each WebSocket connection, never serialized as they may hold secrets.
+    #[serde(default, skip_serializing)]
+    pub handshake: Handshake,
+    /// The upstream proxy of the WebSocket connections, e.g.
+    /// `socks5h://127.0.0.1:1080`, or `direct` to bypass the global and
+    /// environment proxies.
+    #[serde(default)]
+    pub proxy: Upstream,
+    /// TLS options of `wss://` remotes.
+    #[serde(default)]
+    pub tls: TlsOptions,
+    /// How many handshaken WebSocket connections are kept ready for new TCP
+    /// and Unix socket connections, the pool is disabled if not set or with
+    /// `mux` or `resume`.
+    #[serde(default)]
+    pub pool_size: Option<usize>,
+    /// How long an idle pooled connection is kept before it is replaced, 300
+    /// seconds if not set. Serialized as seconds, which may have a fraction.
+    #[serde(default, with = "secs")]
+    pub pool_idle_timeout: Option<Duration>,
+    /// Compress the data of each WebSocket connection if the remote supports
+    /// it, compression is disabled if not set or with the `text` encoding.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Encrypt the data of each WebSocket connection end to end with a key
+    /// shared with the remote, which must use the same key. Can't be used
+    /// with the `text` encoding, never serialized.
+    #[serde(default, skip_serializing)]
+    pub encryption: Option<EncryptionKey>,
+    /// How long a WebSocket handshake may take before it fails, 10 seconds
+    /// if not set. Serialized as seconds, which may have a fraction.
+    #[serde(default, with = "secs")]
+    pub connect_timeout: Option<Duration>,
+    /// How many times connecting a new connection to the remote is retried,
+    /// with an exponential backoff, before it is dropped. Only failures that
+    /// may go away are retried, like refused connections, timeouts and
+    /// server errors, but not rejections by the remote or TLS errors.
+    #[serde(default)]
+    pub connect_retries: Option<u32>,
+    /// How many connections or UDP peers are proxied at once, further
+    /// connections wait to be accepted and the datagrams of further peers
+    /// are dropped. Unlimited if not set.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// How long a connection may pass no data in either direction before it
+    /// is closed, never if not set, except for UDP peers which are closed
+    /// after [`datagram::DEFAULT_IDLE_TIMEOUT`]. Serialized as seconds, which
+    /// may have a fraction.
+    #[serde(default, with = "secs")]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Serializes a file mode as an octal string.
+mod unix_mode {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+        match mode {
+            Some(mode) => serializer.serialize_some(&format!("{mode:o}")),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|mode| crate::unix::parse_mode(&mode).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// Serializes a duration as seconds, with a fraction only if needed.
+mod secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) if duration.subsec_nanos() == 0 => {
+                serializer.serialize_some(&duration.as_secs())
+            }
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|secs| Duration::try_from_secs_f64(secs).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+impl TunnelConfig {
+    /// Checks a configuration that comes from an untrusted caller, such as a
+    /// web page talking to a local HTTP API.
+    ///
+    /// Such a configuration may pick its remote, proxy and TLS pins, but Unix
+    /// sockets, frame limits, pools larger than [`MAX_UNTRUSTED_POOL_SIZE`]
+    /// and the local TLS files are left to the user.
+    pub fn check_untrusted(&self) -> Result<(), &'static str> {
+        self.tls.check_untrusted()?;
+        if self.local.starts_with(crate::unix::UNIX_PREFIX) || self.unix_mode.is_some() {
+            return Err("unix sockets can't be listened on through the API");
+        }
+        if self.frame_limits != FrameLimits::default() {
+            return Err("frame limits can't be set through the API");
+        }
+        if self
+            .pool_size
+            .is_some_and(|size| size > MAX_UNTRUSTED_POOL_SIZE)
+        {
+            return Err("the pool size is too large to be set through the API");
+        }
+        Ok(())
+    }
+
+    /// Returns the keepalive pings of the configuration, if enabled.
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        let keepalive = Keepalive::new(self.ping_interval.filter(|interval| !interval.is_zero())?);
+        Some(match self.max_missed_pongs {
+            Some(max_missed) => keepalive.with_max_missed(max_missed),
+            None => keepalive,
+        })
+    }
+
+    /// Returns how long a WebSocket handshake may take.
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+            .filter(|timeout| !timeout.is_zero())
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    /// Returns how long a connection may stay idle, if limited.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.filter(|timeout| !timeout.is_zero())
+    }
+
+    /// Connects with `connect`, retrying failures that may go away up to
+    /// [`TunnelConfig::connect_retries`] times with an exponential backoff.
+    ///
+    /// Gives up with the last error once the token is cancelled.
+    pub async fn retry<T, F, Fut>(
+        &self, token: &CancellationToken, mut connect: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut retries = self.connect_retries.unwrap_or(0);
+        let mut delay = RETRY_DELAY;
+        loop {
+            match connect().await {
+                Err(e) if retries > 0 && is_transient(&e) => {
+                    debug!(
+                        "Failed to connect to {}: {e}, retrying in {delay:?}",
+                        self.remote
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = token.cancelled() => return Err(e),
+                    }
+                    retries -= 1;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Connects a new WebSocket to the remote of the configuration, with its
+    /// handshake data, frame limits, encoding, compression and keepalive
+    /// pings.
+    pub async fn connect(&self) -> Result<WrappedWsStream, Error> {
+        self.connect_with_counter(None).await
+    }
+
+    /// Connects a new WebSocket like [`TunnelConfig::connect`], counting its
+    /// compression into the counter.
+    pub(crate) async fn connect_with_counter(
+        &self, counter: Option<&TrafficCounter>,
+    ) -> Result<WrappedWsStream, Error> {
+        let (ws, negotiated) = self.open_websocket().await?;
+        Ok(self.wrap(ws, negotiated, counter))
+    }
+
+    /// Starts a resumable session with the remote, which reconnects its
+    /// WebSocket when it breaks.
+    ///
+    /// Returns `None` if the remote doesn't support resumable sessions.
+    pub async fn connect_resumable(&self) -> Result<Option<ResumableStream>, Error> {
+        self.connect_resumable_with_counter(None).await
+    }
+
+    /// Starts a resumable session like [`TunnelConfig::connect_resumable`],
+    /// counting the compression of its WebSockets into the counter.
+    pub(crate) async fn connect_resumable_with_counter(
+        &self, counter: Option<&TrafficCounter>,
+    ) -> Result<Option<ResumableStream>, Error> {
+        let Some(ws) = self.connect_with_protocol(RESUME_PROTOCOL, counter).await? else {
+            return Ok(None);
+        };
+        let config = Arc::new(self.clone());
+        let counter = counter.cloned();
+        let connect = move || {
+            let config = config.clone();
+            let counter = counter.clone();
+            Box::pin(async move {
+                config
+                    .connect_with_protocol(RESUME_PROTOCOL, counter.as_ref())
+                    .await?
+                    .ok_or_else(|| {
+                        std::io::Error::other("remote no longer supports resumable sessions").into()
+                    })
+            }) as _
+        };
+        ResumableStream::client(ws, connect, DEFAULT_GRACE)
+            .await
+            .map(Some)
+    }
+
+    /// Connects a new WebSocket that offers the subprotocol before the
+    /// configured ones, `None` if the remote picked none or another one.
+    pub(crate) async fn connect_with_protocol(
+        &self, protocol: &str, counter: Option<&TrafficCounter>,
+    ) -> Result<Option<WrappedWsStream>, Error> {
+        use tokio_tungstenite::tungstenite::{
+            Error as TgError,
+            error::{ProtocolError, SubProtocolError},
+        };
+
+        let (request, exchange) = self.request(&[protocol])?;
+        let ws_config = self.frame_limits.websocket_config();
+        let timeout = self.connect_timeout();
+        match connect_websocket(request, &self.proxy, &self.tls, ws_config, timeout).await {
+            Ok((ws, response)) if handshake::accepted_protocol(&response, protocol) => {
+                let negotiated = Negotiated::new(&response, exchange)?;
+                Ok(Some(self.wrap(ws, negotiated, counter)))
+            }
+            Ok(_)
+            | Err(TgError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+                SubProtocolError::NoSubProtocol,
+            ))) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Performs the WebSocket handshake with the remote, without wrapping
+    /// the connection yet.
+    ///
+    /// Returns the connection and what the remote agreed to.
+    pub(crate) async fn open_websocket(&self) -> Result<(RawWebSocket, Negotiated), Error> {
+        let (request, exchange) = self.request(&[])?;
+        let ws_config = self.frame_limits.websocket_config();
+        let timeout = self.connect_timeout();
+        let (ws, response) =
+            connect_websocket(request, &self.proxy, &self.tls, ws_config, timeout).await?;
+        Ok((ws, Negotiated::new(&response, exchange)?))
+    }
+
+    /// Performs the WebSocket handshake like
+    /// [`TunnelConfig::open_websocket`], asking the remote to connect its
+    /// backend only once the connection is attached.
+    ///
+    /// Returns `None` if the remote doesn't support pooled connections, the
+    /// connection is closed then.
+    pub(crate) async fn open_pooled_websocket(
+        &self,
+    ) -> Result<Option<(RawWebSocket, Negotiated)>, Error> {
+        let (mut request, exchange) = self.request(&[])?;
+        pool::offer(&mut request);
+        let ws_config = self.frame_limits.websocket_config();
+        let timeout = self.connect_timeout();
+        let (mut ws, response) =
+            connect_websocket(request, &self.proxy, &self.tls, ws_config, timeout).await?;
+        if !pool::accepted(&response) {
+            ws.close(None).await.ok();
+            return Ok(None);
+        }
+        Ok(Some((ws, Negotiated::new(&response, exchange)?)))
+    }
+
+    /// Returns the compression of the configuration, if enabled and usable
+    /// with its encoding.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+            .filter(|_| self.encoding != FrameEncoding::Text)
+    }
+
+    /// Builds the handshake request, offering the subprotocols before the
+    /// configured ones and asking for compression and encryption if enabled.
+    ///
+    /// Returns the request and the key exchange to finish with the response.
+    fn request(
+        &self, protocols: &[&str],
+    ) -> Result<
+        (
+            tokio_tungstenite::tungstenite::handshake::client::Request,
+            Option<KeyExchange>,
+        ),
+        Error,
+    > {
+        let mut request = self
+            .handshake
+            .request_with_protocols(&self.remote, protocols)?;
+        if self.compression().is_some() {
+            deflate::offer(&mut request);
+        }
+        let exchange = match &self.encryption {
+            Some(_) if self.encoding == FrameEncoding::Text => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "encryption can't be used with the text encoding",
+                )
+                .into());
+            }
+            Some(key) => Some(encrypt::offer(key, &mut request)?),
+            None => None,
+        };
+        Ok((request, exchange))
+    }
+
+    /// Wraps a handshaken connection with the encoding, compression if the
+    /// remote agreed to it, encryption and keepalive pings.
+    pub(crate) fn wrap(
+        &self, ws: RawWebSocket, negotiated: Negotiated, counter: Option<&TrafficCounter>,
+    ) -> WrappedWsStream {
+        let deflate = self
+            .compression()
+            .filter(|_| negotiated.deflate)
+            .map(|compression| {
+                compression
+                    .deflate(&self.frame_limits)
+                    .with_counter(counter.cloned())
+            });
+        WrappedWsStream::from(ws)
+            .with_encoding(self.encoding)
+            .with_deflate(deflate)
+            .with_cipher(negotiated.cipher)
+            .with_keepalive(self.keepalive())
+    }
+}
+
+/// Returns whether connecting again may succeed, only network failures,
+/// timeouts and server errors are transient. Rejections by the remote, TLS
+/// errors and invalid configurations are final.
+fn is_transient(e: &Error) -> bool {
+    use tokio_tungstenite::tungstenite::{Error as TgError, error::ProtocolError};
+
+    match e {
+        Error::WebSocket(TgError::Http(response)) => response.status().is_server_error(),
+        Error::WebSocket(
+            TgError::ConnectionClosed
+            | TgError::AlreadyClosed
+            | TgError::Protocol(ProtocolError::HandshakeIncomplete),
+        ) => true,
+        Error::WebSocket(TgError::Io(e)) | Error::Io(e) => is_transient_io(e),
+        _ => false,
+    }
+}
+
+/// Returns whether an IO error may go away, like a refused connection or a
+/// timeout.
+fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown
+    )
+}
+
+/// What the remote agreed to in the handshake of a connection.
+#[derive(Debug)]
+pub(crate) struct Negotiated {
+    /// The remote compresses data.
+    deflate: bool,
+    /// The encryption of the connection, if enabled.
+    cipher: Option<Cipher>,
+}
+
+impl Negotiated {
+    /// Reads the answers of the remote from the handshake response, failing
+    /// if it didn't agree to encrypt.
+    fn new(
+        response: &tokio_tungstenite::tungstenite::handshake::client::Response,
+        exchange: Option<KeyExchange>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            deflate: deflate::accepted(response),
+            cipher: exchange
+                .map(|exchange| encrypt::accepted(exchange, response))
+                .transpose()?,
+        })
+    }
+}
+
+/// A local listener that a tunnel accepts traffic from.
+#[derive(Debug)]
+pub enum TunnelListener {
+    /// A TCP listener, every accepted connection gets its own WebSocket.
+    Tcp(TcpListener),
+    /// A UDP socket, every peer address gets its own WebSocket.
+    Udp(UdpSocket),
+    /// A Unix socket listener, every accepted connection gets its own
+    /// WebSocket.
+    #[cfg(unix)]
+    Unix(crate::unix::UnixSocketListener),
+}
+
+impl TunnelListener {
+    /// Returns the local address of the listener, prefixed with `udp:` for
+    /// UDP sockets and `unix:` for Unix sockets.
+    pub fn address(&self) -> std::io::Result<String> {
+        match self {
+            TunnelListener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            TunnelListener::Udp(socket) => Ok(format!("{UDP_PREFIX}{}", socket.local_addr()?)),
+            #[cfg(unix)]
+            TunnelListener::Unix(listener) => {
+                Ok(format!("{UNIX_PREFIX}{}", listener.path().display()))
+            }
+        }
+    }
+}
+
+impl From<TcpListener> for TunnelListener {
+    fn from(listener: TcpListener) -> Self {
+        TunnelListener::Tcp(listener)
+    }
+}
+
+impl From<UdpSocket> for TunnelListener {
+    fn from(socket: UdpSocket) -> Self {
+        TunnelListener::Udp(socket)
+    }
+}
+
+#[cfg(unix)]
+impl From<crate::unix::UnixSocketListener> for TunnelListener {
+    fn from(listener: crate::unix::UnixSocketListener) -> Self {
+        TunnelListener::Unix(listener)
+    }
+}
+
+/// A listener of byte streams, accepted connections are proxied one
+/// WebSocket each.
+trait StreamListener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Accepts a new connection, returning it with a printable peer address.
+    fn accept(&self) -> impl Future<Output = std::io::Result<(Self::Stream, String)>> + Send;
+
+    /// Returns the peer and local socket addresses of a connection, if it
+    /// has any.
+    fn addresses(_stream: &Self::Stream) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        (None, None)
+    }
+}
+
+impl StreamListener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Stream, String)> {
+        let (tcp, peer_addr) = TcpListener::accept(self).await?;
+        Ok((tcp, peer_addr.to_string()))
+    }
+
+    fn addresses(stream: &Self::Stream) -> (Option<SocketAddr>, Option<SocketAddr>) {
+        (stream.peer_addr().ok(), stream.local_addr().ok())
+    }
+}
+
+#[cfg(unix)]
+impl StreamListener for crate::unix::UnixSocketListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Stream, String)> {
+        let stream = crate::unix::UnixSocketListener::accept(self).await?;
+        Ok((stream, format!("{UNIX_PREFIX}{}", self.path().display())))
+    }
 }
 
 /// A tunnel that proxies TCP connections to a remote WebSocket server.
@@ -22,11 +612,180 @@ pub struct TunnelConfig {
 /// incoming connections. It will then establish a WebSocket connection to the
 /// remote server and proxy the data between the TCP connection and the
 /// WebSocket connection.
+///
+/// When created from a UDP socket, datagrams are grouped by their source
+/// address and each peer is proxied through its own WebSocket connection.
 #[derive(Debug)]
 pub struct Tunnel {
     config: TunnelConfig,
+    counter: TrafficCounter,
+    capture: SharedCapture,
+    observer: SharedObserver,
+    events: TunnelEvents,
+    sessions: TaskTracker,
     token: CancellationToken,
     handle: JoinHandle<()>,
+    /// The task keeping the connection pool filled, if any.
+    refill: Option<JoinHandle<()>>,
+}
+
+/// How the sessions of a tunnel ended when it was shut down, see
+/// [`Tunnel::shutdown`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "binary", derive(Serialize))]
+pub struct ShutdownStats {
+    /// Sessions that finished within the grace period.
+    pub drained: usize,
+    /// Sessions that were still running at the deadline and were closed.
+    pub cut: usize,
+}
+
+/// The capture sessions of a tunnel are recorded into, if any.
+type SharedCapture = Arc<Mutex<Option<Capture>>>;
+
+/// The observer of the sessions of a tunnel, if any.
+type SharedObserver = Arc<RwLock<Option<Arc<dyn Observer>>>>;
+
+/// An event in the life of a tunnel, see [`Tunnel::subscribe`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "binary", derive(Serialize))]
+pub struct TunnelEvent {
+    /// The local address of the tunnel.
+    pub tunnel: String,
+    /// What happened.
+    #[cfg_attr(feature = "binary", serde(flatten))]
+    pub kind: TunnelEventKind,
+}
+
+/// What happened in a [`TunnelEvent`].
+///
+/// The events of a connection share its `id`, which is unique within the
+/// process. UDP peers are connections too, from their first datagram until
+/// their session ends.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "binary", derive(Serialize))]
+#[cfg_attr(feature = "binary", serde(tag = "event", rename_all = "snake_case"))]
+pub enum TunnelEventKind {
+    /// The tunnel is listening on its local address.
+    Listening,
+    /// A connection was accepted from a local peer.
+    Accepted { id: u64, peer: String },
+    /// The connection is connected to the remote.
+    Connected { id: u64, peer: String },
+    /// The connection could not be connected to the remote and was dropped.
+    Failed {
+        id: u64,
+        peer: String,
+        error: String,
+    },
+    /// The connection is over, with its statistics or the error that ended
+    /// it.
+    Closed {
+        id: u64,
+        peer: String,
+        stats: Option<TrafficStats>,
+        error: Option<String>,
+    },
+    /// The tunnel stopped listening, because it was dropped or its listener
+    /// failed.
+    Stopped { error: Option<String> },
+}
+
+/// Sends the events of a tunnel to its subscribers.
+#[derive(Clone, Debug)]
+struct TunnelEvents {
+    tunnel: String,
+    sender: broadcast::Sender<TunnelEvent>,
+}
+
+impl TunnelEvents {
+    fn send(&self, kind: TunnelEventKind) {
+        // Nobody may be subscribed, which is fine.
+        self.sender
+            .send(TunnelEvent {
+                tunnel: self.tunnel.clone(),
+                kind,
+            })
+            .ok();
+    }
+
+    /// Reports a new connection from the peer.
+    fn accepted(&self, peer: impl Into<String>) -> ConnectionEvents {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let connection = ConnectionEvents {
+            events: self.clone(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer: peer.into(),
+        };
+        self.send(TunnelEventKind::Accepted {
+            id: connection.id,
+            peer: connection.peer.clone(),
+        });
+        connection
+    }
+}
+
+/// Reports the life of a connection, as events and log lines.
+struct ConnectionEvents {
+    events: TunnelEvents,
+    id: u64,
+    peer: String,
+}
+
+impl ConnectionEvents {
+    fn connected(&self) {
+        self.events.send(TunnelEventKind::Connected {
+            id: self.id,
+            peer: self.peer.clone(),
+        });
+    }
+
+    fn failed(&self, remote: &str, e: Error) {
+        error!("Failed to connect to {remote}: {e}");
+        self.events.send(TunnelEventKind::Failed {
+            id: self.id,
+            peer: self.peer.clone(),
+            error: e.to_string(),
+        });
+    }
+
+    fn closed(self, res: Result<TrafficStats, Error>) {
+        let (stats, error) = match res {
+            Ok(stats) => {
+                debug!(
+                    "UNLINK {}: {} bytes in, {} bytes out in {:?}, closed by {:?}",
+                    self.peer,
+                    stats.inbound_bytes,
+                    stats.outbound_bytes,
+                    stats.duration,
+                    stats.closed_by
+                );
+                (Some(stats), None)
+            }
+            Err(e) => {
+                error!("Failed to proxy: {e}");
+                (None, Some(e.to_string()))
+            }
+        };
+        self.events.send(TunnelEventKind::Closed {
+            id: self.id,
+            peer: self.peer,
+            stats,
+            error,
+        });
+    }
+}
+
+/// The serialized form of a `Tunnel`, its configuration and live traffic.
+#[derive(Serialize)]
+struct TunnelView<'a> {
+    #[serde(flatten)]
+    config: &'a TunnelConfig,
+    traffic: TrafficSnapshot,
+    /// The file sessions are captured into.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture: Option<PathBuf>,
 }
 
 impl Serialize for Tunnel {
@@ -35,91 +794,617 @@ impl Serialize for Tunnel {
     where
         S: serde::Serializer,
     {
-        self.config.serialize(serializer)
+        TunnelView {
+            config: &self.config,
+            traffic: self.traffic(),
+            capture: self
+                .capture()
+                .and_then(|capture| capture.path().map(PathBuf::from)),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Builds a [`Tunnel`] with its limits and hooks set before it accepts its
+/// first connection.
+pub struct TunnelBuilder {
+    config: TunnelConfig,
+    events: Option<broadcast::Sender<TunnelEvent>>,
+    observer: Option<Arc<dyn Observer>>,
+    capture: Option<Capture>,
+}
+
+impl TunnelBuilder {
+    /// Creates a builder of a tunnel to the remote with the default
+    /// configuration.
+    pub fn new(remote: impl AsRef<str>) -> Self {
+        Self::from(TunnelConfig {
+            remote: remote.as_ref().to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Sets how long a WebSocket handshake may take.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how many times a failed connection is retried before it is
+    /// dropped.
+    pub fn with_connect_retries(mut self, retries: u32) -> Self {
+        self.config.connect_retries = Some(retries);
+        self
+    }
+
+    /// Sets how many connections may be open at once, `None` for no limit.
+    pub fn with_max_connections(mut self, max: Option<usize>) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    /// Sets how long a connection may stay idle, `None` to never close idle
+    /// connections.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Sends the events of the tunnel into an existing channel.
+    ///
+    /// Receivers of the channel see the [`TunnelEventKind::Listening`] event
+    /// too, which is sent before [`TunnelBuilder::build`] returns, and many
+    /// tunnels can share one channel.
+    pub fn with_events(mut self, events: broadcast::Sender<TunnelEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Sets the observer of the sessions, see [`Tunnel::set_observer`].
+    pub fn with_observer(mut self, observer: Option<Arc<dyn Observer>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Records the sessions into the capture, see [`Tunnel::start_capture`].
+    pub fn with_capture(mut self, capture: Option<Capture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Starts the tunnel on the listener.
+    ///
+    /// The local address of the configuration is replaced by the actual
+    /// address of the listener, fails if it can't be read.
+    pub fn build(self, listener: impl Into<TunnelListener>) -> Result<Tunnel, Error> {
+        let mut config = self.config;
+        let listener = listener.into();
+        config.local = listener.address()?;
+
+        info!("CREATE tunnel: {} <-wsrx-> {}", config.local, config.remote);
+        let events = TunnelEvents {
+            tunnel: config.local.clone(),
+            sender: self
+                .events
+                .unwrap_or_else(|| broadcast::channel(EVENT_CAPACITY).0),
+        };
+        events.send(TunnelEventKind::Listening);
+
+        let token = CancellationToken::new();
+        let counter = TrafficCounter::new();
+        let capture = Arc::new(Mutex::new(self.capture));
+        let observer = Arc::new(RwLock::new(self.observer));
+        // Uploads are read from the local side and written to the WebSocket.
+        let limits = RateLimits::new()
+            .outbound_rate(config.upload_limit)
+            .inbound_rate(config.download_limit);
+
+        let loop_config = Arc::new(config.clone());
+        let loop_counter = counter.clone();
+        let loop_capture = capture.clone();
+        let loop_observer = observer.clone();
+        let loop_events = events.clone();
+        let sessions = TaskTracker::new();
+        let loop_sessions = sessions.clone();
+        let loop_token = token.clone();
+        let pool = match (&listener, config.mux || config.resume) {
+            (TunnelListener::Udp(_), _) | (_, true) => None,
+            _ => WsPool::new(loop_config.clone(), counter.clone()),
+        };
+        // The refill task is tracked like the sessions, and stopped before
+        // they are counted.
+        let refill = pool
+            .as_ref()
+            .map(|pool| sessions.spawn(pool.clone().refill(token.clone())));
+        let handle = match listener {
+            TunnelListener::Tcp(listener) => tokio::spawn(accept_streams(
+                listener,
+                loop_config,
+                loop_counter,
+                loop_capture,
+                loop_observer,
+                loop_events,
+                limits,
+                pool.clone(),
+                loop_sessions,
+                loop_token,
+            )),
+            #[cfg(unix)]
+            TunnelListener::Unix(listener) => tokio::spawn(accept_streams(
+                listener,
+                loop_config,
+                loop_counter,
+                loop_capture,
+                loop_observer,
+                loop_events,
+                limits,
+                pool.clone(),
+                loop_sessions,
+                loop_token,
+            )),
+            TunnelListener::Udp(socket) => tokio::spawn(dispatch_udp(
+                socket,
+                loop_config,
+                loop_counter,
+                loop_capture,
+                loop_observer,
+                loop_events,
+                limits,
+                loop_sessions,
+                loop_token,
+            )),
+        };
+
+        Ok(Tunnel {
+            config,
+            counter,
+            capture,
+            observer,
+            events,
+            sessions,
+            token,
+            handle,
+            refill,
+        })
+    }
+}
+
+impl From<TunnelConfig> for TunnelBuilder {
+    fn from(config: TunnelConfig) -> Self {
+        Self {
+            config,
+            events: None,
+            observer: None,
+            capture: None,
+        }
     }
 }
 
 impl Tunnel {
     /// Creates a new `Tunnel` instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the address of the listener can't be read, use
+    /// [`Tunnel::builder`] to handle that error.
     pub fn new(remote: impl AsRef<str>, listener: TcpListener) -> Self {
-        let local = listener
-            .local_addr()
+        TunnelBuilder::new(remote)
+            .build(listener)
             .expect("failed to bind port")
-            .to_string();
+    }
 
-        info!("CREATE tcp server: {} <-wsrx-> {}", local, remote.as_ref());
+    /// Creates a new `Tunnel` instance that forwards the datagrams of a UDP
+    /// socket, one WebSocket per peer address.
+    pub fn new_udp(remote: impl AsRef<str>, socket: UdpSocket) -> Result<Self, Error> {
+        TunnelBuilder::new(remote).build(socket)
+    }
 
-        let token = CancellationToken::new();
+    /// Creates a builder of a tunnel to the remote.
+    pub fn builder(remote: impl AsRef<str>) -> TunnelBuilder {
+        TunnelBuilder::new(remote)
+    }
 
-        let config = TunnelConfig {
-            local,
-            remote: remote.as_ref().to_string(),
+    /// Creates a new `Tunnel` instance with the given configuration.
+    ///
+    /// The local address of the configuration is replaced by the actual
+    /// address of the listener.
+    pub fn with_config(
+        config: TunnelConfig, listener: impl Into<TunnelListener>,
+    ) -> Result<Self, Error> {
+        TunnelBuilder::from(config).build(listener)
+    }
+
+    /// Creates a new `Tunnel` instance like [`Tunnel::with_config`], sending
+    /// its events into an existing channel.
+    ///
+    /// Receivers of the channel see the [`TunnelEventKind::Listening`] event
+    /// too, which is sent before this returns, and many tunnels can share one
+    /// channel.
+    pub fn with_events(
+        config: TunnelConfig, listener: impl Into<TunnelListener>,
+        events: broadcast::Sender<TunnelEvent>,
+    ) -> Result<Self, Error> {
+        TunnelBuilder::from(config)
+            .with_events(events)
+            .build(listener)
+    }
+
+    /// Subscribes to the events of the tunnel from now on.
+    ///
+    /// Events are kept until every receiver has seen them, up to
+    /// [`EVENT_CAPACITY`]. A receiver that falls further behind skips the
+    /// oldest ones and gets a `Lagged` error first.
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.sender.subscribe()
+    }
+
+    /// Returns the live traffic counter shared by all sessions of the tunnel.
+    pub fn counter(&self) -> &TrafficCounter {
+        &self.counter
+    }
+
+    /// Returns the traffic of all sessions of the tunnel so far.
+    pub fn traffic(&self) -> TrafficSnapshot {
+        self.counter.snapshot()
+    }
+
+    /// Records the sessions opened from now on into the capture, replacing
+    /// and closing the previous one.
+    pub fn start_capture(&self, capture: Capture) {
+        if let Some(previous) = self.capture.lock().unwrap().replace(capture) {
+            previous.close();
+        }
+    }
+
+    /// Stops capturing, and closes the capture so running sessions stop
+    /// being recorded too.
+    pub fn stop_capture(&self) -> Option<Capture> {
+        let capture = self.capture.lock().unwrap().take()?;
+        capture.close();
+        Some(capture)
+    }
+
+    /// Returns the capture sessions are recorded into, if any.
+    pub fn capture(&self) -> Option<Capture> {
+        self.capture.lock().unwrap().clone()
+    }
+
+    /// Sets the observer of the sessions opened from now on, or removes it
+    /// with `None`.
+    pub fn set_observer(&self, observer: Option<Arc<dyn Observer>>) {
+        *self.observer.write().unwrap() = observer;
+    }
+
+    /// Stops accepting connections and waits for the running sessions to
+    /// finish, up to `grace`, then closes the rest.
+    ///
+    /// UDP peers are drained once their queued datagrams are sent, their
+    /// replies are still forwarded until then.
+    pub async fn shutdown(mut self, grace: Duration) -> ShutdownStats {
+        // The refill task of the pool is tracked too, but it is no session.
+        if let Some(refill) = self.refill.take() {
+            refill.abort();
+            refill.await.ok();
+        }
+        // Counted before the listener stops, UDP sessions may finish as soon
+        // as their peers are no longer dispatched.
+        let running = self.sessions.len();
+        if !self.handle.is_finished() {
+            self.handle.abort();
+            (&mut self.handle).await.ok();
+            self.events.send(TunnelEventKind::Stopped { error: None });
+        }
+        self.sessions.close();
+        let running = running.max(self.sessions.len());
+
+        let cut = match tokio::time::timeout(grace, self.sessions.wait()).await {
+            Ok(_) => 0,
+            Err(_) => {
+                let cut = self.sessions.len();
+                self.token.cancel();
+                self.sessions.wait().await;
+                cut
+            }
         };
+        let drained = running - cut;
+        info!(
+            "SHUTDOWN tunnel: {} <-wsrx-> {}: {drained} sessions drained, {cut} cut",
+            self.config.local, self.config.remote
+        );
+        ShutdownStats { drained, cut }
+    }
+}
 
-        let loop_config = Arc::new(config.clone());
-        let loop_token = token.clone();
-        let handle = tokio::spawn(async move {
-            loop {
-                let Ok((tcp, _)) = listener.accept().await else {
-                    error!("Failed to accept tcp connection, exiting.");
-                    loop_token.cancel();
-                    return;
-                };
-
-                let peer_addr = tcp.peer_addr().unwrap();
-
-                if loop_token.is_cancelled() {
-                    info!(
-                        "STOP tcp server: {} <-wsrx-> {}: Task cancelled",
-                        loop_config.local, loop_config.remote
-                    );
-                    return;
-                }
+/// Starts recording a session into the capture of the tunnel, if any.
+fn capture_session(
+    capture: &SharedCapture, udp: bool, client: Option<SocketAddr>, server: Option<SocketAddr>,
+) -> Option<CaptureSession> {
+    let capture = capture.lock().unwrap();
+    let capture = capture.as_ref().filter(|capture| !capture.is_closed())?;
+    Some(match udp {
+        true => capture.udp_session(client, server),
+        false => capture.tcp_session(client, server),
+    })
+}
 
-                info!("LINK {} <-wsrx-> {}", loop_config.remote, peer_addr);
+/// Accepts TCP or Unix socket connections and proxies each of them through a
+/// new or pooled WebSocket, or a new stream of the shared mux session if
+/// enabled.
+#[allow(clippy::too_many_arguments)]
+async fn accept_streams<L: StreamListener>(
+    listener: L, config: Arc<TunnelConfig>, counter: TrafficCounter, capture: SharedCapture,
+    observer: SharedObserver, events: TunnelEvents, limits: RateLimits, pool: Option<Arc<WsPool>>,
+    sessions: TaskTracker, token: CancellationToken,
+) {
+    let mux = config
+        .mux
+        .then(|| Arc::new(MuxClient::new(config.clone()).with_counter(counter.clone())));
+    let slots = config
+        .max_connections
+        .filter(|max| *max > 0)
+        .map(|max| Arc::new(Semaphore::new(max)));
+    loop {
+        // Connections beyond the limit wait in the backlog of the listener.
+        let slot = match &slots {
+            Some(slots) => Some(slots.clone().acquire_owned().await.expect("never closed")),
+            None => None,
+        };
+        let (conn, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection, exiting.");
+                events.send(TunnelEventKind::Stopped {
+                    error: Some(e.to_string()),
+                });
+                token.cancel();
+                return;
+            }
+        };
 
-                let proxy_config = loop_config.clone();
-                let proxy_token = loop_token.clone();
+        if token.is_cancelled() {
+            info!(
+                "STOP server: {} <-wsrx-> {}: Task cancelled",
+                config.local, config.remote
+            );
+            return;
+        }
 
-                tokio::spawn(async move {
-                    use tokio_tungstenite::connect_async;
+        info!("LINK {} <-wsrx-> {}", config.remote, peer_addr);
+        let connection = events.accepted(&peer_addr);
 
-                    let ws = match connect_async(proxy_config.remote.as_str()).await {
-                        Ok((ws, _)) => ws,
-                        Err(e) => {
-                            error!("Failed to connect to {}: {}", proxy_config.remote, e);
-                            return;
-                        }
-                    };
+        let (client, server) = L::addresses(&conn);
+        let accepted = Accepted {
+            conn,
+            label: peer_addr,
+            connection,
+            session: capture_session(&capture, false, client, server),
+            observer: observer.read().unwrap().clone(),
+            limits: limits.clone(),
+            counter: counter.clone(),
+            config: config.clone(),
+            token: token.clone(),
+        };
+        let proxy_mux = mux.clone();
+        let proxy_pool = pool.clone();
 
-                    match proxy(ws.into(), tcp, proxy_token).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Failed to proxy: {e}");
-                        }
+        sessions.spawn(async move {
+            let _slot = slot;
+            let config = &accepted.config;
+            let token = &accepted.token;
+            if let Some(mux) = proxy_mux {
+                match config.retry(token, || mux.open()).await {
+                    Ok(Some(stream)) => return accepted.proxy(stream).await,
+                    Ok(None) => {}
+                    Err(e) => return accepted.failed(e),
+                }
+            }
+
+            if config.resume {
+                let resumable = config
+                    .retry(token, || {
+                        config.connect_resumable_with_counter(Some(&accepted.counter))
+                    })
+                    .await;
+                match resumable {
+                    Ok(Some(stream)) => return accepted.proxy(stream).await,
+                    Ok(None) => {}
+                    Err(e) => return accepted.failed(e),
+                }
+            }
+
+            let ws = config
+                .retry(token, || async {
+                    match &proxy_pool {
+                        Some(pool) => pool.connect().await,
+                        None => config.connect_with_counter(Some(&accepted.counter)).await,
                     }
-                });
+                })
+                .await;
+            match ws {
+                Ok(ws) => accepted.proxy(ws).await,
+                Err(e) => accepted.failed(e),
             }
         });
+    }
+}
 
-        Self {
-            config,
+/// An accepted connection of a tunnel, waiting for its WebSocket.
+struct Accepted<C> {
+    conn: C,
+    label: String,
+    connection: ConnectionEvents,
+    session: Option<CaptureSession>,
+    observer: Option<Arc<dyn Observer>>,
+    limits: RateLimits,
+    counter: TrafficCounter,
+    config: Arc<TunnelConfig>,
+    token: CancellationToken,
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> Accepted<C> {
+    /// Proxies the connection through a WebSocket, a mux stream or a
+    /// resumable stream, with the frame limits, rate limits, capture and
+    /// idle timeout of the tunnel, then reports how it closed.
+    async fn proxy<S>(self, ws: S)
+    where
+        S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        self.connection.connected();
+        let conn = RateLimited::new(self.config.frame_limits.framed(self.conn), self.limits);
+        let conn = Captured::new(conn, self.session);
+        let conn = IdleTimeout::new(conn, self.config.idle_timeout(), self.token);
+        let token = conn.token();
+        let res = proxy_stream_observed(
+            ws,
+            conn,
             token,
-            handle,
+            &self.counter,
+            self.observer.as_deref(),
+            &self.label,
+        )
+        .await;
+        self.connection.closed(res);
+    }
+
+    /// Reports that no WebSocket could be connected for the connection.
+    fn failed(self, e: Error) {
+        self.connection.failed(&self.config.remote, e);
+    }
+}
+
+/// Receives UDP datagrams and dispatches them to per-peer WebSocket sessions.
+///
+/// A session is created on the first datagram of a peer, and is dropped when
+/// its WebSocket closes or it stays idle for too long.
+#[allow(clippy::too_many_arguments)]
+async fn dispatch_udp(
+    socket: UdpSocket, config: Arc<TunnelConfig>, counter: TrafficCounter, capture: SharedCapture,
+    observer: SharedObserver, events: TunnelEvents, limits: RateLimits, sessions: TaskTracker,
+    token: CancellationToken,
+) {
+    let socket = Arc::new(socket);
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, peer_addr) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(e) => {
+                    // ICMP errors caused by previous replies show up here, they
+                    // should not bring the whole listener down.
+                    debug!("Failed to receive udp datagram: {e}");
+                    continue;
+                }
+            },
+            _ = token.cancelled() => {
+                info!(
+                    "STOP udp server: {} <-wsrx-> {}: Task cancelled",
+                    config.local, config.remote
+                );
+                return;
+            }
+        };
+        let datagram = Bytes::copy_from_slice(&buf[..len]);
+
+        let datagram = match peers.get(&peer_addr) {
+            Some(tx) => match tx.try_send(datagram) {
+                Ok(_) => continue,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!("Dropping udp datagram from {peer_addr}: queue is full");
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+
+        peers.retain(|_, tx| !tx.is_closed());
+        if config
+            .max_connections
+            .is_some_and(|max| max > 0 && peers.len() >= max)
+        {
+            debug!("Dropping udp datagram from {peer_addr}: too many peers");
+            continue;
         }
+        let session = capture_session(&capture, true, Some(peer_addr), socket.local_addr().ok());
+        let tx = open_udp_session(
+            socket.clone(),
+            peer_addr,
+            config.clone(),
+            counter.clone(),
+            session,
+            observer.read().unwrap().clone(),
+            &events,
+            limits.clone(),
+            &sessions,
+            token.clone(),
+        );
+        tx.try_send(datagram).ok();
+        peers.insert(peer_addr, tx);
     }
 }
 
+/// Opens a new WebSocket session for a UDP peer.
+///
+/// Returns the channel that feeds the datagrams of the peer to the session.
+#[allow(clippy::too_many_arguments)]
+fn open_udp_session(
+    socket: Arc<UdpSocket>, peer_addr: SocketAddr, config: Arc<TunnelConfig>,
+    counter: TrafficCounter, session: Option<CaptureSession>, observer: Option<Arc<dyn Observer>>,
+    events: &TunnelEvents, limits: RateLimits, sessions: &TaskTracker, token: CancellationToken,
+) -> mpsc::Sender<Bytes> {
+    info!(
+        "LINK {} <-wsrx-> {}{}",
+        config.remote, UDP_PREFIX, peer_addr
+    );
+    let label = format!("{UDP_PREFIX}{peer_addr}");
+    let connection = events.accepted(&label);
+
+    let (tx, rx) = mpsc::channel(UDP_PEER_QUEUE);
+
+    sessions.spawn(async move {
+        let ws = config
+            .retry(&token, || config.connect_with_counter(Some(&counter)))
+            .await;
+        let ws = match ws {
+            Ok(ws) => ws,
+            Err(e) => {
+                connection.failed(&config.remote, e);
+                return;
+            }
+        };
+        connection.connected();
+
+        let udp = DatagramStream::peer(socket, peer_addr, rx).with_idle_timeout(
+            config
+                .idle_timeout()
+                .unwrap_or(datagram::DEFAULT_IDLE_TIMEOUT),
+        );
+        let udp = Captured::new(RateLimited::new(udp, limits), session);
+        let res =
+            proxy_stream_observed(ws, udp, token, &counter, observer.as_deref(), &label).await;
+        connection.closed(res);
+    });
+
+    tx
+}
+
 /// Implements the `Drop` trait for the `Tunnel` struct.
 ///
 /// This will cancel the cancellation token and abort the task when the
-/// `Tunnel` instance is dropped.
+/// `Tunnel` instance is dropped, cutting its sessions. Use
+/// [`Tunnel::shutdown`] to let them finish first.
 impl Drop for Tunnel {
     fn drop(&mut self) {
         info!(
-            "REMOVE tcp server: {} <-wsrx-> {}",
+            "REMOVE tunnel: {} <-wsrx-> {}",
             self.config.local, self.config.remote
         );
+        // A failed listener already reported why it stopped.
+        if !self.handle.is_finished() {
+            self.events.send(TunnelEventKind::Stopped { error: None });
+        }
         self.token.cancel();
         self.handle.abort();
     }
@@ -138,3 +1423,157 @@ impl std::ops::DerefMut for Tunnel {
         &mut self.config
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{Error as TgError, http::Response};
+
+    use super::*;
+
+    /// Serves WebSocket connections that echo binary messages back, returns
+    /// the url of the server.
+    async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if msg.is_binary() && ws.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    fn http(status: u16) -> Error {
+        let response = Response::builder().status(status).body(None).unwrap();
+        TgError::Http(Box::new(response)).into()
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        assert!(is_transient(
+            &io::Error::from(io::ErrorKind::ConnectionRefused).into()
+        ));
+        assert!(is_transient(
+            &TgError::Io(io::ErrorKind::TimedOut.into()).into()
+        ));
+        assert!(is_transient(&TgError::ConnectionClosed.into()));
+        assert!(is_transient(&http(502)));
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        assert!(!is_transient(&http(403)));
+        assert!(!is_transient(&http(404)));
+        assert!(!is_transient(
+            &io::Error::from(io::ErrorKind::InvalidInput).into()
+        ));
+        assert!(!is_transient(
+            &io::Error::from(io::ErrorKind::PermissionDenied).into()
+        ));
+        assert!(!is_transient(&TgError::Utf8(String::new()).into()));
+    }
+
+    #[test]
+    fn timeouts_keep_their_precision() {
+        let builder = TunnelBuilder::new("ws://127.0.0.1:1")
+            .with_connect_timeout(Duration::from_millis(250))
+            .with_idle_timeout(Some(Duration::from_millis(1500)));
+        assert_eq!(builder.config.connect_timeout(), Duration::from_millis(250));
+        assert_eq!(
+            builder.config.idle_timeout(),
+            Some(Duration::from_millis(1500))
+        );
+
+        let builder = TunnelBuilder::new("ws://127.0.0.1:1")
+            .with_connect_timeout(Duration::ZERO)
+            .with_idle_timeout(Some(Duration::ZERO));
+        assert_eq!(builder.config.connect_timeout(), DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(builder.config.idle_timeout(), None);
+
+        let mut config = TunnelConfig {
+            ping_interval: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        let keepalive = config.keepalive().unwrap();
+        assert_eq!(keepalive.interval, Duration::from_millis(500));
+        config.ping_interval = Some(Duration::ZERO);
+        assert!(config.keepalive().is_none());
+    }
+
+    #[test]
+    fn untrusted_configs_are_limited() {
+        let config = TunnelConfig {
+            local: "127.0.0.1:0".to_string(),
+            remote: "ws://127.0.0.1:1".to_string(),
+            pool_size: Some(MAX_UNTRUSTED_POOL_SIZE),
+            ..Default::default()
+        };
+        assert!(config.check_untrusted().is_ok());
+
+        let untrusted = [
+            TunnelConfig {
+                local: "unix:/tmp/wsrx.sock".to_string(),
+                ..config.clone()
+            },
+            TunnelConfig {
+                unix_mode: Some(0o666),
+                ..config.clone()
+            },
+            TunnelConfig {
+                pool_size: Some(MAX_UNTRUSTED_POOL_SIZE + 1),
+                ..config.clone()
+            },
+            TunnelConfig {
+                frame_limits: FrameLimits {
+                    max_message_size: usize::MAX,
+                    ..Default::default()
+                },
+                ..config.clone()
+            },
+            TunnelConfig {
+                tls: TlsOptions {
+                    insecure: true,
+                    ..Default::default()
+                },
+                ..config
+            },
+        ];
+        for config in untrusted {
+            assert!(config.check_untrusted().is_err(), "{config:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_udp_sessions() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tunnel = Tunnel::new_udp(echo_server().await, socket).unwrap();
+        let local = tunnel.local.strip_prefix(UDP_PREFIX).unwrap().to_string();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peer.send_to(b"ping", &local).await.unwrap();
+        let mut buf = [0; 16];
+        let len = tokio::time::timeout(Duration::from_secs(5), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        let stats = tokio::time::timeout(
+            Duration::from_secs(5),
+            tunnel.shutdown(Duration::from_secs(10)),
+        )
+        .await
+        .unwrap();
+        assert_eq!((stats.drained, stats.cut), (1, 0));
+    }
+}
@@ -0,0 +1,184 @@
+//! Unix domain socket support for WebSocket Reflector X.
+//!
+//! A tunnel can listen on a Unix socket instead of a loopback port, so only
+//! users allowed by the file permissions of the socket can reach it.
+
+#[cfg(unix)]
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// The prefix of addresses that are Unix socket paths, e.g.
+/// `unix:/run/wsrx/pwn.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// The file mode of a Unix socket if none is given, only its owner can
+/// connect.
+pub const DEFAULT_UNIX_MODE: u32 = 0o600;
+
+/// Parses an octal file mode, e.g. `600` or `0o660`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("invalid file mode `{mode}`, expected octal like `600`"))
+}
+
+/// A Unix socket listener that removes its socket file when dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    /// Binds a Unix socket at `path` with the given file mode.
+    ///
+    /// A stale socket file left by a dead process is replaced, but a
+    /// socket that still accepts connections or any other kind of file
+    /// is never touched.
+    ///
+    /// The socket is bound inside a private directory next to `path` and
+    /// only moved into place once it has its mode, so no one else can
+    /// connect to it before.
+    pub async fn bind(path: impl AsRef<Path>, mode: u32) -> io::Result<Self> {
+        let path = path.as_ref();
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            let stale = meta.file_type().is_socket()
+                && UnixStream::connect(path)
+                    .await
+                    .is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused);
+            if !stale {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} already exists", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let private = private_dir(path)?;
+        let bound = private.join("s");
+        let listener = UnixListener::bind(&bound).and_then(|listener| {
+            fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+            fs::rename(&bound, path)?;
+            Ok(listener)
+        });
+        fs::remove_file(&bound).ok();
+        fs::remove_dir(&private).ok();
+        Ok(Self {
+            listener: listener?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accepts a new connection.
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        self.listener.accept().await.map(|(stream, _)| stream)
+    }
+}
+
+/// Creates a directory next to `path` that only the current user can enter.
+#[cfg(unix)]
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir = parent.join(format!(
+        ".wsrx-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Returns a socket path in a new directory, removed with the guard.
+    fn socket_path(name: &str) -> (PathBuf, impl Drop) {
+        struct Guard(PathBuf);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                fs::remove_dir_all(&self.0).ok();
+            }
+        }
+        let dir = std::env::temp_dir().join(format!("wsrx-unix-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        (dir.join("test.sock"), Guard(dir))
+    }
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert_eq!(parse_mode("0o660"), Ok(0o660));
+        assert!(parse_mode("800").is_err());
+        assert!(parse_mode("1777").is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_gets_its_mode_and_nothing_is_left_behind() {
+        let (path, _guard) = socket_path("mode");
+        let listener = UnixSocketListener::bind(&path, 0o640).await.unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        // Only the socket is in the directory, the private one is gone.
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+
+        let (client, server) = tokio::join!(UnixStream::connect(&path), listener.accept());
+        client.unwrap();
+        server.unwrap();
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced_but_live_ones_are_not() {
+        let (path, _guard) = socket_path("stale");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let live = UnixSocketListener::bind(&path, DEFAULT_UNIX_MODE)
+            .await
+            .unwrap();
+
+        let err = UnixSocketListener::bind(&path, DEFAULT_UNIX_MODE)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(live);
+
+        fs::write(&path, b"not a socket").unwrap();
+        let err = UnixSocketListener::bind(&path, DEFAULT_UNIX_MODE)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -1,10 +1,82 @@
 use std::net::ToSocketAddrs;
 
 use axum::http::StatusCode;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UdpSocket};
 #[cfg(feature = "log")]
 use tracing::error;
 
+use crate::{datagram::UDP_PREFIX, tunnel::TunnelListener, unix::UNIX_PREFIX};
+
+/// Creates a listener on the specified local address.
+///
+/// Addresses prefixed with `udp:` bind a UDP socket, those prefixed with
+/// `unix:` bind a Unix socket only its owner can connect to, others bind a
+/// TCP listener.
+///
+/// @param local The local address to bind the listener to.
+///
+/// @returns A `Result` containing the `TunnelListener` if successful,
+/// or an error tuple
+pub async fn create_listener(local: &str) -> Result<TunnelListener, (StatusCode, String)> {
+    create_listener_with_mode(local, None).await
+}
+
+/// Creates a listener on the specified local address, Unix sockets get the
+/// given file mode.
+///
+/// @param local The local address to bind the listener to.
+/// @param unix_mode The file mode of a Unix socket, `0o600` if `None`.
+///
+/// @returns A `Result` containing the `TunnelListener` if successful,
+/// or an error tuple
+pub async fn create_listener_with_mode(
+    local: &str, unix_mode: Option<u32>,
+) -> Result<TunnelListener, (StatusCode, String)> {
+    if let Some(path) = local.strip_prefix(UNIX_PREFIX) {
+        return create_unix_listener(path, unix_mode).await.map(Into::into);
+    }
+    match local.strip_prefix(UDP_PREFIX) {
+        Some(local) => create_udp_socket(local).await.map(Into::into),
+        None => create_tcp_listener(local).await.map(Into::into),
+    }
+}
+
+/// Creates a Unix socket listener at the specified path.
+///
+/// @param path The path of the socket.
+/// @param mode The file mode of the socket, `0o600` if `None`.
+///
+/// @returns A `Result` containing the `UnixSocketListener` if successful,
+/// or an error tuple
+#[cfg(unix)]
+pub async fn create_unix_listener(
+    path: &str, mode: Option<u32>,
+) -> Result<crate::unix::UnixSocketListener, (StatusCode, String)> {
+    let mode = mode.unwrap_or(crate::unix::DEFAULT_UNIX_MODE);
+    crate::unix::UnixSocketListener::bind(path, mode)
+        .await
+        .map_err(|err| {
+            #[cfg(feature = "log")]
+            error!("Failed to bind unix socket {path}: {err}");
+            let status = match err.kind() {
+                std::io::ErrorKind::AddrInUse => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, format!("failed to bind unix socket {path}: {err}"))
+        })
+}
+
+/// Unix sockets are not available on this platform.
+#[cfg(not(unix))]
+pub async fn create_unix_listener(
+    _path: &str, _mode: Option<u32>,
+) -> Result<TunnelListener, (StatusCode, String)> {
+    Err((
+        StatusCode::BAD_REQUEST,
+        "unix sockets are not supported on this platform".to_owned(),
+    ))
+}
+
 /// Creates a TCP listener on the specified local address.
 ///
 /// @param local The local address to bind the TCP listener to.
@@ -39,3 +111,38 @@ pub async fn create_tcp_listener(local: &str) -> Result<TcpListener, (StatusCode
         )
     })
 }
+
+/// Creates a UDP socket bound to the specified local address.
+///
+/// @param local The local address to bind the UDP socket to.
+///
+/// @returns A `Result` containing the `UdpSocket` if successful,
+/// or an error tuple
+pub async fn create_udp_socket(local: &str) -> Result<UdpSocket, (StatusCode, String)> {
+    let mut udp_addr_obj = local.to_socket_addrs().map_err(|err| {
+        #[cfg(feature = "log")]
+        error!("Failed to parse from address: {err}");
+        (
+            StatusCode::BAD_REQUEST,
+            "failed to parse from address".to_owned(),
+        )
+    })?;
+
+    let udp_addr_obj = udp_addr_obj.next().ok_or_else(|| {
+        #[cfg(feature = "log")]
+        error!("Failed to get socket addr");
+        (
+            StatusCode::BAD_REQUEST,
+            "failed to get socket addr".to_owned(),
+        )
+    })?;
+
+    UdpSocket::bind(udp_addr_obj).await.map_err(|err| {
+        #[cfg(feature = "log")]
+        error!("Failed to bind udp address {udp_addr_obj:?}: {err}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to bind udp address {udp_addr_obj:?}: {err}"),
+        )
+    })
+}